@@ -0,0 +1,333 @@
+use serde_json::{json, Value};
+use tokio::net::TcpListener;
+use tokio::sync::{mpsc, oneshot};
+
+use crate::config::{GossipFilterPolicy, MESSAGE_TAIL_LENGTH};
+use crate::http::{read_request, respond, Request};
+use crate::node::Node;
+use crate::serialization::ShortChannelIDElement;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ApiRequest {
+    Node([u8; 33]),
+    NodeChannels([u8; 33]),
+    Channel(ShortChannelIDElement),
+    Connections,
+    // the last n messages we received
+    Messages(usize),
+    // open connections only
+    Peers,
+    GossipFilter,
+    AutoConnect,
+    // the rest change things and are only served on the control address
+    Connect(Node),
+    Disconnect([u8; 33]),
+    SetGossipFilter(GossipFilterPolicy),
+    SetAutoConnect(bool),
+}
+
+impl ApiRequest {
+    pub fn is_control(&self) -> bool {
+        matches!(
+            self,
+            ApiRequest::Connect(_)
+                | ApiRequest::Disconnect(_)
+                | ApiRequest::SetGossipFilter(_)
+                | ApiRequest::SetAutoConnect(_)
+        )
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ApiError {
+    NotFound,
+    BadRequest(String),
+    MethodNotAllowed,
+    // a control request on the read-only address
+    Forbidden,
+    // we couldn't do it, a peer that won't connect for instance
+    Failed(String),
+}
+
+pub type ApiResponse = Result<Value, ApiError>;
+
+// a request on its way to the event loop, which owns everything the answer comes from
+pub struct ApiCall {
+    pub request: ApiRequest,
+    pub reply: oneshot::Sender<ApiResponse>,
+}
+
+fn parse_node_id(value: &str) -> Result<[u8; 33], ApiError> {
+    hex::decode(value)
+        .ok()
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or_else(|| ApiError::BadRequest(format!("Invalid node id: {}", value)))
+}
+
+// 123x4x5 or the short channel id as a number
+fn parse_short_channel_id(value: &str) -> Result<ShortChannelIDElement, ApiError> {
+    let invalid = || ApiError::BadRequest(format!("Invalid short channel id: {}", value));
+    let parts: Vec<&str> = value.split('x').collect();
+    match parts.as_slice() {
+        [block_height, tx_index, output_index] => Ok(ShortChannelIDElement {
+            block_height: block_height.parse().map_err(|_| invalid())?,
+            tx_index: tx_index.parse().map_err(|_| invalid())?,
+            output_index: output_index.parse().map_err(|_| invalid())?,
+        }),
+        [number] => {
+            let number: u64 = number.parse().map_err(|_| invalid())?;
+            Ok(ShortChannelIDElement {
+                block_height: (number >> 40) as u32,
+                tx_index: ((number >> 16) & 0xffffff) as u32,
+                output_index: number as u16,
+            })
+        }
+        _ => Err(invalid()),
+    }
+}
+
+fn parse_bool(value: &str) -> Result<bool, ApiError> {
+    match value {
+        "on" | "true" => Ok(true),
+        "off" | "false" => Ok(false),
+        _ => Err(ApiError::BadRequest(format!(
+            "Expected on or off: {}",
+            value
+        ))),
+    }
+}
+
+pub fn route(request: &Request) -> Result<ApiRequest, ApiError> {
+    let segments: Vec<&str> = request
+        .path
+        .trim_matches('/')
+        .split('/')
+        .filter(|segment| !segment.is_empty())
+        .collect();
+    let method = request.method.as_str();
+    match segments.as_slice() {
+        ["peers"] if method == "GET" => Ok(ApiRequest::Peers),
+        ["peers", node] if method == "POST" => match Node::from_str(node) {
+            Some(node) => Ok(ApiRequest::Connect(node)),
+            None => Err(ApiError::BadRequest(format!(
+                "Expected pubkey@host:port: {}",
+                node
+            ))),
+        },
+        ["peers", node_id] if method == "DELETE" => {
+            Ok(ApiRequest::Disconnect(parse_node_id(node_id)?))
+        }
+        ["gossip-filter"] if method == "GET" => Ok(ApiRequest::GossipFilter),
+        ["gossip-filter", policy] if method == "PUT" => Ok(ApiRequest::SetGossipFilter(
+            policy.parse().map_err(ApiError::BadRequest)?,
+        )),
+        ["auto-connect"] if method == "GET" => Ok(ApiRequest::AutoConnect),
+        ["auto-connect", enabled] if method == "PUT" => {
+            Ok(ApiRequest::SetAutoConnect(parse_bool(enabled)?))
+        }
+        ["peers"] | ["peers", _] | ["gossip-filter", ..] | ["auto-connect", ..] => {
+            Err(ApiError::MethodNotAllowed)
+        }
+        _ if method != "GET" => Err(ApiError::MethodNotAllowed),
+        ["nodes", node_id] => Ok(ApiRequest::Node(parse_node_id(node_id)?)),
+        ["nodes", node_id, "channels"] => Ok(ApiRequest::NodeChannels(parse_node_id(node_id)?)),
+        ["channels", short_channel_id] => Ok(ApiRequest::Channel(parse_short_channel_id(
+            short_channel_id,
+        )?)),
+        ["connections"] => Ok(ApiRequest::Connections),
+        ["messages"] => {
+            let limit = match request.query.get("limit") {
+                Some(limit) => limit
+                    .parse()
+                    .map_err(|_| ApiError::BadRequest(format!("Invalid limit: {}", limit)))?,
+                None => MESSAGE_TAIL_LENGTH,
+            };
+            Ok(ApiRequest::Messages(limit))
+        }
+        _ => Err(ApiError::NotFound),
+    }
+}
+
+// json over the live graph and connections, answered by the event loop. control requests
+// are refused unless this is the control address
+pub async fn serve(listener: TcpListener, calls: mpsc::Sender<ApiCall>, control: bool) {
+    loop {
+        let (mut stream, _) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                println!("Failed to accept api connection: {:?}", e);
+                continue;
+            }
+        };
+        let calls = calls.clone();
+        tokio::spawn(async move {
+            let request = match read_request(&mut stream).await {
+                Ok(request) => request,
+                Err(_) => return,
+            };
+            let response = match route(&request) {
+                Ok(request) if request.is_control() && !control => Some(Err(ApiError::Forbidden)),
+                Ok(request) => {
+                    let (reply, answer) = oneshot::channel();
+                    match calls.send(ApiCall { request, reply }).await {
+                        Ok(()) => answer.await.ok(),
+                        Err(_) => None,
+                    }
+                }
+                Err(e) => Some(Err(e)),
+            };
+            let (status, body) = match response {
+                Some(Ok(value)) => (200, value),
+                Some(Err(ApiError::NotFound)) => (404, json!({ "error": "not found" })),
+                Some(Err(ApiError::BadRequest(reason))) => (400, json!({ "error": reason })),
+                Some(Err(ApiError::MethodNotAllowed)) => {
+                    (405, json!({ "error": "method not allowed" }))
+                }
+                Some(Err(ApiError::Forbidden)) => (
+                    403,
+                    json!({ "error": "only allowed on the control address" }),
+                ),
+                Some(Err(ApiError::Failed(reason))) => (502, json!({ "error": reason })),
+                // the event loop has stopped
+                None => (503, json!({ "error": "shutting down" })),
+            };
+            let body = serde_json::to_vec_pretty(&body).unwrap();
+            if let Err(e) = respond(&mut stream, status, "application/json", &body).await {
+                println!("Failed to answer api request: {:?}", e);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::get;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+
+    #[test]
+    fn test_route() {
+        let request =
+            |path: &str| Request::parse(&format!("GET {} HTTP/1.1\r\n\r\n", path)).unwrap();
+        let node_id = "02".to_string() + &"ab".repeat(32);
+        assert_eq!(
+            route(&request(&format!("/nodes/{}/channels", node_id))),
+            Ok(ApiRequest::NodeChannels(
+                hex::decode(&node_id).unwrap().try_into().unwrap()
+            ))
+        );
+        let short_channel_id = ShortChannelIDElement {
+            block_height: 700_000,
+            tx_index: 1234,
+            output_index: 1,
+        };
+        assert_eq!(
+            route(&request("/channels/700000x1234x1")),
+            Ok(ApiRequest::Channel(short_channel_id.clone()))
+        );
+        assert_eq!(
+            route(&request("/channels/769658139524071425")),
+            Ok(ApiRequest::Channel(short_channel_id))
+        );
+        assert_eq!(
+            route(&request("/messages?limit=5")),
+            Ok(ApiRequest::Messages(5))
+        );
+        assert!(matches!(
+            route(&request("/nodes/02ab")),
+            Err(ApiError::BadRequest(_))
+        ));
+        assert_eq!(route(&request("/graph")), Err(ApiError::NotFound));
+    }
+
+    #[test]
+    fn test_route_control() {
+        let request = |method: &str, path: &str| {
+            Request::parse(&format!("{} {} HTTP/1.1\r\n\r\n", method, path)).unwrap()
+        };
+        let node_id = "02".to_string() + &"ab".repeat(32);
+        match route(&request(
+            "POST",
+            &format!("/peers/{}@127.0.0.1:9735", node_id),
+        )) {
+            Ok(ApiRequest::Connect(node)) => assert_eq!(node.address(), "127.0.0.1:9735"),
+            other => panic!("Unexpected route: {:?}", other),
+        }
+        assert!(matches!(
+            route(&request("POST", "/peers/02ab@127.0.0.1")),
+            Err(ApiError::BadRequest(_))
+        ));
+        // the right length, but not a key
+        assert!(matches!(
+            route(&request(
+                "POST",
+                &format!("/peers/05{}@127.0.0.1:9735", "ab".repeat(32))
+            )),
+            Err(ApiError::BadRequest(_))
+        ));
+        assert!(route(&request("DELETE", &format!("/peers/{}", node_id)))
+            .unwrap()
+            .is_control());
+        assert_eq!(route(&request("GET", "/peers")), Ok(ApiRequest::Peers));
+        assert_eq!(
+            route(&request("PUT", "/gossip-filter/window:1700000000:86400")),
+            Ok(ApiRequest::SetGossipFilter(GossipFilterPolicy::Window {
+                first_timestamp: 1_700_000_000,
+                timestamp_range: 86_400,
+            }))
+        );
+        assert_eq!(
+            route(&request("PUT", "/auto-connect/off")),
+            Ok(ApiRequest::SetAutoConnect(false))
+        );
+        assert_eq!(
+            route(&request("POST", "/auto-connect")),
+            Err(ApiError::MethodNotAllowed)
+        );
+        assert_eq!(
+            route(&request("DELETE", "/connections")),
+            Err(ApiError::MethodNotAllowed)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_serve() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (calls, mut received) = mpsc::channel(8);
+        let server = tokio::spawn(serve(listener, calls, false));
+        // stands in for the event loop
+        let answers = tokio::spawn(async move {
+            while let Some(call) = received.recv().await {
+                let response = match call.request {
+                    ApiRequest::Connections => Ok(json!([{ "address": "127.0.0.1:9735" }])),
+                    _ => Err(ApiError::NotFound),
+                };
+                let _ = call.reply.send(response);
+            }
+        });
+
+        let response = get(port, "/connections").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        let body: Value = serde_json::from_str(response.split("\r\n\r\n").nth(1).unwrap()).unwrap();
+        assert_eq!(body[0]["address"], "127.0.0.1:9735");
+        assert!(get(port, "/channels/1x2x3")
+            .await
+            .starts_with("HTTP/1.1 404"));
+        assert!(get(port, "/channels/nope")
+            .await
+            .starts_with("HTTP/1.1 400"));
+        // the read-only address refuses control requests before the event loop sees them
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        stream
+            .write_all(b"PUT /auto-connect/on HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 403"));
+        server.abort();
+        answers.abort();
+    }
+}
@@ -0,0 +1,210 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufReader, Read, Write};
+use std::path::Path;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+// header: magic, version, wall clock start in unix millis
+// record: peer pubkey (33), direction (1), micros since start (8), length (2), message bytes
+const MAGIC: &[u8; 4] = b"LMPC";
+const VERSION: u8 = 1;
+const HEADER_LENGTH: usize = 4 + 1 + 8;
+const RECORD_HEADER_LENGTH: usize = 33 + 1 + 8 + 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Inbound,
+    Outbound,
+}
+
+impl Direction {
+    fn to_byte(self) -> u8 {
+        match self {
+            Direction::Inbound => 0,
+            Direction::Outbound => 1,
+        }
+    }
+
+    fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            0 => Some(Direction::Inbound),
+            1 => Some(Direction::Outbound),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CaptureRecord {
+    pub node_public_key: [u8; 33],
+    pub direction: Direction,
+    // since the start of the capture, never goes backwards within a run
+    pub micros: u64,
+    // decrypted message, type included
+    pub bytes: Vec<u8>,
+}
+
+fn invalid_data(reason: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, reason.to_string())
+}
+
+// appends decrypted messages to a capture file
+pub struct CaptureWriter {
+    file: File,
+    // where this run starts on the capture's clock
+    offset_micros: u64,
+    started: Instant,
+}
+
+impl CaptureWriter {
+    // keeps the header of an existing capture so later runs line up after earlier ones
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(path)?;
+        let now_micros = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_micros() as u64;
+        let start_ms = if file.metadata()?.len() == 0 {
+            let mut header = Vec::with_capacity(HEADER_LENGTH);
+            header.extend_from_slice(MAGIC);
+            header.push(VERSION);
+            header.extend_from_slice(&(now_micros / 1000).to_be_bytes());
+            file.write_all(&header)?;
+            now_micros / 1000
+        } else {
+            read_header(&mut file)?
+        };
+        let offset_micros = now_micros.saturating_sub(start_ms * 1000);
+        Ok(CaptureWriter {
+            file,
+            offset_micros,
+            started: Instant::now(),
+        })
+    }
+
+    pub fn write(
+        &mut self,
+        node_public_key: &[u8; 33],
+        direction: Direction,
+        bytes: &[u8],
+    ) -> io::Result<()> {
+        if bytes.len() > u16::MAX as usize {
+            return Err(invalid_data("message too long"));
+        }
+        let micros = self.offset_micros + self.started.elapsed().as_micros() as u64;
+        let mut record = Vec::with_capacity(RECORD_HEADER_LENGTH + bytes.len());
+        record.extend_from_slice(node_public_key);
+        record.push(direction.to_byte());
+        record.extend_from_slice(&micros.to_be_bytes());
+        record.extend_from_slice(&(bytes.len() as u16).to_be_bytes());
+        record.extend_from_slice(bytes);
+        // one write per record, so a crash loses at most the last one
+        self.file.write_all(&record)?;
+        self.file.flush()
+    }
+}
+
+fn read_header(reader: &mut impl Read) -> io::Result<u64> {
+    let mut header = [0u8; HEADER_LENGTH];
+    reader.read_exact(&mut header)?;
+    if &header[..4] != MAGIC {
+        return Err(invalid_data("not a capture file"));
+    }
+    if header[4] != VERSION {
+        return Err(invalid_data("unknown capture version"));
+    }
+    Ok(u64::from_be_bytes(header[5..].try_into().unwrap()))
+}
+
+// reads records back in the order they were written
+pub struct CaptureReader {
+    reader: BufReader<File>,
+    pub start_ms: u64,
+}
+
+impl CaptureReader {
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let mut reader = BufReader::new(File::open(path)?);
+        let start_ms = read_header(&mut reader)?;
+        Ok(CaptureReader { reader, start_ms })
+    }
+
+    fn read_record(&mut self) -> io::Result<Option<CaptureRecord>> {
+        let mut header = [0u8; RECORD_HEADER_LENGTH];
+        // a clean end of file is only allowed between records
+        match self.reader.read(&mut header[..1])? {
+            0 => return Ok(None),
+            _ => self.reader.read_exact(&mut header[1..])?,
+        }
+        let node_public_key: [u8; 33] = header[..33].try_into().unwrap();
+        let direction =
+            Direction::from_byte(header[33]).ok_or_else(|| invalid_data("unknown direction"))?;
+        let micros = u64::from_be_bytes(header[34..42].try_into().unwrap());
+        let length = u16::from_be_bytes([header[42], header[43]]) as usize;
+        let mut bytes = vec![0u8; length];
+        self.reader.read_exact(&mut bytes)?;
+        Ok(Some(CaptureRecord {
+            node_public_key,
+            direction,
+            micros,
+            bytes,
+        }))
+    }
+}
+
+impl Iterator for CaptureReader {
+    type Item = io::Result<CaptureRecord>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_record().transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write_and_read_capture() {
+        let path = std::env::temp_dir().join("lmprs_capture_test.lmpc");
+        let _ = std::fs::remove_file(&path);
+        let alice = [2u8; 33];
+        let bob = [3u8; 33];
+        let mut writer = CaptureWriter::open(&path).unwrap();
+        writer
+            .write(&alice, Direction::Inbound, &[0, 18, 1])
+            .unwrap();
+        writer.write(&bob, Direction::Outbound, &[0, 19]).unwrap();
+        drop(writer);
+        // a second run appends after the first
+        let mut writer = CaptureWriter::open(&path).unwrap();
+        writer.write(&alice, Direction::Inbound, &[]).unwrap();
+        drop(writer);
+
+        let records: Vec<CaptureRecord> = CaptureReader::open(&path)
+            .unwrap()
+            .map(|record| record.unwrap())
+            .collect();
+        assert_eq!(records.len(), 3);
+        assert_eq!(records[0].node_public_key, alice);
+        assert_eq!(records[0].bytes, vec![0, 18, 1]);
+        assert_eq!(records[1].direction, Direction::Outbound);
+        assert!(records[1].micros >= records[0].micros);
+        assert!(records[2].micros >= records[1].micros);
+        assert!(records[2].bytes.is_empty());
+
+        // a record cut short is an error, not the end of the capture
+        let length = std::fs::metadata(&path).unwrap().len();
+        let file = OpenOptions::new().write(true).open(&path).unwrap();
+        file.set_len(length - 1).unwrap();
+        let mut reader = CaptureReader::open(&path).unwrap();
+        assert!(reader.nth(2).unwrap().is_err());
+
+        std::fs::write(&path, b"nope").unwrap();
+        assert!(CaptureReader::open(&path).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,248 @@
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use bytes::BytesMut;
+use tokio_util::codec::{Decoder, Encoder};
+
+use crate::capture::{CaptureWriter, Direction};
+use crate::keylog::KeyLog;
+use crate::message_decoder::{MessageContainer, MessageDecoder};
+use crate::node_connection::NodeConnectionError;
+use crate::noise::{PeerChannelEncryptor, LENGTH_HEADER_LENGTH, MAC_LENGTH};
+use crate::pcap::PcapWriter;
+use crate::stats::TrafficStats;
+
+// everything that wants to see decrypted traffic, shared by every connection
+#[derive(Clone, Default)]
+pub struct Taps {
+    pub capture: Option<Arc<Mutex<CaptureWriter>>>,
+    pub pcap: Option<Arc<Mutex<PcapWriter>>>,
+    // session secrets for decrypting a separate packet capture, off unless asked for
+    pub keylog: Option<Arc<Mutex<KeyLog>>>,
+}
+
+// BOLT 8 frames: an encrypted length and its mac, then the encrypted message and its mac
+pub struct LightningCodec {
+    pub encryptor: PeerChannelEncryptor,
+    public_key: [u8; 33],
+    // (local, remote), for the pcap
+    endpoints: Option<(SocketAddr, SocketAddr)>,
+    taps: Taps,
+    // length of the message we've read the header of
+    pending_length: Option<usize>,
+    // when the last message came off the wire, before anything else gets to it
+    pub received_at: Instant,
+    pub stats: TrafficStats,
+}
+
+impl LightningCodec {
+    pub fn new(
+        encryptor: PeerChannelEncryptor,
+        public_key: [u8; 33],
+        endpoints: Option<(SocketAddr, SocketAddr)>,
+        taps: Taps,
+    ) -> Self {
+        LightningCodec {
+            encryptor,
+            public_key,
+            endpoints,
+            taps,
+            pending_length: None,
+            received_at: Instant::now(),
+            stats: TrafficStats::default(),
+        }
+    }
+
+    // called once the handshake is done, logs its keys if there is a key log
+    pub fn start_keylog(&mut self) {
+        if let (Some(keylog), Some(keys)) = (&self.taps.keylog, self.encryptor.handshake_keys()) {
+            if let Err(e) = keylog.lock().unwrap().log_handshake(keys) {
+                println!("Failed to write key log: {:?}", e);
+            }
+        }
+    }
+
+    // taken whether or not there is a key log, so they don't pile up
+    fn log_rotations(&mut self) {
+        let rotations = self.encryptor.take_rotations();
+        if let (Some(keylog), Some(keys)) = (&self.taps.keylog, self.encryptor.handshake_keys()) {
+            for rotation in rotations {
+                if let Err(e) = keylog.lock().unwrap().log_rotation(keys, &rotation) {
+                    println!("Failed to write key log: {:?}", e);
+                }
+            }
+        }
+    }
+
+    fn tap(&mut self, direction: Direction, bytes: &[u8]) {
+        self.stats.record(direction, bytes);
+        if let Some(capture) = &self.taps.capture {
+            if let Err(e) = capture
+                .lock()
+                .unwrap()
+                .write(&self.public_key, direction, bytes)
+            {
+                println!("Failed to capture message: {:?}", e);
+            }
+        }
+        if let (Some(pcap), Some((local, remote))) = (&self.taps.pcap, self.endpoints) {
+            if let Err(e) =
+                pcap.lock()
+                    .unwrap()
+                    .write(local, remote, &self.public_key, direction, bytes)
+            {
+                println!("Failed to write pcap packet: {:?}", e);
+            }
+        }
+    }
+
+    // the next decrypted message, if all of it has arrived
+    fn decrypt_next(&mut self, src: &mut BytesMut) -> Result<Option<Vec<u8>>, NodeConnectionError> {
+        let length = match self.pending_length {
+            Some(length) => length,
+            None => {
+                if src.len() < LENGTH_HEADER_LENGTH {
+                    return Ok(None);
+                }
+                let header = src.split_to(LENGTH_HEADER_LENGTH);
+                let length = match self.encryptor.decrypt_length_header(&header) {
+                    Ok(length) => length as usize,
+                    Err(err) => return Err(NodeConnectionError::DecryptionError(err)),
+                };
+                self.pending_length = Some(length);
+                length
+            }
+        };
+        if src.len() < length + MAC_LENGTH {
+            src.reserve(length + MAC_LENGTH - src.len());
+            return Ok(None);
+        }
+        self.pending_length = None;
+        self.stats.bytes_in += (LENGTH_HEADER_LENGTH + length + MAC_LENGTH) as u64;
+        let body = src.split_to(length + MAC_LENGTH);
+        let decrypted = self.encryptor.decrypt_message(&body);
+        self.log_rotations();
+        match decrypted {
+            Ok(message) => Ok(Some(message)),
+            Err(err) => Err(NodeConnectionError::DecryptionError(err)),
+        }
+    }
+}
+
+impl Decoder for LightningCodec {
+    type Item = MessageContainer;
+    type Error = NodeConnectionError;
+
+    // a message we can't decode is reported and skipped, an error here would end the stream
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<MessageContainer>, Self::Error> {
+        while let Some(bytes) = self.decrypt_next(src)? {
+            self.received_at = Instant::now();
+            self.tap(Direction::Inbound, &bytes);
+            match MessageDecoder::from_bytes(&bytes) {
+                Ok((message, _)) => return Ok(Some(message)),
+                Err(e) => {
+                    self.stats.decode_errors += 1;
+                    println!(
+                        "Failed to decode message from {}: {:?} {}",
+                        hex::encode(self.public_key),
+                        e,
+                        hex::encode(&bytes)
+                    );
+                }
+            }
+        }
+        Ok(None)
+    }
+}
+
+impl Encoder<&[u8]> for LightningCodec {
+    type Error = NodeConnectionError;
+
+    fn encode(&mut self, bytes: &[u8], dst: &mut BytesMut) -> Result<(), Self::Error> {
+        self.tap(Direction::Outbound, bytes);
+        let encrypted = self.encryptor.encrypt_message(bytes);
+        self.log_rotations();
+        match encrypted {
+            Ok(encrypted) => {
+                self.stats.bytes_out += encrypted.len() as u64;
+                dst.extend_from_slice(&encrypted);
+                Ok(())
+            }
+            Err(err) => Err(NodeConnectionError::NoiseError(err)),
+        }
+    }
+}
+
+impl Encoder<&MessageContainer> for LightningCodec {
+    type Error = NodeConnectionError;
+
+    fn encode(
+        &mut self,
+        message: &MessageContainer,
+        dst: &mut BytesMut,
+    ) -> Result<(), Self::Error> {
+        self.encode(message.to_bytes().as_slice(), dst)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::messages::PingMessage;
+    use crate::serialization::IgnoredBytesElement;
+    use crate::util::new_random_secret_key;
+    use bitcoin::secp256k1::{PublicKey, Secp256k1};
+
+    // both ends of a finished handshake
+    fn codecs() -> (LightningCodec, LightningCodec) {
+        let responder_key = new_random_secret_key();
+        let responder_id = PublicKey::from_secret_key(&Secp256k1::new(), &responder_key);
+        let mut initiator =
+            PeerChannelEncryptor::new_outbound(responder_id, new_random_secret_key());
+        let mut responder = PeerChannelEncryptor::new_inbound(responder_key);
+        let act_one = initiator.get_act_one().unwrap();
+        let act_two = responder
+            .process_act_one(&act_one, new_random_secret_key())
+            .unwrap();
+        let (act_three, _) = initiator
+            .process_act_two(&act_two, &new_random_secret_key())
+            .unwrap();
+        responder.process_act_three(&act_three).unwrap();
+        (
+            LightningCodec::new(initiator, [2; 33], None, Taps::default()),
+            LightningCodec::new(responder, [3; 33], None, Taps::default()),
+        )
+    }
+
+    #[test]
+    fn test_frames_split_anywhere() {
+        let (mut initiator, mut responder) = codecs();
+        let ping = MessageContainer::Ping(PingMessage {
+            num_pong_bytes: 4,
+            ignored: IgnoredBytesElement::new(vec![0; 10]),
+        });
+        let mut wire = BytesMut::new();
+        initiator.encode(&ping, &mut wire).unwrap();
+        // a truncated stfu is skipped, the stream goes on
+        initiator.encode([0u8, 2].as_slice(), &mut wire).unwrap();
+        initiator.encode(&ping, &mut wire).unwrap();
+
+        let mut src = BytesMut::new();
+        let mut decoded = Vec::new();
+        for byte in wire.iter() {
+            src.extend_from_slice(&[*byte]);
+            if let Some(message) = responder.decode(&mut src).unwrap() {
+                decoded.push(message);
+            }
+        }
+        assert_eq!(decoded.len(), 2);
+        assert!(decoded
+            .iter()
+            .all(|message| message.to_bytes() == ping.to_bytes()));
+        assert_eq!(responder.stats.decode_errors, 1);
+        assert_eq!(responder.stats.messages_in["Ping"], 2);
+        assert_eq!(responder.stats.bytes_in, wire.len() as u64);
+        assert!(src.is_empty());
+    }
+}
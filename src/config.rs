@@ -1,2 +1,314 @@
+use std::fmt;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+use bitcoin::Network;
+
+use crate::serialization::{FeatureFlag, Features};
+use crate::util::get_current_timestamp;
+
+// the default, it can be switched at runtime through the control api
 pub const DO_CONNECT_TO_NEW_NODES: bool = false;
 pub const PING_INTERVAL: u64 = 60;
+// a ping unanswered for PONG_TIMEOUT seconds is missed and another one goes out, after
+// MAX_MISSED_PONGS in a row the peer is dropped
+pub const PONG_TIMEOUT: u64 = 30;
+pub const MAX_MISSED_PONGS: u32 = 3;
+// our pings ask for a random number of pong bytes below this
+pub const MAX_PING_PONG_BYTES: u16 = 256;
+// seconds before giving up on a peer, the idle timeout has to leave room for a ping
+pub const CONNECT_TIMEOUT: u64 = 10;
+pub const HANDSHAKE_TIMEOUT: u64 = 10;
+pub const INIT_TIMEOUT: u64 = 30;
+pub const IDLE_TIMEOUT: u64 = 5 * PING_INTERVAL;
+// milliseconds between checks for pings, timeouts and the periodic jobs, messages are
+// handled as soon as they arrive
+pub const HOUSEKEEPING_INTERVAL_MILLIS: u64 = 1000;
+pub const PROVIDE_STORAGE: bool = false;
+pub const PEER_STORAGE_DIR: &str = "peer_storage";
+// bolt 1 caps a single blob at 65531 bytes
+pub const PEER_STORAGE_MAX_BLOB_SIZE: usize = 65531;
+pub const PEER_STORAGE_QUOTA: u64 = 64 * 1024 * 1024;
+pub const RELAY_GOSSIP: bool = true;
+// seconds between relay batches, bolt 7 suggests 60
+pub const RELAY_FLUSH_INTERVAL: u64 = 60;
+// hashes of relayed messages remembered to drop duplicates, the oldest are forgotten first
+pub const RELAY_SEEN_CAPACITY: usize = 100_000;
+// short ids per reply_channel_range, small enough to fit timestamps and checksums in one message
+pub const GOSSIP_QUERY_MAX_SHORT_IDS: usize = 2000;
+// minisketch reconciliation experiment: every RECONCILE_INTERVAL seconds, simulate reconciling
+// the last RECONCILE_WINDOW seconds of gossip between each pair of peers
+pub const RECONCILE_INTERVAL: u64 = 600;
+pub const RECONCILE_WINDOW: u32 = 3600;
+// differences a sketch can decode, each costs 4 bytes on the wire
+pub const RECONCILE_CAPACITY: usize = 64;
+// also send our sketches to peers, which only other lmprs instances understand
+pub const RECONCILE_LIVE: bool = false;
+// seconds to wait for a message to arrive from other peers before its spread is final
+pub const PROPAGATION_WINDOW: u64 = 600;
+pub const PROPAGATION_EXPORT_INTERVAL: u64 = 300;
+pub const PROPAGATION_EXPORT_FILE: &str = "propagation.csv";
+// seconds between per-peer traffic tables, the full report is written on shutdown
+pub const STATS_INTERVAL: u64 = 300;
+pub const PEER_REPORT_FILE: &str = "peers.json";
+// received messages kept for the api
+pub const MESSAGE_TAIL_LENGTH: usize = 100;
+// messages buffered for each feed subscriber before it starts skipping
+pub const FEED_CAPACITY: usize = 1024;
+// gossip rate limits, a burst of updates and then one more every interval seconds, like lnd
+pub const CHANNEL_UPDATE_BURST: u32 = 10;
+pub const CHANNEL_UPDATE_INTERVAL: u64 = 60;
+pub const NODE_ANNOUNCEMENT_BURST: u32 = 5;
+pub const NODE_ANNOUNCEMENT_INTERVAL: u64 = 300;
+// re-sending the same policy sooner than this is spam, bolt 7 refreshes take two weeks
+pub const NEAR_DUPLICATE_INTERVAL: u32 = 24 * 60 * 60;
+// more than FLAP_LIMIT disabled bit toggles within FLAP_WINDOW seconds is flapping
+pub const FLAP_LIMIT: usize = 4;
+pub const FLAP_WINDOW: u64 = 60 * 60;
+// bolt 7 prunes channels whose latest update is older than two weeks
+pub const STALE_CHANNEL_AGE: u64 = 14 * 24 * 60 * 60;
+pub const PRUNE_INTERVAL: u64 = 60 * 60;
+pub const LOCAL_FEATURES: &[(Features, FeatureFlag)] = &[
+    (Features::DataLossProtect, FeatureFlag::Optional),
+    (Features::UpfrontShutdownScript, FeatureFlag::Optional),
+    (Features::GossipQueries, FeatureFlag::Optional),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GossipFilterPolicy {
+    // everything the peer has, as far back as it goes
+    FullHistory,
+    // only gossip created from the moment the filter is sent
+    LiveOnly,
+    Window {
+        first_timestamp: u32,
+        timestamp_range: u32,
+    },
+}
+
+impl GossipFilterPolicy {
+    // (first_timestamp, timestamp_range) for a gossip_timestamp_filter
+    pub fn timestamps(&self) -> (u32, u32) {
+        match self {
+            GossipFilterPolicy::FullHistory => (0, u32::MAX),
+            GossipFilterPolicy::LiveOnly => (get_current_timestamp() as u32, u32::MAX),
+            GossipFilterPolicy::Window {
+                first_timestamp,
+                timestamp_range,
+            } => (*first_timestamp, *timestamp_range),
+        }
+    }
+}
+
+// the same form --gossip takes
+impl fmt::Display for GossipFilterPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            GossipFilterPolicy::FullHistory => write!(f, "full"),
+            GossipFilterPolicy::LiveOnly => write!(f, "live"),
+            GossipFilterPolicy::Window {
+                first_timestamp,
+                timestamp_range,
+            } => write!(f, "window:{}:{}", first_timestamp, timestamp_range),
+        }
+    }
+}
+
+impl FromStr for GossipFilterPolicy {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let parts: Vec<&str> = value.split(':').collect();
+        match parts.as_slice() {
+            ["full"] => Ok(GossipFilterPolicy::FullHistory),
+            ["live"] => Ok(GossipFilterPolicy::LiveOnly),
+            ["window", first_timestamp, timestamp_range] => Ok(GossipFilterPolicy::Window {
+                first_timestamp: first_timestamp
+                    .parse()
+                    .map_err(|_| format!("Invalid timestamp: {}", first_timestamp))?,
+                timestamp_range: timestamp_range
+                    .parse()
+                    .map_err(|_| format!("Invalid timestamp range: {}", timestamp_range))?,
+            }),
+            _ => Err(format!("Unknown gossip policy: {}", value)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timeouts {
+    pub connect: Duration,
+    // all three acts
+    pub handshake: Duration,
+    // from the end of the handshake until the peer's init
+    pub init: Duration,
+    // between two messages from the peer
+    pub idle: Duration,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Timeouts {
+            connect: Duration::from_secs(CONNECT_TIMEOUT),
+            handshake: Duration::from_secs(HANDSHAKE_TIMEOUT),
+            init: Duration::from_secs(INIT_TIMEOUT),
+            idle: Duration::from_secs(IDLE_TIMEOUT),
+        }
+    }
+}
+
+pub struct Config {
+    pub network: Network,
+    pub gossip_filter: GossipFilterPolicy,
+    // ask gossip_queries peers for every channel they know of after init
+    pub query_channel_range: bool,
+    // sqlite database to keep gossip in, needs the sqlite feature
+    pub db_path: Option<PathBuf>,
+    // file to append every decrypted message to
+    pub capture_path: Option<PathBuf>,
+    // pcapng file for wireshark with the decrypted messages as tcp segments
+    pub pcap_path: Option<PathBuf>,
+    // BOLT 8 session secrets, for decrypting a tcpdump of the same connections
+    pub keylog_path: Option<PathBuf>,
+    // capture to feed through the graph instead of connecting to anyone
+    pub replay_path: Option<PathBuf>,
+    pub timeouts: Timeouts,
+    // where to serve prometheus metrics
+    pub metrics_addr: Option<SocketAddr>,
+    // where to serve the json api
+    pub api_addr: Option<SocketAddr>,
+    // where to serve the json api with the control requests
+    pub control_addr: Option<SocketAddr>,
+    // where to serve the websocket feed of received messages
+    pub feed_addr: Option<SocketAddr>,
+    // print received messages as one json object per line instead of debug output
+    pub json_output: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            network: Network::Bitcoin,
+            gossip_filter: GossipFilterPolicy::FullHistory,
+            query_channel_range: false,
+            db_path: None,
+            capture_path: None,
+            pcap_path: None,
+            keylog_path: None,
+            replay_path: None,
+            timeouts: Timeouts::default(),
+            metrics_addr: None,
+            api_addr: None,
+            control_addr: None,
+            feed_addr: None,
+            json_output: false,
+        }
+    }
+}
+
+impl Config {
+    // pulls the --flags out of the arguments and returns the rest
+    pub fn from_args(args: &[String]) -> Result<(Config, Vec<String>), String> {
+        let mut config = Config::default();
+        let mut rest = Vec::new();
+        for arg in args {
+            let (flag, value) = match arg.strip_prefix("--") {
+                Some(flag) => flag.split_once('=').unwrap_or((flag, "")),
+                None => {
+                    rest.push(arg.clone());
+                    continue;
+                }
+            };
+            match flag {
+                "network" => config.network = parse_network(value)?,
+                "gossip" => config.gossip_filter = GossipFilterPolicy::from_str(value)?,
+                "query-channel-range" => config.query_channel_range = true,
+                "db" => config.db_path = Some(PathBuf::from(value)),
+                "capture" => config.capture_path = Some(PathBuf::from(value)),
+                "pcap" => config.pcap_path = Some(PathBuf::from(value)),
+                "keylog" => config.keylog_path = Some(PathBuf::from(value)),
+                "replay" => config.replay_path = Some(PathBuf::from(value)),
+                "connect-timeout" => config.timeouts.connect = parse_seconds(value)?,
+                "handshake-timeout" => config.timeouts.handshake = parse_seconds(value)?,
+                "init-timeout" => config.timeouts.init = parse_seconds(value)?,
+                "idle-timeout" => config.timeouts.idle = parse_seconds(value)?,
+                "metrics" => config.metrics_addr = Some(parse_addr(value)?),
+                "api" => config.api_addr = Some(parse_addr(value)?),
+                "control" => config.control_addr = Some(parse_addr(value)?),
+                "feed" => config.feed_addr = Some(parse_addr(value)?),
+                "json" => config.json_output = true,
+                _ => return Err(format!("Unknown option: --{}", flag)),
+            }
+        }
+        Ok((config, rest))
+    }
+}
+
+fn parse_network(value: &str) -> Result<Network, String> {
+    match value {
+        "mainnet" => Ok(Network::Bitcoin),
+        _ => Network::from_str(value).map_err(|_| format!("Unknown network: {}", value)),
+    }
+}
+
+fn parse_addr(value: &str) -> Result<SocketAddr, String> {
+    value
+        .parse()
+        .map_err(|_| format!("Invalid address: {}", value))
+}
+
+fn parse_seconds(value: &str) -> Result<Duration, String> {
+    match value.parse() {
+        Ok(seconds) if seconds > 0 => Ok(Duration::from_secs(seconds)),
+        _ => Err(format!("Invalid timeout: {}", value)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::serialization::ChainHashElement;
+
+    #[test]
+    fn test_network_from_args() {
+        let args = vec!["--network=testnet".to_string(), "node".to_string()];
+        let (config, rest) = Config::from_args(&args).unwrap();
+        assert_eq!(config.network, Network::Testnet);
+        assert_eq!(rest, vec!["node".to_string()]);
+        assert!(Config::from_args(&["--network=moon".to_string()]).is_err());
+
+        let (config, _) = Config::from_args(&["--network=mainnet".to_string()]).unwrap();
+        assert_eq!(
+            hex::encode(ChainHashElement::from_network(config.network).value),
+            "6fe28c0ab6f1b372c1a6a246ae63f74f931e8365e15a089c68d6190000000000"
+        );
+    }
+
+    #[test]
+    fn test_gossip_filter_policy_from_args() {
+        let (config, _) = Config::from_args(&["--gossip=window:1000:60".to_string()]).unwrap();
+        assert_eq!(config.gossip_filter.timestamps(), (1000, 60));
+        let (config, _) = Config::from_args(&[]).unwrap();
+        assert_eq!(config.gossip_filter.timestamps(), (0, u32::MAX));
+        assert!(Config::from_args(&["--gossip=window:1000".to_string()]).is_err());
+        assert!(!config.query_channel_range);
+        let (config, _) = Config::from_args(&["--query-channel-range".to_string()]).unwrap();
+        assert!(config.query_channel_range);
+        let policy: GossipFilterPolicy = "window:1000:60".parse().unwrap();
+        assert_eq!(policy.to_string(), "window:1000:60");
+    }
+
+    #[test]
+    fn test_timeouts_from_args() {
+        let (config, _) = Config::from_args(&["--init-timeout=5".to_string()]).unwrap();
+        assert_eq!(config.timeouts.init, Duration::from_secs(5));
+        assert_eq!(
+            config.timeouts.connect,
+            Duration::from_secs(CONNECT_TIMEOUT)
+        );
+        assert!(Config::from_args(&["--idle-timeout=0".to_string()]).is_err());
+        assert!(Config::from_args(&["--handshake-timeout=soon".to_string()]).is_err());
+    }
+}
@@ -0,0 +1,412 @@
+use std::collections::HashSet;
+use std::io;
+use std::sync::Arc;
+
+use bitcoin::base64::{engine::general_purpose::STANDARD, Engine};
+use bitcoin::hashes::{sha1, Hash};
+use serde_json::{json, Value};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::sync::mpsc;
+
+use crate::http::{read_request, respond, Request};
+use crate::message_decoder::MessageContainer;
+
+// RFC 6455, appended to the client's key for the accept header
+const WEBSOCKET_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+// clients have nothing to send us but close, ping and pong frames, anything they do send
+// has to be small
+const MAX_CLIENT_FRAME: u64 = 1024;
+
+const OPCODE_CONTINUATION: u8 = 0x0;
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_BINARY: u8 = 0x2;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xa;
+// the close status we fail a connection with
+const PROTOCOL_ERROR: u16 = 1002;
+
+// a received message as json, encoded once however many subscribers there are
+#[derive(Debug, Clone)]
+pub struct FeedMessage {
+    pub peer: [u8; 33],
+    pub message_type: String,
+    pub json: Arc<String>,
+}
+
+impl FeedMessage {
+    pub fn new(received_at: u64, peer: [u8; 33], message: &MessageContainer) -> Self {
+        let json = message_json(received_at, &peer, message);
+        FeedMessage {
+            peer,
+            message_type: json["message"]["type"].as_str().unwrap_or("").to_string(),
+            json: Arc::new(json.to_string()),
+        }
+    }
+}
+
+// how a received message looks everywhere we hand them out
+pub fn message_json(received_at: u64, peer: &[u8; 33], message: &MessageContainer) -> Value {
+    json!({
+        "received_at": received_at,
+        "peer": hex::encode(peer),
+        "message": serde_json::to_value(message).unwrap_or(Value::Null),
+    })
+}
+
+// ?type=ChannelUpdate,NodeAnnouncement&peer=02ab...,03cd... where a peer can be a prefix
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Filter {
+    types: HashSet<String>,
+    peers: Vec<String>,
+}
+
+impl Filter {
+    pub fn from_request(request: &Request) -> Self {
+        let list = |key: &str| -> Vec<String> {
+            match request.query.get(key) {
+                Some(value) => value
+                    .split(',')
+                    .filter(|item| !item.is_empty())
+                    .map(|item| item.to_string())
+                    .collect(),
+                None => Vec::new(),
+            }
+        };
+        Filter {
+            types: list("type").into_iter().collect(),
+            peers: list("peer")
+                .into_iter()
+                .map(|peer| peer.to_lowercase())
+                .collect(),
+        }
+    }
+
+    pub fn matches(&self, message: &FeedMessage) -> bool {
+        if !self.types.is_empty() && !self.types.contains(&message.message_type) {
+            return false;
+        }
+        if !self.peers.is_empty() {
+            let peer = hex::encode(message.peer);
+            return self.peers.iter().any(|prefix| peer.starts_with(prefix));
+        }
+        true
+    }
+}
+
+fn accept_key(key: &str) -> String {
+    let hash = sha1::Hash::hash(format!("{}{}", key, WEBSOCKET_GUID).as_bytes());
+    STANDARD.encode(hash.to_byte_array())
+}
+
+// a single unmasked frame, servers never mask
+fn frame(opcode: u8, payload: &[u8]) -> Vec<u8> {
+    let mut frame = vec![0x80 | opcode];
+    match payload.len() {
+        len if len < 126 => frame.push(len as u8),
+        len if len <= u16::MAX as usize => {
+            frame.push(126);
+            frame.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        len => {
+            frame.push(127);
+            frame.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+    }
+    frame.extend_from_slice(payload);
+    frame
+}
+
+fn protocol_error(reason: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, reason)
+}
+
+// (fin, opcode, unmasked payload) of the next frame from the client
+async fn read_frame(reader: &mut OwnedReadHalf) -> io::Result<(bool, u8, Vec<u8>)> {
+    let mut head = [0u8; 2];
+    reader.read_exact(&mut head).await?;
+    let fin = head[0] & 0x80 != 0;
+    let opcode = head[0] & 0x0f;
+    // rfc 6455 has servers fail the connection on anything a client didn't mask
+    if head[1] & 0x80 == 0 {
+        return Err(protocol_error("unmasked frame"));
+    }
+    let len = match head[1] & 0x7f {
+        126 => reader.read_u16().await? as u64,
+        127 => reader.read_u64().await?,
+        len => len as u64,
+    };
+    if len > MAX_CLIENT_FRAME {
+        return Err(protocol_error("frame too large"));
+    }
+    // control frames can't be fragmented and fit in a short length
+    if opcode & 0x8 != 0 && (!fin || len > 125) {
+        return Err(protocol_error("fragmented or oversized control frame"));
+    }
+    let mut mask = [0u8; 4];
+    reader.read_exact(&mut mask).await?;
+    let mut payload = vec![0u8; len as usize];
+    reader.read_exact(&mut payload).await?;
+    for (i, byte) in payload.iter_mut().enumerate() {
+        *byte ^= mask[i % 4];
+    }
+    Ok((fin, opcode, payload))
+}
+
+// hands control frames to forward and drops data, which may come in fragments with control
+// frames in between. stops at the end of the stream or the first frame that breaks the rules
+async fn read_frames(mut reader: OwnedReadHalf, frames: mpsc::Sender<io::Result<(u8, Vec<u8>)>>) {
+    // whether a fragmented data message is still missing its last frame
+    let mut continuing = false;
+    loop {
+        let frame = match read_frame(&mut reader).await {
+            Ok((fin, opcode, payload)) => match opcode {
+                OPCODE_CONTINUATION if !continuing => {
+                    Err(protocol_error("continuation without a message"))
+                }
+                OPCODE_TEXT | OPCODE_BINARY if continuing => {
+                    Err(protocol_error("message inside a fragmented message"))
+                }
+                OPCODE_CONTINUATION | OPCODE_TEXT | OPCODE_BINARY => {
+                    continuing = !fin;
+                    continue;
+                }
+                OPCODE_CLOSE | OPCODE_PING | OPCODE_PONG => Ok((opcode, payload)),
+                _ => Err(protocol_error("unknown opcode")),
+            },
+            // the client hung up
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return,
+            Err(e) => Err(e),
+        };
+        let failed = frame.is_err();
+        if frames.send(frame).await.is_err() || failed {
+            return;
+        }
+    }
+}
+
+async fn handshake(stream: &mut TcpStream) -> io::Result<Option<Request>> {
+    let request = read_request(stream).await?;
+    let key = match request.headers.get("sec-websocket-key") {
+        Some(key) if request.method == "GET" && request.path == "/feed" => key,
+        _ => {
+            let status = if request.path == "/feed" { 400 } else { 404 };
+            respond(stream, status, "text/plain", b"").await?;
+            return Ok(None);
+        }
+    };
+    let head = format!(
+        "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+        accept_key(key)
+    );
+    stream.write_all(head.as_bytes()).await?;
+    Ok(Some(request))
+}
+
+async fn subscribe(
+    mut stream: TcpStream,
+    messages: broadcast::Sender<FeedMessage>,
+) -> io::Result<()> {
+    // subscribed before the upgrade is answered, so the client misses nothing after it
+    let mut receiver = messages.subscribe();
+    let filter = match handshake(&mut stream).await? {
+        Some(request) => Filter::from_request(&request),
+        None => return Ok(()),
+    };
+    let (reader, mut writer) = stream.into_split();
+    // frames are read in their own task, a read cut short by select! would lose bytes
+    let (frames, mut received) = mpsc::channel(8);
+    let reading = tokio::spawn(read_frames(reader, frames));
+    let result = forward(&mut writer, &mut receiver, &mut received, &filter).await;
+    reading.abort();
+    result
+}
+
+async fn forward(
+    writer: &mut OwnedWriteHalf,
+    messages: &mut broadcast::Receiver<FeedMessage>,
+    frames: &mut mpsc::Receiver<io::Result<(u8, Vec<u8>)>>,
+    filter: &Filter,
+) -> io::Result<()> {
+    loop {
+        tokio::select! {
+            message = messages.recv() => match message {
+                Ok(message) => {
+                    if filter.matches(&message) {
+                        writer.write_all(&frame(OPCODE_TEXT, message.json.as_bytes())).await?;
+                    }
+                }
+                Err(RecvError::Lagged(skipped)) => {
+                    println!("Feed subscriber fell behind, skipped {} messages", skipped);
+                }
+                Err(RecvError::Closed) => {
+                    writer.write_all(&frame(OPCODE_CLOSE, &[])).await?;
+                    return Ok(());
+                }
+            },
+            frame_received = frames.recv() => match frame_received {
+                // the client hung up
+                None => return Ok(()),
+                Some(Err(e)) => {
+                    writer.write_all(&frame(OPCODE_CLOSE, &PROTOCOL_ERROR.to_be_bytes())).await?;
+                    return Err(e);
+                }
+                Some(Ok((OPCODE_CLOSE, payload))) => {
+                    writer.write_all(&frame(OPCODE_CLOSE, &payload)).await?;
+                    return Ok(());
+                }
+                Some(Ok((OPCODE_PING, payload))) => {
+                    writer.write_all(&frame(OPCODE_PONG, &payload)).await?;
+                }
+                _ => (),
+            },
+        }
+    }
+}
+
+// pushes every received message to websocket clients of /feed
+pub async fn serve(listener: TcpListener, messages: broadcast::Sender<FeedMessage>) {
+    loop {
+        let (stream, _) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                println!("Failed to accept feed connection: {:?}", e);
+                continue;
+            }
+        };
+        let messages = messages.clone();
+        tokio::spawn(async move {
+            if let Err(e) = subscribe(stream, messages).await {
+                println!("Feed connection closed: {:?}", e);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message_decoder::MessageDecoder;
+
+    #[test]
+    fn test_accept_key() {
+        // the example from RFC 6455
+        assert_eq!(
+            accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+        assert_eq!(frame(OPCODE_TEXT, b"hi"), vec![0x81, 2, b'h', b'i']);
+        assert_eq!(&frame(OPCODE_TEXT, &[0; 300])[..4], &[0x81, 126, 1, 44]);
+    }
+
+    // a client past the upgrade, subscribed to pings from peers starting with 0202
+    async fn subscribed(messages: &broadcast::Sender<FeedMessage>) -> TcpStream {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(serve(listener, messages.clone()));
+
+        let mut client = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        client
+            .write_all(
+                b"GET /feed?type=Ping&peer=0202 HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\n\
+                  Connection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+                  Sec-WebSocket-Version: 13\r\n\r\n",
+            )
+            .await
+            .unwrap();
+        let mut head = Vec::new();
+        while !head.ends_with(b"\r\n\r\n") {
+            head.push(client.read_u8().await.unwrap());
+        }
+        assert!(head.starts_with(b"HTTP/1.1 101"));
+        client
+    }
+
+    // a short frame as a client sends it, masked unless the mask bit is taken out of the length
+    fn client_frame(first: u8, second: u8, payload: &[u8]) -> Vec<u8> {
+        let mask = [1, 2, 3, 4];
+        let mut frame = vec![first, second | payload.len() as u8];
+        if second & 0x80 != 0 {
+            frame.extend_from_slice(&mask);
+        }
+        frame.extend(payload.iter().enumerate().map(|(i, byte)| {
+            if second & 0x80 != 0 {
+                byte ^ mask[i % 4]
+            } else {
+                *byte
+            }
+        }));
+        frame
+    }
+
+    // (first byte, payload) of the next short frame from the server
+    async fn server_frame(client: &mut TcpStream) -> (u8, Vec<u8>) {
+        let mut head = [0u8; 2];
+        client.read_exact(&mut head).await.unwrap();
+        let mut payload = vec![0u8; head[1] as usize];
+        client.read_exact(&mut payload).await.unwrap();
+        (head[0], payload)
+    }
+
+    #[tokio::test]
+    async fn test_feed() {
+        let (messages, _) = broadcast::channel(16);
+        let mut client = subscribed(&messages).await;
+
+        let (ping, _) = MessageDecoder::from_bytes(&[0, 18, 0, 4, 0, 0]).unwrap();
+        let (pong, _) = MessageDecoder::from_bytes(&[0, 19, 0, 0]).unwrap();
+        messages.send(FeedMessage::new(1, [3; 33], &ping)).unwrap();
+        messages.send(FeedMessage::new(2, [2; 33], &pong)).unwrap();
+        messages.send(FeedMessage::new(3, [2; 33], &ping)).unwrap();
+
+        // only the last one gets through the filter
+        let mut frame_head = [0u8; 2];
+        client.read_exact(&mut frame_head).await.unwrap();
+        assert_eq!(frame_head[0], 0x81);
+        let len = match frame_head[1] {
+            126 => client.read_u16().await.unwrap() as usize,
+            len => len as usize,
+        };
+        let mut payload = vec![0u8; len];
+        client.read_exact(&mut payload).await.unwrap();
+        let json: Value = serde_json::from_slice(&payload).unwrap();
+        assert_eq!(json["received_at"], 3);
+        assert_eq!(json["message"]["type"], "Ping");
+    }
+
+    #[tokio::test]
+    async fn test_client_frames() {
+        let (messages, _) = broadcast::channel(16);
+        let mut client = subscribed(&messages).await;
+        // a ping in the middle of a fragmented text message is answered
+        client
+            .write_all(&client_frame(OPCODE_TEXT, 0x80, b"hel"))
+            .await
+            .unwrap();
+        client
+            .write_all(&client_frame(0x80 | OPCODE_PING, 0x80, b"hi"))
+            .await
+            .unwrap();
+        client
+            .write_all(&client_frame(0x80 | OPCODE_CONTINUATION, 0x80, b"lo"))
+            .await
+            .unwrap();
+        assert_eq!(
+            server_frame(&mut client).await,
+            (0x80 | OPCODE_PONG, b"hi".to_vec())
+        );
+        // an unmasked frame fails the connection
+        client
+            .write_all(&client_frame(0x80 | OPCODE_PING, 0, b"hi"))
+            .await
+            .unwrap();
+        assert_eq!(
+            server_frame(&mut client).await,
+            (0x80 | OPCODE_CLOSE, PROTOCOL_ERROR.to_be_bytes().to_vec())
+        );
+        assert_eq!(client.read(&mut [0u8; 1]).await.unwrap(), 0);
+    }
+}
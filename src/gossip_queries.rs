@@ -0,0 +1,292 @@
+use std::collections::HashSet;
+
+use crate::config::GOSSIP_QUERY_MAX_SHORT_IDS;
+use crate::graph::{ChannelInfo, NetworkGraph};
+use crate::message_decoder::MessageContainer;
+use crate::messages::{
+    QueryChannelRangeMessage, QueryShortChannelIdsMessage, ReplyChannelRangeMessage,
+    ReplyShortChannelIdsEndMessage, QUERY_OPTION_CHECKSUMS, QUERY_OPTION_TIMESTAMPS,
+};
+use crate::serialization::{
+    decode_short_ids, encode_short_ids, encode_tlv_stream, SerializationError, TLVRecord,
+};
+
+// reply_channel_range tlv types
+const TLV_TIMESTAMPS: u64 = 1;
+const TLV_CHECKSUMS: u64 = 3;
+
+pub fn reply_channel_range(
+    graph: &NetworkGraph,
+    query: &QueryChannelRangeMessage,
+) -> Vec<ReplyChannelRangeMessage> {
+    split_reply_channel_range(graph, query, GOSSIP_QUERY_MAX_SHORT_IDS)
+}
+
+fn split_reply_channel_range(
+    graph: &NetworkGraph,
+    query: &QueryChannelRangeMessage,
+    max_short_ids: usize,
+) -> Vec<ReplyChannelRangeMessage> {
+    // u64 so that a query for (0, u32::MAX) can't overflow
+    let first = query.first_blocknum as u64;
+    let end = first + query.number_of_blocks as u64;
+    let mut channels: Vec<&ChannelInfo> = graph
+        .channels
+        .values()
+        .filter(|channel| {
+            let height = channel.announcement.short_channel_id.block_height as u64;
+            first <= height && height < end
+        })
+        .collect();
+    channels.sort_by_key(|channel| channel.announcement.short_channel_id.sort_key());
+
+    // a block's channels all go in the same reply, so only split between blocks
+    let mut chunks: Vec<Vec<&ChannelInfo>> = vec![Vec::new()];
+    let mut last_height = None;
+    for channel in channels {
+        let height = channel.announcement.short_channel_id.block_height;
+        let chunk = chunks.last_mut().unwrap();
+        if chunk.len() >= max_short_ids && last_height != Some(height) {
+            chunks.push(vec![channel]);
+        } else {
+            chunk.push(channel);
+        }
+        last_height = Some(height);
+    }
+
+    // the replies cover the queried range back to back, with no gaps between them
+    let starts: Vec<u64> = chunks
+        .iter()
+        .enumerate()
+        .map(|(i, chunk)| match i {
+            0 => first,
+            _ => chunk[0].announcement.short_channel_id.block_height as u64,
+        })
+        .collect();
+    let query_option = query.query_option();
+    chunks
+        .iter()
+        .enumerate()
+        .map(|(i, chunk)| {
+            let start = starts[i];
+            let stop = starts.get(i + 1).copied().unwrap_or(end);
+            ReplyChannelRangeMessage {
+                chain_hash: query.chain_hash.clone(),
+                first_blocknum: start as u32,
+                number_of_blocks: (stop - start).min(u32::MAX as u64) as u32,
+                sync_complete: 1,
+                encoded_short_ids: encode_short_ids(
+                    &chunk
+                        .iter()
+                        .map(|channel| channel.announcement.short_channel_id.clone())
+                        .collect::<Vec<_>>(),
+                ),
+                reply_channel_range_tlvs: reply_tlvs(chunk, query_option),
+            }
+        })
+        .collect()
+}
+
+fn reply_tlvs(channels: &[&ChannelInfo], query_option: u64) -> Vec<u8> {
+    let mut records = Vec::new();
+    if query_option & QUERY_OPTION_TIMESTAMPS != 0 {
+        // encoding type 0, then a timestamp per direction, 0 when we have no update
+        let mut value = vec![0u8];
+        for channel in channels {
+            for update in &channel.updates {
+                let timestamp = update.as_ref().map(|u| u.timestamp).unwrap_or(0);
+                value.extend(timestamp.to_be_bytes());
+            }
+        }
+        records.push(TLVRecord {
+            tlv_type: TLV_TIMESTAMPS,
+            value,
+        });
+    }
+    if query_option & QUERY_OPTION_CHECKSUMS != 0 {
+        let mut value = Vec::new();
+        for channel in channels {
+            for update in &channel.updates {
+                let checksum = update.as_ref().map(|u| u.checksum()).unwrap_or(0);
+                value.extend(checksum.to_be_bytes());
+            }
+        }
+        records.push(TLVRecord {
+            tlv_type: TLV_CHECKSUMS,
+            value,
+        });
+    }
+    encode_tlv_stream(&records)
+}
+
+// everything we know about the queried channels, followed by reply_short_channel_ids_end
+pub fn reply_short_channel_ids(
+    graph: &NetworkGraph,
+    query: &QueryShortChannelIdsMessage,
+) -> Result<Vec<MessageContainer>, SerializationError> {
+    let mut replies = Vec::new();
+    let mut sent_nodes = HashSet::new();
+    for short_id in decode_short_ids(&query.encoded_short_ids)? {
+        let channel = match graph.channels.get(&short_id) {
+            Some(channel) => channel,
+            None => continue,
+        };
+        replies.push(MessageContainer::ChannelAnnouncement(
+            channel.announcement.clone(),
+        ));
+        for update in channel.updates.iter().flatten() {
+            replies.push(MessageContainer::ChannelUpdate(update.clone()));
+        }
+        // node announcements are only valid after a channel_announcement for the node
+        for node_id in [
+            &channel.announcement.node_id_1,
+            &channel.announcement.node_id_2,
+        ] {
+            if let Some(node) = graph.nodes.get(node_id) {
+                if sent_nodes.insert(node_id.clone()) {
+                    replies.push(MessageContainer::NodeAnnouncement(node.clone()));
+                }
+            }
+        }
+    }
+    replies.push(MessageContainer::ReplyShortChannelIdsEnd(
+        ReplyShortChannelIdsEndMessage {
+            chain_hash: query.chain_hash.clone(),
+            full_information: 1,
+        },
+    ));
+    Ok(replies)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message_decoder::example_messages;
+    use crate::serialization::{decode_tlv_stream, ChainHashElement, ShortChannelIDElement};
+
+    fn example_graph() -> NetworkGraph {
+        let mut graph = NetworkGraph::new();
+        for message in example_messages() {
+            match message {
+                MessageContainer::ChannelAnnouncement(msg) => {
+                    let _ = graph.handle_channel_announcement(&msg);
+                }
+                MessageContainer::NodeAnnouncement(msg) => {
+                    let _ = graph.handle_node_announcement(&msg);
+                }
+                MessageContainer::ChannelUpdate(msg) => {
+                    let _ = graph.handle_channel_update(&msg);
+                }
+                _ => {}
+            }
+        }
+        graph
+    }
+
+    fn chain_hash(graph: &NetworkGraph) -> ChainHashElement {
+        graph
+            .channels
+            .values()
+            .next()
+            .unwrap()
+            .announcement
+            .chain_hash
+            .clone()
+    }
+
+    #[test]
+    fn test_reply_channel_range_covers_query() {
+        let mut graph = example_graph();
+        let chain_hash = chain_hash(&graph);
+        // copies of the example channel in other blocks, to force a split
+        let example = graph.channels.drain().next().unwrap().1.announcement;
+        let mut announcements = Vec::new();
+        for block_height in [100, 100, 200, 300] {
+            let mut announcement = example.clone();
+            announcement.short_channel_id = ShortChannelIDElement {
+                block_height,
+                tx_index: announcements.len() as u32,
+                output_index: 0,
+            };
+            announcements.push(announcement);
+        }
+        for announcement in announcements {
+            graph.channels.insert(
+                announcement.short_channel_id.clone(),
+                ChannelInfo {
+                    announcement,
+                    updates: [None, None],
+                    first_seen: 0,
+                },
+            );
+        }
+
+        let query = QueryChannelRangeMessage::new(chain_hash, 50, 300);
+        let replies = split_reply_channel_range(&graph, &query, 1);
+        let ranges: Vec<(u32, u32)> = replies
+            .iter()
+            .map(|r| (r.first_blocknum, r.number_of_blocks))
+            .collect();
+        assert_eq!(ranges, vec![(50, 150), (200, 100), (300, 50)]);
+        let short_ids: Vec<usize> = replies
+            .iter()
+            .map(|r| decode_short_ids(&r.encoded_short_ids).unwrap().len())
+            .collect();
+        assert_eq!(short_ids, vec![2, 1, 1]);
+        assert!(replies
+            .iter()
+            .all(|r| r.reply_channel_range_tlvs.is_empty()));
+    }
+
+    #[test]
+    fn test_reply_channel_range_timestamps_and_checksums() {
+        let graph = example_graph();
+        let channel = graph.channels.values().next().unwrap();
+        let mut query = QueryChannelRangeMessage::new(chain_hash(&graph), 0, u32::MAX);
+        query.set_query_option(QUERY_OPTION_TIMESTAMPS | QUERY_OPTION_CHECKSUMS);
+
+        let replies = reply_channel_range(&graph, &query);
+        assert_eq!(replies.len(), 1);
+        assert_eq!(replies[0].number_of_blocks, u32::MAX);
+        let records = decode_tlv_stream(&replies[0].reply_channel_range_tlvs).unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].value.len(), 1 + 8);
+        let timestamp = u32::from_be_bytes(records[0].value[1..5].try_into().unwrap());
+        let expected = channel.updates[0]
+            .as_ref()
+            .map(|u| u.timestamp)
+            .unwrap_or(0);
+        assert_eq!(timestamp, expected);
+        assert_eq!(records[1].value.len(), 8);
+    }
+
+    #[test]
+    fn test_reply_short_channel_ids_replays_gossip() {
+        let graph = example_graph();
+        let channel = graph.channels.values().next().unwrap();
+        let unknown = ShortChannelIDElement {
+            block_height: 1,
+            tx_index: 2,
+            output_index: 3,
+        };
+        let query = QueryShortChannelIdsMessage::new(
+            chain_hash(&graph),
+            &[channel.announcement.short_channel_id.clone(), unknown],
+        );
+        let replies = reply_short_channel_ids(&graph, &query).unwrap();
+        let updates = channel.updates.iter().flatten().count();
+        assert!(matches!(
+            replies[0],
+            MessageContainer::ChannelAnnouncement(_)
+        ));
+        assert!(matches!(
+            replies.last().unwrap(),
+            MessageContainer::ReplyShortChannelIdsEnd(_)
+        ));
+        let nodes = replies
+            .iter()
+            .filter(|r| matches!(r, MessageContainer::NodeAnnouncement(_)))
+            .count();
+        assert_eq!(replies.len(), 1 + updates + nodes + 1);
+    }
+}
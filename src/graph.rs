@@ -0,0 +1,349 @@
+use std::collections::HashMap;
+
+use serde::Serialize;
+
+use crate::config::{PRUNE_INTERVAL, STALE_CHANNEL_AGE};
+use crate::messages::{ChannelAnnouncementMessage, ChannelUpdateMessage, NodeAnnouncementMessage};
+use crate::serialization::{PointElement, ShortChannelIDElement};
+use crate::spam::{SpamFilter, SpamReason};
+use crate::util::get_current_timestamp;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum GossipError {
+    InvalidSignature,
+    UnknownChannel,
+    // bolt 7: a node announcement is ignored until we know one of the node's channels
+    UnknownNode,
+    // we already have this exact message or a newer one
+    Duplicate,
+    Spam(SpamReason),
+    // an update for a zombie channel that is too old to bring it back
+    Stale,
+}
+
+#[derive(Serialize)]
+pub struct ChannelInfo {
+    pub announcement: ChannelAnnouncementMessage,
+    // indexed by the direction bit of channel_flags
+    pub updates: [Option<ChannelUpdateMessage>; 2],
+    pub first_seen: u64,
+}
+
+impl ChannelInfo {
+    // a direction is stale when its latest update, or the announcement if it has none, is too old
+    fn is_stale(&self, cutoff: u64) -> bool {
+        self.updates.iter().all(|update| match update {
+            Some(update) => (update.timestamp as u64) < cutoff,
+            None => self.first_seen < cutoff,
+        })
+    }
+
+    fn node_id(&self, direction: usize) -> &PointElement {
+        match direction {
+            0 => &self.announcement.node_id_1,
+            _ => &self.announcement.node_id_2,
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct NetworkGraph {
+    pub channels: HashMap<ShortChannelIDElement, ChannelInfo>,
+    pub nodes: HashMap<PointElement, NodeAnnouncementMessage>,
+    pub signature_failures: u64,
+    pub spam: SpamFilter,
+    // pruned channels, kept so that a fresh update can bring them back
+    pub zombies: HashMap<ShortChannelIDElement, ChannelInfo>,
+    pub resurrected: u64,
+    last_prune: u64,
+    // live channels per node, so a node announcement doesn't have to search the graph
+    node_channels: HashMap<PointElement, usize>,
+}
+
+#[allow(dead_code)]
+#[derive(Debug)]
+pub struct PruneReport {
+    pub at: u64,
+    pub pruned: usize,
+    pub resurrected: u64,
+    pub channels: usize,
+    pub zombies: usize,
+}
+
+impl NetworkGraph {
+    pub fn new() -> Self {
+        NetworkGraph::default()
+    }
+
+    // the plain variants are only used when reloading from the database
+    #[allow(dead_code)]
+    pub fn handle_channel_announcement(
+        &mut self,
+        msg: &ChannelAnnouncementMessage,
+    ) -> Result<(), GossipError> {
+        self.handle_channel_announcement_at(msg, get_current_timestamp())
+    }
+
+    // the _at variants take the time the message arrived, which differs from now on replay
+    pub fn handle_channel_announcement_at(
+        &mut self,
+        msg: &ChannelAnnouncementMessage,
+        now: u64,
+    ) -> Result<(), GossipError> {
+        if self.channels.contains_key(&msg.short_channel_id)
+            || self.zombies.contains_key(&msg.short_channel_id)
+        {
+            return Err(GossipError::Duplicate);
+        }
+        if !msg.verify_signatures() {
+            self.signature_failures += 1;
+            return Err(GossipError::InvalidSignature);
+        }
+        let channel = ChannelInfo {
+            announcement: msg.clone(),
+            updates: [None, None],
+            first_seen: now,
+        };
+        self.count_channel(&channel);
+        self.channels.insert(msg.short_channel_id.clone(), channel);
+        Ok(())
+    }
+
+    #[allow(dead_code)]
+    pub fn handle_node_announcement(
+        &mut self,
+        msg: &NodeAnnouncementMessage,
+    ) -> Result<(), GossipError> {
+        self.handle_node_announcement_at(msg, get_current_timestamp())
+    }
+
+    pub fn handle_node_announcement_at(
+        &mut self,
+        msg: &NodeAnnouncementMessage,
+        now: u64,
+    ) -> Result<(), GossipError> {
+        if let Some(known) = self.nodes.get(&msg.node_id) {
+            if known.timestamp >= msg.timestamp {
+                return Err(GossipError::Duplicate);
+            }
+        }
+        if !self.node_channels.contains_key(&msg.node_id) {
+            return Err(GossipError::UnknownNode);
+        }
+        if !msg.verify_signature() {
+            self.signature_failures += 1;
+            return Err(GossipError::InvalidSignature);
+        }
+        // only checked once the signature is good, so nobody can spend another node's budget
+        self.spam
+            .check_node_announcement(msg, self.nodes.get(&msg.node_id), now)
+            .map_err(GossipError::Spam)?;
+        self.nodes.insert(msg.node_id.clone(), msg.clone());
+        Ok(())
+    }
+
+    fn count_channel(&mut self, channel: &ChannelInfo) {
+        for direction in 0..2 {
+            *self
+                .node_channels
+                .entry(channel.node_id(direction).clone())
+                .or_insert(0) += 1;
+        }
+    }
+
+    // a node left without live channels is forgotten until one comes back
+    fn uncount_channel(&mut self, channel: &ChannelInfo) {
+        for direction in 0..2 {
+            let node_id = channel.node_id(direction);
+            if let Some(count) = self.node_channels.get_mut(node_id) {
+                *count -= 1;
+                if *count == 0 {
+                    self.node_channels.remove(node_id);
+                }
+            }
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn handle_channel_update(&mut self, msg: &ChannelUpdateMessage) -> Result<(), GossipError> {
+        self.handle_channel_update_at(msg, get_current_timestamp())
+    }
+
+    pub fn handle_channel_update_at(
+        &mut self,
+        msg: &ChannelUpdateMessage,
+        now: u64,
+    ) -> Result<(), GossipError> {
+        let zombie = self.zombies.contains_key(&msg.short_channel_id);
+        if zombie && (msg.timestamp as u64) < now.saturating_sub(STALE_CHANNEL_AGE) {
+            return Err(GossipError::Stale);
+        }
+        // a zombie goes through the same checks and only comes back if its update is accepted
+        let channel = match self.channels.get_mut(&msg.short_channel_id) {
+            Some(channel) => channel,
+            None => match self.zombies.get_mut(&msg.short_channel_id) {
+                Some(channel) => channel,
+                None => return Err(GossipError::UnknownChannel),
+            },
+        };
+        let direction = msg.direction();
+        if let Some(known) = &channel.updates[direction] {
+            if known.timestamp >= msg.timestamp {
+                return Err(GossipError::Duplicate);
+            }
+        }
+        if !msg.verify_signature(channel.node_id(direction)) {
+            self.signature_failures += 1;
+            return Err(GossipError::InvalidSignature);
+        }
+        self.spam
+            .check_channel_update(
+                msg,
+                channel.updates[direction].as_ref(),
+                channel.node_id(direction),
+                now,
+            )
+            .map_err(GossipError::Spam)?;
+        channel.updates[direction] = Some(msg.clone());
+        if zombie {
+            let channel = self.zombies.remove(&msg.short_channel_id).unwrap();
+            self.count_channel(&channel);
+            self.channels.insert(msg.short_channel_id.clone(), channel);
+            self.resurrected += 1;
+        }
+        Ok(())
+    }
+
+    // whether a pruning run is due, at most once per interval
+    pub fn prune_due(&mut self) -> bool {
+        let now = get_current_timestamp();
+        if self.last_prune + PRUNE_INTERVAL > now {
+            return false;
+        }
+        self.last_prune = now;
+        true
+    }
+
+    // bolt 7 forgets channels whose latest updates are older than two weeks, we keep them as zombies
+    pub fn prune_stale(&mut self, now: u64) -> PruneReport {
+        let cutoff = now.saturating_sub(STALE_CHANNEL_AGE);
+        let stale: Vec<ShortChannelIDElement> = self
+            .channels
+            .iter()
+            .filter(|(_, channel)| channel.is_stale(cutoff))
+            .map(|(short_channel_id, _)| short_channel_id.clone())
+            .collect();
+        for short_channel_id in &stale {
+            let channel = self.channels.remove(short_channel_id).unwrap();
+            self.uncount_channel(&channel);
+            self.zombies.insert(short_channel_id.clone(), channel);
+        }
+        PruneReport {
+            at: now,
+            pruned: stale.len(),
+            resurrected: self.resurrected,
+            channels: self.channels.len(),
+            zombies: self.zombies.len(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message_decoder::{example_messages, MessageContainer};
+
+    #[test]
+    fn test_graph_accepts_signed_gossip_once() {
+        let mut graph = NetworkGraph::new();
+        let mut updates = 0;
+        for message in example_messages() {
+            match message {
+                MessageContainer::ChannelAnnouncement(msg) => {
+                    assert_eq!(graph.handle_channel_announcement(&msg), Ok(()));
+                    assert_eq!(
+                        graph.handle_channel_announcement(&msg),
+                        Err(GossipError::Duplicate)
+                    );
+                }
+                MessageContainer::NodeAnnouncement(msg) => {
+                    assert_eq!(graph.handle_node_announcement(&msg), Ok(()));
+                }
+                MessageContainer::ChannelUpdate(msg)
+                    if graph.handle_channel_update(&msg).is_ok() =>
+                {
+                    updates += 1;
+                }
+                _ => {}
+            }
+        }
+        assert_eq!(graph.channels.len(), 1);
+        assert_eq!(graph.nodes.len(), 1);
+        assert!(updates > 0);
+        assert_eq!(graph.signature_failures, 0);
+    }
+
+    #[test]
+    fn test_node_announcement_needs_a_channel() {
+        let mut graph = NetworkGraph::new();
+        for message in example_messages() {
+            if let MessageContainer::NodeAnnouncement(msg) = message {
+                assert_eq!(
+                    graph.handle_node_announcement(&msg),
+                    Err(GossipError::UnknownNode)
+                );
+            }
+        }
+        assert!(graph.nodes.is_empty());
+    }
+
+    #[test]
+    fn test_prune_and_resurrect_zombies() {
+        let mut graph = NetworkGraph::new();
+        let mut updates = Vec::new();
+        for message in example_messages() {
+            match message {
+                MessageContainer::ChannelAnnouncement(msg) => {
+                    graph.handle_channel_announcement(&msg).unwrap();
+                }
+                MessageContainer::ChannelUpdate(msg) => updates.push(msg),
+                _ => {}
+            }
+        }
+        updates.sort_by_key(|update| update.timestamp);
+        let newest = updates.pop().unwrap();
+        let mut latest = 0;
+        for update in &updates {
+            graph
+                .handle_channel_update_at(update, update.timestamp as u64)
+                .unwrap();
+            latest = latest.max(update.timestamp as u64);
+        }
+
+        let report = graph.prune_stale(latest + STALE_CHANNEL_AGE);
+        assert_eq!((report.pruned, report.channels), (0, 1));
+        let now = latest + STALE_CHANNEL_AGE + 1;
+        let report = graph.prune_stale(now);
+        assert_eq!((report.pruned, report.channels, report.zombies), (1, 0, 1));
+        assert!(graph.node_channels.is_empty());
+
+        // an update we already have doesn't bring it back
+        let known = updates.last().unwrap();
+        assert_eq!(
+            graph.handle_channel_update_at(known, known.timestamp as u64),
+            Err(GossipError::Duplicate)
+        );
+        assert_eq!((graph.channels.len(), graph.zombies.len()), (0, 1));
+
+        // the newest update is only fresh enough when it isn't two weeks old yet
+        let far_future = newest.timestamp as u64 + STALE_CHANNEL_AGE + 1;
+        assert_eq!(
+            graph.handle_channel_update_at(&newest, far_future),
+            Err(GossipError::Stale)
+        );
+        assert_eq!(graph.handle_channel_update_at(&newest, now), Ok(()));
+        assert_eq!((graph.channels.len(), graph.zombies.len()), (1, 0));
+        assert_eq!(graph.node_channels.len(), 2);
+        assert_eq!(graph.resurrected, 1);
+    }
+}
@@ -0,0 +1,153 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+
+use crate::noise::{HandshakeKeys, KeyRotation};
+
+// appends session secrets in the spirit of SSLKEYLOGFILE, one secret per line:
+//   <label> <initiator ephemeral public key> [<generation> <chaining key>] <key>
+// the ephemeral key is the one in act one, so tooling can tell which tcp stream the keys
+// belong to. SENDING keys encrypt what we send, RECEIVING keys what the peer sends us.
+pub struct KeyLog {
+    file: File,
+}
+
+impl KeyLog {
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let mut options = OpenOptions::new();
+        options.append(true).create(true);
+        // the secrets decrypt every session, so only we may read them
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        let file = options.open(path)?;
+        Ok(KeyLog { file })
+    }
+
+    fn write_line(&mut self, line: String) -> io::Result<()> {
+        self.file.write_all(format!("{}\n", line).as_bytes())?;
+        self.file.flush()
+    }
+
+    pub fn log_handshake(&mut self, keys: &HandshakeKeys) -> io::Result<()> {
+        let id = hex::encode(keys.initiator_ephemeral.serialize());
+        for (label, key) in [
+            ("HANDSHAKE_TEMP_K1", keys.temp_k1),
+            ("HANDSHAKE_TEMP_K2", keys.temp_k2),
+            ("HANDSHAKE_TEMP_K3", keys.temp_k3),
+        ] {
+            self.write_line(format!("{} {} {}", label, id, hex::encode(key)))?;
+        }
+        for (sending, key) in [(true, keys.sk), (false, keys.rk)] {
+            self.log_rotation(
+                keys,
+                &KeyRotation {
+                    sending,
+                    generation: 0,
+                    ck: keys.ck,
+                    key,
+                },
+            )?;
+        }
+        Ok(())
+    }
+
+    // a transport key of the session the handshake keys belong to
+    pub fn log_rotation(&mut self, keys: &HandshakeKeys, rotation: &KeyRotation) -> io::Result<()> {
+        let label = if rotation.sending {
+            "SENDING_KEY"
+        } else {
+            "RECEIVING_KEY"
+        };
+        self.write_line(format!(
+            "{} {} {} {} {}",
+            label,
+            hex::encode(keys.initiator_ephemeral.serialize()),
+            rotation.generation,
+            hex::encode(rotation.ck),
+            hex::encode(rotation.key)
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::noise::{hkdf, PeerChannelEncryptor};
+    use bitcoin::secp256k1::{PublicKey, SecretKey};
+
+    fn secret(byte: u8) -> SecretKey {
+        SecretKey::from_slice(&[byte; 32]).unwrap()
+    }
+
+    // initiator test vectors from BOLT 8
+    #[test]
+    fn test_handshake_keys_match_bolt_8() {
+        let remote_static = PublicKey::from_slice(
+            &hex::decode("028d7500dd4c12685d1f568b4c2b5048e8534b873319f3a8daa612b469132ec7f7")
+                .unwrap(),
+        )
+        .unwrap();
+        let act_two = hex::decode("0002466d7fcae563e5cb09a0d1870bb580344804617879a14949cf22285f1bae3f276e2470b93aac583c9ef6eafca3f730ae").unwrap();
+        let mut encryptor = PeerChannelEncryptor::new_outbound(remote_static, secret(0x12));
+        encryptor.get_act_one().unwrap();
+        encryptor.process_act_two(&act_two, &secret(0x11)).unwrap();
+        let keys = encryptor.handshake_keys().unwrap().clone();
+        assert_eq!(
+            hex::encode(keys.sk),
+            "969ab31b4d288cedf6218839b27a3e2140827047f2c0f01bf5c04435d43511a9"
+        );
+        assert_eq!(
+            hex::encode(keys.rk),
+            "bb9020b8965f4df047e07f955f3c4b88418984aadc5cdb35096b9ea8fa5c3442"
+        );
+        assert_eq!(
+            hex::encode(keys.ck),
+            "919219dbb2920afa8db80f9a51787a840bcf111ed8d588caf9ab4be716e42b01"
+        );
+
+        // a new sending key after 500 messages, the receiving key is untouched
+        let path = std::env::temp_dir().join("lmprs_keylog_test.txt");
+        let _ = std::fs::remove_file(&path);
+        let mut log = KeyLog::open(&path).unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        log.log_handshake(&keys).unwrap();
+        for _ in 0..500 {
+            encryptor.encrypt_message(b"hello").unwrap();
+            for rotation in encryptor.take_rotations() {
+                log.log_rotation(&keys, &rotation).unwrap();
+            }
+        }
+        let lines: Vec<String> = std::fs::read_to_string(&path)
+            .unwrap()
+            .lines()
+            .map(|line| line.to_string())
+            .collect();
+        assert_eq!(lines.len(), 6);
+        let id = "036360e856310ce5d294e8be33fc807077dc56ac80d95d9cd4ddbd21325eff73f7";
+        assert_eq!(
+            lines[3],
+            format!(
+                "SENDING_KEY {} 0 {} {}",
+                id,
+                hex::encode(keys.ck),
+                hex::encode(keys.sk)
+            )
+        );
+        let (ck, sk) = hkdf(&keys.ck, &keys.sk);
+        assert_eq!(
+            lines[5],
+            format!(
+                "SENDING_KEY {} 1 {} {}",
+                id,
+                hex::encode(ck),
+                hex::encode(sk)
+            )
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,32 +1,136 @@
+use config::Config;
 use node::Node;
 use peer::MiniPeer;
 
+use crate::config::FEED_CAPACITY;
 use crate::util::new_random_secret_key;
 
 use std::env;
+use std::sync::{Arc, Mutex};
+use tokio::net::TcpListener;
+use tokio::sync::{broadcast, mpsc};
 
+mod api;
+mod capture;
+mod codec;
 mod config;
+mod feed;
+mod gossip_queries;
+mod graph;
+mod http;
+mod keylog;
 mod message_decoder;
 mod messages;
+mod metrics;
+mod minisketch;
 mod node;
 mod node_connection;
+mod noise;
+mod pcap;
 mod peer;
+mod peer_storage;
+mod ping;
+mod propagation;
+mod reconciliation;
+mod relay;
 mod serialization;
+mod spam;
+mod stats;
+#[cfg(feature = "sqlite")]
+mod store;
 mod util;
-mod vendor;
 
 #[tokio::main]
 async fn main() {
-    let mut peer = MiniPeer::new(new_random_secret_key());
+    let args: Vec<String> = env::args().skip(1).collect();
+    let (config, args) = match Config::from_args(&args) {
+        Ok(parsed) => parsed,
+        Err(e) => {
+            eprintln!("{}", e);
+            return;
+        }
+    };
+    // with a control address peers can be added later
+    if args.is_empty() && config.replay_path.is_none() && config.control_addr.is_none() {
+        println!("Usage: lmprs2 [--network=<mainnet|testnet|testnet4|signet|regtest>] [--query-channel-range] [--db=<path>] [--capture=<path>] [--pcap=<path>] [--keylog=<path>] [--connect-timeout=<secs>] [--handshake-timeout=<secs>] [--init-timeout=<secs>] [--idle-timeout=<secs>] [--metrics=<ip:port>] [--api=<ip:port>] [--control=<ip:port>] [--feed=<ip:port>] [--json] <node_address_1> ... <node_address_n>");
+        println!("       lmprs2 [--network=...] --replay=<path>");
+        return;
+    }
+    println!("Using network {}", config.network);
+    if config.db_path.is_some() && !cfg!(feature = "sqlite") {
+        eprintln!("--db needs lmprs2 to be built with --features sqlite");
+        return;
+    }
 
-    let args: Vec<String> = env::args().collect();
-    if args.len() < 2 {
-        println!("Usage: lmprs2 <node_address_1> ... <node_address_n>");
+    let replay_path = config.replay_path.clone();
+    let metrics_addr = config.metrics_addr;
+    let api_addr = config.api_addr;
+    let control_addr = config.control_addr;
+    let feed_addr = config.feed_addr;
+    let mut peer = MiniPeer::new(new_random_secret_key(), config);
+    if let Some(path) = replay_path {
+        if let Err(e) = peer.replay(&path) {
+            eprintln!("Error replaying {}: {}", path.display(), e);
+        }
         return;
     }
 
+    if let Some(addr) = metrics_addr {
+        let listener = match TcpListener::bind(addr).await {
+            Ok(listener) => listener,
+            Err(e) => {
+                eprintln!("Error serving metrics on {}: {}", addr, e);
+                return;
+            }
+        };
+        println!("Serving metrics on http://{}/metrics", addr);
+        let snapshot = Arc::new(Mutex::new(peer.render_metrics()));
+        peer.set_metrics(snapshot.clone());
+        tokio::spawn(metrics::serve(listener, snapshot));
+    }
+
+    // both addresses hand their requests to the same event loop
+    let (sender, receiver) = mpsc::channel(64);
+    if api_addr.is_some() || control_addr.is_some() {
+        peer.set_api(receiver);
+    }
+    for (addr, control) in [(api_addr, false), (control_addr, true)] {
+        let addr = match addr {
+            Some(addr) => addr,
+            None => continue,
+        };
+        let listener = match TcpListener::bind(addr).await {
+            Ok(listener) => listener,
+            Err(e) => {
+                eprintln!("Error serving the api on {}: {}", addr, e);
+                return;
+            }
+        };
+        if control {
+            println!("Serving the api with control requests on http://{}/", addr);
+        } else {
+            println!("Serving the api on http://{}/", addr);
+        }
+        tokio::spawn(api::serve(listener, sender.clone(), control));
+    }
+    drop(sender);
+
+    if let Some(addr) = feed_addr {
+        let listener = match TcpListener::bind(addr).await {
+            Ok(listener) => listener,
+            Err(e) => {
+                eprintln!("Error serving the feed on {}: {}", addr, e);
+                return;
+            }
+        };
+        println!("Serving the message feed on ws://{}/feed", addr);
+        let (sender, _) = broadcast::channel(FEED_CAPACITY);
+        peer.set_feed(sender.clone());
+        tokio::spawn(feed::serve(listener, sender));
+    }
+
     let mut nodes = Vec::new();
-    for arg in args.iter().skip(1) {
+    for arg in args.iter() {
         let node_str = arg;
         let node = match Node::from_str(node_str) {
             Some(node) => node,
@@ -45,12 +149,17 @@ async fn main() {
             Err(e) => eprintln!("Error connecting to node {:?}: {:?}", node.display_str(), e),
         }
     }
-    if peer.num_connections() == nodes.len() {
+    if !nodes.is_empty() && peer.num_connections() == nodes.len() {
         println!("Successfully connected to all nodes");
     }
-    if peer.num_connections() > 0 {
+    if peer.num_connections() > 0 || control_addr.is_some() {
         println!("Connected to {} nodes", peer.num_connections());
-        peer.event_loop().await;
+        tokio::select! {
+            _ = peer.event_loop() => (),
+            _ = tokio::signal::ctrl_c() => println!("Shutting down"),
+        }
+        peer.flush_store();
+        peer.write_peer_report();
     } else {
         println!("Failed to connect to any nodes");
     }
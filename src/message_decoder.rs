@@ -1,17 +1,23 @@
 use crate::messages::{
-    ChannelAnnouncementMessage, ChannelUpdateMessage, GossipTimestampFilterMessage, InitMessage,
-    MessageType, NodeAnnouncementMessage, PingMessage, PongMessage, QueryChannelRangeMessage,
-    ReplyChannelRangeMessage, UnknownMessage,
+    ChannelAnnouncementMessage, ChannelUpdateMessage, GossipSketchMessage,
+    GossipTimestampFilterMessage, InitMessage, MessageType, NodeAnnouncementMessage,
+    PeerStorageMessage, PeerStorageRetrievalMessage, PingMessage, PongMessage,
+    QueryChannelRangeMessage, QueryShortChannelIdsMessage, ReplyChannelRangeMessage,
+    ReplyShortChannelIdsEndMessage, SpliceAckMessage, SpliceInitMessage, SpliceLockedMessage,
+    StfuMessage, UnknownMessage,
 };
-use crate::serialization::MessageTypeElement;
 use crate::serialization::SerializableToBytes;
+use crate::serialization::{ChainHashElement, FeatureReport, MessageTypeElement};
+use serde::Serialize;
 
 #[derive(Debug)]
 pub enum MessageDecoderError {
     Error,
 }
 
-#[derive(Debug)]
+// as json the message's fields are tagged with its type, {"type": "Ping", ...}
+#[derive(Debug, Serialize)]
+#[serde(tag = "type")]
 #[allow(dead_code)]
 pub enum MessageContainer {
     Init(InitMessage),
@@ -23,10 +29,43 @@ pub enum MessageContainer {
     GossipTimestampFilter(GossipTimestampFilterMessage),
     QueryChannelRange(QueryChannelRangeMessage),
     ReplyChannelRange(ReplyChannelRangeMessage),
+    QueryShortChannelIds(QueryShortChannelIdsMessage),
+    ReplyShortChannelIdsEnd(ReplyShortChannelIdsEndMessage),
+    GossipSketch(GossipSketchMessage),
+    PeerStorage(PeerStorageMessage),
+    PeerStorageRetrieval(PeerStorageRetrievalMessage),
+    Stfu(StfuMessage),
+    SpliceInit(SpliceInitMessage),
+    SpliceAck(SpliceAckMessage),
+    SpliceLocked(SpliceLockedMessage),
     Unknown(UnknownMessage),
 }
 
 impl MessageContainer {
+    pub fn chain_hash(&self) -> Option<&ChainHashElement> {
+        match self {
+            MessageContainer::ChannelAnnouncement(message) => Some(&message.chain_hash),
+            MessageContainer::ChannelUpdate(message) => Some(&message.chain_hash),
+            MessageContainer::GossipTimestampFilter(message) => Some(&message.chain_hash),
+            MessageContainer::QueryChannelRange(message) => Some(&message.chain_hash),
+            MessageContainer::ReplyChannelRange(message) => Some(&message.chain_hash),
+            MessageContainer::QueryShortChannelIds(message) => Some(&message.chain_hash),
+            MessageContainer::ReplyShortChannelIdsEnd(message) => Some(&message.chain_hash),
+            MessageContainer::GossipSketch(message) => Some(&message.chain_hash),
+            _ => None,
+        }
+    }
+
+    // only messages carrying a feature vector have something to report
+    pub fn feature_report(&self) -> Option<FeatureReport> {
+        match self {
+            MessageContainer::Init(message) => Some(message.feature_report()),
+            MessageContainer::NodeAnnouncement(message) => Some(message.feature_report()),
+            MessageContainer::ChannelAnnouncement(message) => Some(message.feature_report()),
+            _ => None,
+        }
+    }
+
     pub fn to_bytes(&self) -> Vec<u8> {
         match self {
             MessageContainer::Init(message) => message.to_bytes(),
@@ -37,7 +76,16 @@ impl MessageContainer {
             MessageContainer::GossipTimestampFilter(message) => message.to_bytes(),
             MessageContainer::QueryChannelRange(message) => message.to_bytes(),
             MessageContainer::ReplyChannelRange(message) => message.to_bytes(),
+            MessageContainer::QueryShortChannelIds(message) => message.to_bytes(),
+            MessageContainer::ReplyShortChannelIdsEnd(message) => message.to_bytes(),
+            MessageContainer::GossipSketch(message) => message.to_bytes(),
             MessageContainer::ChannelUpdate(message) => message.to_bytes(),
+            MessageContainer::PeerStorage(message) => message.to_bytes(),
+            MessageContainer::PeerStorageRetrieval(message) => message.to_bytes(),
+            MessageContainer::Stfu(message) => message.to_bytes(),
+            MessageContainer::SpliceInit(message) => message.to_bytes(),
+            MessageContainer::SpliceAck(message) => message.to_bytes(),
+            MessageContainer::SpliceLocked(message) => message.to_bytes(),
             MessageContainer::Unknown(message) => message.to_bytes(),
         }
     }
@@ -51,7 +99,9 @@ impl MessageDecoder {
             Ok(message_type) => message_type,
             Err(_) => return Err(MessageDecoderError::Error),
         };
-        let message_type = MessageType::from_int(message_type_struct.id).unwrap();
+        // types we have never heard of are kept as unknown messages
+        let message_type =
+            MessageType::from_int(message_type_struct.id).unwrap_or(MessageType::Unknown);
         match message_type {
             MessageType::Init => {
                 let (message, data) = match InitMessage::from_bytes(bytes) {
@@ -109,6 +159,27 @@ impl MessageDecoder {
                 };
                 Ok((MessageContainer::QueryChannelRange(message), data))
             }
+            MessageType::QueryShortChannelIds => {
+                let (message, data) = match QueryShortChannelIdsMessage::from_bytes(bytes) {
+                    Ok(x) => x,
+                    Err(_) => return Err(MessageDecoderError::Error),
+                };
+                Ok((MessageContainer::QueryShortChannelIds(message), data))
+            }
+            MessageType::ReplyShortChannelIdsEnd => {
+                let (message, data) = match ReplyShortChannelIdsEndMessage::from_bytes(bytes) {
+                    Ok(x) => x,
+                    Err(_) => return Err(MessageDecoderError::Error),
+                };
+                Ok((MessageContainer::ReplyShortChannelIdsEnd(message), data))
+            }
+            MessageType::GossipSketch => {
+                let (message, data) = match GossipSketchMessage::from_bytes(bytes) {
+                    Ok(x) => x,
+                    Err(_) => return Err(MessageDecoderError::Error),
+                };
+                Ok((MessageContainer::GossipSketch(message), data))
+            }
             MessageType::ChannelUpdate => {
                 let (message, data) = match ChannelUpdateMessage::from_bytes(bytes) {
                     Ok(x) => x,
@@ -116,6 +187,48 @@ impl MessageDecoder {
                 };
                 Ok((MessageContainer::ChannelUpdate(message), data))
             }
+            MessageType::PeerStorage => {
+                let (message, data) = match PeerStorageMessage::from_bytes(bytes) {
+                    Ok(x) => x,
+                    Err(_) => return Err(MessageDecoderError::Error),
+                };
+                Ok((MessageContainer::PeerStorage(message), data))
+            }
+            MessageType::PeerStorageRetrieval => {
+                let (message, data) = match PeerStorageRetrievalMessage::from_bytes(bytes) {
+                    Ok(x) => x,
+                    Err(_) => return Err(MessageDecoderError::Error),
+                };
+                Ok((MessageContainer::PeerStorageRetrieval(message), data))
+            }
+            MessageType::Stfu => {
+                let (message, data) = match StfuMessage::from_bytes(bytes) {
+                    Ok(x) => x,
+                    Err(_) => return Err(MessageDecoderError::Error),
+                };
+                Ok((MessageContainer::Stfu(message), data))
+            }
+            MessageType::SpliceInit => {
+                let (message, data) = match SpliceInitMessage::from_bytes(bytes) {
+                    Ok(x) => x,
+                    Err(_) => return Err(MessageDecoderError::Error),
+                };
+                Ok((MessageContainer::SpliceInit(message), data))
+            }
+            MessageType::SpliceAck => {
+                let (message, data) = match SpliceAckMessage::from_bytes(bytes) {
+                    Ok(x) => x,
+                    Err(_) => return Err(MessageDecoderError::Error),
+                };
+                Ok((MessageContainer::SpliceAck(message), data))
+            }
+            MessageType::SpliceLocked => {
+                let (message, data) = match SpliceLockedMessage::from_bytes(bytes) {
+                    Ok(x) => x,
+                    Err(_) => return Err(MessageDecoderError::Error),
+                };
+                Ok((MessageContainer::SpliceLocked(message), data))
+            }
             _ => {
                 let (message, data) = match UnknownMessage::from_bytes(bytes) {
                     Ok(x) => x,
@@ -128,22 +241,35 @@ impl MessageDecoder {
 }
 
 #[cfg(test)]
-mod tests {
+pub fn read_example_messages() -> Vec<String> {
     use std::{
         fs::File,
         io::{BufRead, BufReader},
     };
+    // open examples file
+    let f = File::open("test/examples").unwrap();
+    let reader = BufReader::new(f);
+    let lines = reader.lines();
+    // return a vec of strings
+    lines.map(|line| line.unwrap()).collect()
+}
 
-    use super::*;
+// the example messages decoded, for tests all over the crate
+#[cfg(test)]
+pub fn example_messages() -> Vec<MessageContainer> {
+    read_example_messages()
+        .iter()
+        .map(|line| {
+            MessageDecoder::from_bytes(&hex::decode(line).unwrap())
+                .unwrap()
+                .0
+        })
+        .collect()
+}
 
-    fn read_example_messages() -> Vec<String> {
-        // open examples file
-        let f = File::open("test/examples").unwrap();
-        let reader = BufReader::new(f);
-        let lines = reader.lines();
-        // return a vec of strings
-        lines.map(|line| line.unwrap()).collect()
-    }
+#[cfg(test)]
+mod tests {
+    use super::*;
 
     #[test]
     fn test_messages_deserialize_and_serialize() {
@@ -156,4 +282,25 @@ mod tests {
             assert_eq!([msg.to_bytes(), remainder.to_vec()].concat(), initial_bytes);
         }
     }
+
+    #[test]
+    fn test_messages_as_json() {
+        for line in read_example_messages() {
+            let bytes = hex::decode(line).unwrap();
+            let (msg, _) = MessageDecoder::from_bytes(bytes.as_slice()).unwrap();
+            let json = serde_json::to_value(&msg).unwrap();
+            assert!(json["type"].is_string());
+            if let MessageContainer::ChannelAnnouncement(_) = msg {
+                assert_eq!(json["node_id_1"].as_str().unwrap().len(), 66);
+                assert_eq!(
+                    json["short_channel_id"]
+                        .as_str()
+                        .unwrap()
+                        .split('x')
+                        .count(),
+                    3
+                );
+            }
+        }
+    }
 }
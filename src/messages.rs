@@ -1,15 +1,23 @@
 use crate::{
     node::Node,
     serialization::{
-        ChainHashElement, FeaturesElement, IgnoredBytesElement, MessageTypeElement,
-        NodeAddressesElement, NodeAliasElement, NumPongBytesElement, PointElement,
-        SerializableToBytes, SerializationError, ShortChannelIDElement, SignatureElement,
-        TLVStreamElement, TimestampElement, TimestampRangeElement, Wire1Byte, Wire3Bytes,
-        WireU16Int, WireU16SizedBytes, WireU32Int, WireU64Int,
+        decode_tlv_stream, encode_short_ids, encode_tlv_stream, hex_bytes, BigSizeElement,
+        ChainHashElement, ChannelIDElement, FeatureContext, FeatureReport, FeaturesElement,
+        IgnoredBytesElement, MessageTypeElement, NodeAddressesElement, NodeAliasElement,
+        NumPongBytesElement, PointElement, SerializableToBytes, SerializationError,
+        ShortChannelIDElement, SignatureElement, TLVRecord, TLVStreamElement, TimestampElement,
+        TimestampRangeElement, TxIdElement, Wire1Byte, Wire3Bytes, WireI64Int, WireU16Int,
+        WireU16SizedBytes, WireU32Int, WireU64Int,
     },
 };
 
+use std::net::{IpAddr, SocketAddr};
+
+use bitcoin::hashes::{sha256d, Hash};
+
+use crate::util::crc32c;
 use num_enum::TryFromPrimitive;
+use serde::Serialize;
 use strum::IntoEnumIterator;
 use strum_macros::{EnumIter, IntoStaticStr};
 
@@ -19,6 +27,9 @@ pub enum MessageType {
     Unknown = 0,
     Warning = 1,
     Stfu = 2,
+    // peer storage
+    PeerStorage = 7,
+    PeerStorageRetrieval = 9,
     // connection and keep alive
     Init = 16,
     Error = 17,
@@ -46,6 +57,10 @@ pub enum MessageType {
     TxInitRbf = 72,
     TxAckRbf = 73,
     TxAbort = 74,
+    // splicing
+    SpliceLocked = 77,
+    SpliceInit = 80,
+    SpliceAck = 81,
     // channel updates and htlc management
     UpdateAddHTLC = 128,
     UpdateFulfillHTLC = 130,
@@ -66,6 +81,8 @@ pub enum MessageType {
     QueryChannelRange = 263,
     ReplyChannelRange = 264,
     GossipTimestampFilter = 265,
+    // lmprs experiments, odd so that other implementations ignore them
+    GossipSketch = 32769,
 }
 
 impl MessageType {
@@ -78,13 +95,128 @@ impl MessageType {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize)]
 pub struct InitMessage {
-    global_features: FeaturesElement,
-    local_features: FeaturesElement,
+    pub global_features: FeaturesElement,
+    pub local_features: FeaturesElement,
+    #[serde(serialize_with = "hex_bytes")]
     tlv: Vec<u8>,
 }
 
+const INIT_TLV_NETWORKS: u64 = 1;
+const INIT_TLV_REMOTE_ADDR: u64 = 3;
+
+impl InitMessage {
+    // bolt 1 says to treat the legacy globalfeatures as if they were part of localfeatures
+    pub fn features(&self) -> FeaturesElement {
+        self.local_features.union(&self.global_features)
+    }
+
+    pub fn feature_report(&self) -> FeatureReport {
+        self.features().validate(FeatureContext::Init)
+    }
+
+    fn tlv_records(&self) -> Vec<TLVRecord> {
+        decode_tlv_stream(&self.tlv).unwrap_or_default()
+    }
+
+    pub fn networks(&self) -> Vec<ChainHashElement> {
+        let record = match self
+            .tlv_records()
+            .into_iter()
+            .find(|record| record.tlv_type == INIT_TLV_NETWORKS)
+        {
+            Some(record) => record,
+            None => return Vec::new(),
+        };
+        record
+            .value
+            .chunks_exact(32)
+            .map(|chunk| ChainHashElement {
+                value: chunk.try_into().unwrap(),
+            })
+            .collect()
+    }
+
+    pub fn remote_addr(&self) -> Option<SocketAddr> {
+        let record = self
+            .tlv_records()
+            .into_iter()
+            .find(|record| record.tlv_type == INIT_TLV_REMOTE_ADDR)?;
+        let data = record.value;
+        match (data.first(), data.len()) {
+            (Some(1), 7) => {
+                let ip: [u8; 4] = data[1..5].try_into().unwrap();
+                let port = u16::from_be_bytes([data[5], data[6]]);
+                Some(SocketAddr::new(IpAddr::from(ip), port))
+            }
+            (Some(2), 19) => {
+                let ip: [u8; 16] = data[1..17].try_into().unwrap();
+                let port = u16::from_be_bytes([data[17], data[18]]);
+                Some(SocketAddr::new(IpAddr::from(ip), port))
+            }
+            _ => None,
+        }
+    }
+}
+
+pub struct InitMessageBuilder {
+    features: FeaturesElement,
+    networks: Vec<ChainHashElement>,
+    remote_addr: Option<SocketAddr>,
+}
+
+impl InitMessageBuilder {
+    pub fn new(features: FeaturesElement) -> Self {
+        InitMessageBuilder {
+            features,
+            networks: Vec::new(),
+            remote_addr: None,
+        }
+    }
+
+    pub fn networks(mut self, networks: Vec<ChainHashElement>) -> Self {
+        self.networks = networks;
+        self
+    }
+
+    pub fn remote_addr(mut self, remote_addr: SocketAddr) -> Self {
+        self.remote_addr = Some(remote_addr);
+        self
+    }
+
+    pub fn build(self) -> InitMessage {
+        // only bits 0-13 may go in globalfeatures, and only for the benefit of old nodes
+        let mut global_features = FeaturesElement::empty();
+        for bit in self
+            .features
+            .set_bits()
+            .into_iter()
+            .filter(|bit| *bit <= 13)
+        {
+            global_features.set_bit(bit);
+        }
+        let mut records = Vec::new();
+        if !self.networks.is_empty() {
+            let value = self.networks.iter().flat_map(|n| n.to_bytes()).collect();
+            records.push(TLVRecord::new(INIT_TLV_NETWORKS, value));
+        }
+        if let Some(remote_addr) = self.remote_addr {
+            let mut value = match remote_addr.ip() {
+                IpAddr::V4(ip) => [vec![1], ip.octets().to_vec()].concat(),
+                IpAddr::V6(ip) => [vec![2], ip.octets().to_vec()].concat(),
+            };
+            value.extend(remote_addr.port().to_be_bytes());
+            records.push(TLVRecord::new(INIT_TLV_REMOTE_ADDR, value));
+        }
+        InitMessage {
+            global_features,
+            local_features: self.features,
+            tlv: encode_tlv_stream(&records),
+        }
+    }
+}
+
 impl SerializableToBytes for InitMessage {
     fn from_bytes(data: &[u8]) -> Result<(Self, &[u8]), SerializationError> {
         let (_message, data) = MessageTypeElement::from_bytes(data)?;
@@ -111,12 +243,19 @@ impl SerializableToBytes for InitMessage {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Serialize)]
 pub struct PingMessage {
     pub num_pong_bytes: u16,
     pub ignored: IgnoredBytesElement,
 }
 
+impl PingMessage {
+    // bolt 1: a pong can't be that long, so pings asking for 65532 bytes or more are ignored
+    pub fn expects_pong(&self) -> bool {
+        self.num_pong_bytes < 65532
+    }
+}
+
 impl SerializableToBytes for PingMessage {
     fn from_bytes(data: &[u8]) -> Result<(Self, &[u8]), SerializationError> {
         let (_message, data) = MessageTypeElement::from_bytes(data)?;
@@ -140,7 +279,7 @@ impl SerializableToBytes for PingMessage {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Serialize)]
 pub struct PongMessage {
     ignored: IgnoredBytesElement,
 }
@@ -151,6 +290,10 @@ impl PongMessage {
             ignored: IgnoredBytesElement::new(vec![0; ping.num_pong_bytes as usize]),
         }
     }
+
+    pub fn num_bytes(&self) -> usize {
+        self.ignored.value.value.len()
+    }
 }
 
 impl SerializableToBytes for PongMessage {
@@ -168,19 +311,45 @@ impl SerializableToBytes for PongMessage {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize)]
 pub struct ChannelAnnouncementMessage {
     node_signature_1: SignatureElement,
     node_signature_2: SignatureElement,
     bitcoin_signature_1: SignatureElement,
     bitcoin_signature_2: SignatureElement,
     features: FeaturesElement,
-    chain_hash: ChainHashElement,
+    pub chain_hash: ChainHashElement,
     pub short_channel_id: ShortChannelIDElement,
-    node_id_1: PointElement,
-    node_id_2: PointElement,
-    bitcoin_node_id_1: PointElement,
-    bitcoin_node_id_2: PointElement,
+    pub node_id_1: PointElement,
+    pub node_id_2: PointElement,
+    pub bitcoin_node_id_1: PointElement,
+    pub bitcoin_node_id_2: PointElement,
+    // anything after the known fields is still covered by the signatures
+    #[serde(serialize_with = "hex_bytes")]
+    extra: Vec<u8>,
+}
+
+impl ChannelAnnouncementMessage {
+    // double-sha256 of everything after the four signatures
+    pub fn signed_digest(&self) -> [u8; 32] {
+        sha256d::Hash::hash(&self.to_bytes()[2 + 4 * 64..]).to_byte_array()
+    }
+
+    pub fn verify_signatures(&self) -> bool {
+        let digest = self.signed_digest();
+        self.node_signature_1.verify(&digest, &self.node_id_1)
+            && self.node_signature_2.verify(&digest, &self.node_id_2)
+            && self
+                .bitcoin_signature_1
+                .verify(&digest, &self.bitcoin_node_id_1)
+            && self
+                .bitcoin_signature_2
+                .verify(&digest, &self.bitcoin_node_id_2)
+    }
+
+    pub fn feature_report(&self) -> FeatureReport {
+        self.features.validate(FeatureContext::ChannelAnnouncement)
+    }
 }
 
 impl SerializableToBytes for ChannelAnnouncementMessage {
@@ -197,6 +366,7 @@ impl SerializableToBytes for ChannelAnnouncementMessage {
         let (node_id_2, data) = PointElement::from_bytes(data)?;
         let (bitcoin_node_id_1, data) = PointElement::from_bytes(data)?;
         let (bitcoin_node_id_2, data) = PointElement::from_bytes(data)?;
+        let (extra, data) = TLVStreamElement::from_bytes(data)?;
 
         Ok((
             ChannelAnnouncementMessage {
@@ -211,6 +381,7 @@ impl SerializableToBytes for ChannelAnnouncementMessage {
                 node_id_2,
                 bitcoin_node_id_1,
                 bitcoin_node_id_2,
+                extra: extra.value,
             },
             data,
         ))
@@ -230,17 +401,28 @@ impl SerializableToBytes for ChannelAnnouncementMessage {
         bytes.extend(self.node_id_2.to_bytes());
         bytes.extend(self.bitcoin_node_id_1.to_bytes());
         bytes.extend(self.bitcoin_node_id_2.to_bytes());
+        bytes.extend(TLVStreamElement::new(self.extra.clone()).to_bytes());
         bytes
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize)]
 pub struct GossipTimestampFilterMessage {
     pub chain_hash: ChainHashElement,
     pub first_timestamp: u32,
     pub timestamp_range: u32,
 }
 
+impl GossipTimestampFilterMessage {
+    pub fn new(chain_hash: ChainHashElement, first_timestamp: u32, timestamp_range: u32) -> Self {
+        GossipTimestampFilterMessage {
+            chain_hash,
+            first_timestamp,
+            timestamp_range,
+        }
+    }
+}
+
 impl SerializableToBytes for GossipTimestampFilterMessage {
     fn from_bytes(data: &[u8]) -> Result<(Self, &[u8]), SerializationError> {
         let (_, data) = MessageTypeElement::from_bytes(data)?;
@@ -268,14 +450,49 @@ impl SerializableToBytes for GossipTimestampFilterMessage {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Serialize)]
 pub struct QueryChannelRangeMessage {
-    chain_hash: ChainHashElement,
-    first_blocknum: u32,
-    number_of_blocks: u32,
+    pub chain_hash: ChainHashElement,
+    pub first_blocknum: u32,
+    pub number_of_blocks: u32,
+    #[serde(serialize_with = "hex_bytes")]
     query_range_tlvs: Vec<u8>,
 }
 
+pub const QUERY_OPTION_TIMESTAMPS: u64 = 1;
+pub const QUERY_OPTION_CHECKSUMS: u64 = 2;
+
+impl QueryChannelRangeMessage {
+    pub fn new(chain_hash: ChainHashElement, first_blocknum: u32, number_of_blocks: u32) -> Self {
+        QueryChannelRangeMessage {
+            chain_hash,
+            first_blocknum,
+            number_of_blocks,
+            query_range_tlvs: Vec::new(),
+        }
+    }
+
+    // the query_option tlv asks for timestamps and/or checksums alongside the ids
+    pub fn query_option(&self) -> u64 {
+        let records = decode_tlv_stream(&self.query_range_tlvs).unwrap_or_default();
+        match records.iter().find(|record| record.tlv_type == 1) {
+            Some(record) => match BigSizeElement::from_bytes(&record.value) {
+                Ok((option, _)) => option.value,
+                Err(_) => 0,
+            },
+            None => 0,
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn set_query_option(&mut self, query_option: u64) {
+        self.query_range_tlvs = encode_tlv_stream(&[TLVRecord {
+            tlv_type: 1,
+            value: BigSizeElement::new(query_option).to_bytes(),
+        }]);
+    }
+}
+
 impl SerializableToBytes for QueryChannelRangeMessage {
     fn from_bytes(data: &[u8]) -> Result<(Self, &[u8]), SerializationError> {
         let (_, data) = MessageTypeElement::from_bytes(data)?;
@@ -306,14 +523,16 @@ impl SerializableToBytes for QueryChannelRangeMessage {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Serialize)]
 pub struct ReplyChannelRangeMessage {
-    chain_hash: ChainHashElement,
-    first_blocknum: u32,
-    number_of_blocks: u32,
-    sync_complete: u8,
-    encoded_short_ids: Vec<u8>,
-    reply_channel_range_tlvs: Vec<u8>,
+    pub chain_hash: ChainHashElement,
+    pub first_blocknum: u32,
+    pub number_of_blocks: u32,
+    pub sync_complete: u8,
+    #[serde(serialize_with = "hex_bytes")]
+    pub encoded_short_ids: Vec<u8>,
+    #[serde(serialize_with = "hex_bytes")]
+    pub reply_channel_range_tlvs: Vec<u8>,
 }
 
 impl SerializableToBytes for ReplyChannelRangeMessage {
@@ -352,18 +571,157 @@ impl SerializableToBytes for ReplyChannelRangeMessage {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Serialize)]
+pub struct QueryShortChannelIdsMessage {
+    pub chain_hash: ChainHashElement,
+    #[serde(serialize_with = "hex_bytes")]
+    pub encoded_short_ids: Vec<u8>,
+    #[serde(serialize_with = "hex_bytes")]
+    query_short_channel_ids_tlvs: Vec<u8>,
+}
+
+impl QueryShortChannelIdsMessage {
+    #[allow(dead_code)]
+    pub fn new(chain_hash: ChainHashElement, short_ids: &[ShortChannelIDElement]) -> Self {
+        QueryShortChannelIdsMessage {
+            chain_hash,
+            encoded_short_ids: encode_short_ids(short_ids),
+            query_short_channel_ids_tlvs: Vec::new(),
+        }
+    }
+}
+
+impl SerializableToBytes for QueryShortChannelIdsMessage {
+    fn from_bytes(data: &[u8]) -> Result<(Self, &[u8]), SerializationError> {
+        let (_, data) = MessageTypeElement::from_bytes(data)?;
+        let (chain_hash, data) = ChainHashElement::from_bytes(data)?;
+        let (encoded_short_ids, data) = WireU16SizedBytes::from_bytes(data)?;
+        let (query_short_channel_ids_tlvs, data) = TLVStreamElement::from_bytes(data)?;
+
+        Ok((
+            QueryShortChannelIdsMessage {
+                chain_hash,
+                encoded_short_ids: encoded_short_ids.value,
+                query_short_channel_ids_tlvs: query_short_channel_ids_tlvs.value,
+            },
+            data,
+        ))
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend(MessageTypeElement::new(MessageType::QueryShortChannelIds).to_bytes());
+        bytes.extend(self.chain_hash.to_bytes());
+        bytes.extend(WireU16SizedBytes::new(self.encoded_short_ids.clone()).to_bytes());
+        bytes.extend(TLVStreamElement::new(self.query_short_channel_ids_tlvs.clone()).to_bytes());
+        bytes
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct ReplyShortChannelIdsEndMessage {
+    pub chain_hash: ChainHashElement,
+    pub full_information: u8,
+}
+
+impl SerializableToBytes for ReplyShortChannelIdsEndMessage {
+    fn from_bytes(data: &[u8]) -> Result<(Self, &[u8]), SerializationError> {
+        let (_, data) = MessageTypeElement::from_bytes(data)?;
+        let (chain_hash, data) = ChainHashElement::from_bytes(data)?;
+        let (full_information, data) = Wire1Byte::from_bytes(data)?;
+
+        Ok((
+            ReplyShortChannelIdsEndMessage {
+                chain_hash,
+                full_information: full_information.value,
+            },
+            data,
+        ))
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend(MessageTypeElement::new(MessageType::ReplyShortChannelIdsEnd).to_bytes());
+        bytes.extend(self.chain_hash.to_bytes());
+        bytes.extend(Wire1Byte::new(self.full_information).to_bytes());
+        bytes
+    }
+}
+
+// a minisketch over the short ids of the gossip we have with timestamps in the window
+#[derive(Debug, Serialize)]
+pub struct GossipSketchMessage {
+    pub chain_hash: ChainHashElement,
+    pub first_timestamp: u32,
+    pub timestamp_range: u32,
+    #[serde(serialize_with = "hex_bytes")]
+    pub sketch: Vec<u8>,
+}
+
+impl SerializableToBytes for GossipSketchMessage {
+    fn from_bytes(data: &[u8]) -> Result<(Self, &[u8]), SerializationError> {
+        let (_, data) = MessageTypeElement::from_bytes(data)?;
+        let (chain_hash, data) = ChainHashElement::from_bytes(data)?;
+        let (first_timestamp, data) = WireU32Int::from_bytes(data)?;
+        let (timestamp_range, data) = WireU32Int::from_bytes(data)?;
+        let (sketch, data) = WireU16SizedBytes::from_bytes(data)?;
+
+        Ok((
+            GossipSketchMessage {
+                chain_hash,
+                first_timestamp: first_timestamp.value,
+                timestamp_range: timestamp_range.value,
+                sketch: sketch.value,
+            },
+            data,
+        ))
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend(MessageTypeElement::new(MessageType::GossipSketch).to_bytes());
+        bytes.extend(self.chain_hash.to_bytes());
+        bytes.extend(WireU32Int::new(self.first_timestamp).to_bytes());
+        bytes.extend(WireU32Int::new(self.timestamp_range).to_bytes());
+        bytes.extend(WireU16SizedBytes::new(self.sketch.clone()).to_bytes());
+        bytes
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
 pub struct NodeAnnouncementMessage {
     signature: SignatureElement,
     features: FeaturesElement,
-    timestamp: u32,
+    pub timestamp: u32,
     pub node_id: PointElement,
+    #[serde(serialize_with = "hex_bytes")]
     rgb_color: [u8; 3],
     alias: NodeAliasElement,
     addresses: NodeAddressesElement,
+    #[serde(serialize_with = "hex_bytes")]
+    extra: Vec<u8>,
 }
 
 impl NodeAnnouncementMessage {
+    pub fn verify_signature(&self) -> bool {
+        let digest = sha256d::Hash::hash(&self.to_bytes()[2 + 64..]).to_byte_array();
+        self.signature.verify(&digest, &self.node_id)
+    }
+
+    pub fn feature_report(&self) -> FeatureReport {
+        self.features.validate(FeatureContext::NodeAnnouncement)
+    }
+
+    // whether the two announcements only differ in signature and timestamp
+    pub fn same_content(&self, other: &NodeAnnouncementMessage) -> bool {
+        let content = |msg: &NodeAnnouncementMessage| {
+            let bytes = msg.to_bytes();
+            let features_end = 2 + 64 + msg.features.to_bytes().len();
+            [&bytes[2 + 64..features_end], &bytes[features_end + 4..]].concat()
+        };
+        content(self) == content(other)
+    }
+
     pub fn as_node(&self) -> Option<Node> {
         let ipv4addr = match self.addresses.ipv4_addresses.first() {
             Some(ipv4addr) => ipv4addr,
@@ -392,6 +750,7 @@ impl SerializableToBytes for NodeAnnouncementMessage {
         let (rgb_color, data) = Wire3Bytes::from_bytes(data)?;
         let (alias, data) = NodeAliasElement::from_bytes(data)?;
         let (addresses, data) = NodeAddressesElement::from_bytes(data)?;
+        let (extra, data) = TLVStreamElement::from_bytes(data)?;
 
         Ok((
             NodeAnnouncementMessage {
@@ -402,6 +761,7 @@ impl SerializableToBytes for NodeAnnouncementMessage {
                 rgb_color: rgb_color.value,
                 alias,
                 addresses,
+                extra: extra.value,
             },
             data,
         ))
@@ -417,23 +777,56 @@ impl SerializableToBytes for NodeAnnouncementMessage {
         bytes.extend(Wire3Bytes::new(self.rgb_color).to_bytes());
         bytes.extend(self.alias.to_bytes());
         bytes.extend(self.addresses.to_bytes());
+        bytes.extend(TLVStreamElement::new(self.extra.clone()).to_bytes());
         bytes
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Serialize)]
 pub struct ChannelUpdateMessage {
     signature: SignatureElement,
-    chain_hash: ChainHashElement,
-    short_channel_id: ShortChannelIDElement,
-    timestamp: u32,
+    pub chain_hash: ChainHashElement,
+    pub short_channel_id: ShortChannelIDElement,
+    pub timestamp: u32,
     message_flags: u8,
-    channel_flags: u8,
+    pub channel_flags: u8,
     cltv_expiry_delta: u16,
     htlc_minimum_msat: u64,
     fee_base_msat: u32,
     fee_proportional_millionths: u32,
     htlc_maximum_msat: u64,
+    #[serde(serialize_with = "hex_bytes")]
+    extra: Vec<u8>,
+}
+
+impl ChannelUpdateMessage {
+    // bit 0 of channel_flags tells which end of the channel sent the update
+    pub fn direction(&self) -> usize {
+        (self.channel_flags & 1) as usize
+    }
+
+    pub fn verify_signature(&self, node_id: &PointElement) -> bool {
+        let digest = sha256d::Hash::hash(&self.to_bytes()[2 + 64..]).to_byte_array();
+        self.signature.verify(&digest, node_id)
+    }
+
+    // crc32c over the update without its type, signature and timestamp, as used in
+    // reply_channel_range checksums
+    pub fn checksum(&self) -> u32 {
+        let bytes = self.to_bytes();
+        let signed = &bytes[2 + 64..];
+        crc32c(&[&signed[..32 + 8], &signed[32 + 8 + 4..]].concat())
+    }
+
+    // bit 1 of channel_flags
+    pub fn is_disabled(&self) -> bool {
+        self.channel_flags & 2 != 0
+    }
+
+    // whether the two updates only differ in signature and timestamp
+    pub fn same_policy(&self, other: &ChannelUpdateMessage) -> bool {
+        self.to_bytes()[2 + 64 + 32 + 8 + 4..] == other.to_bytes()[2 + 64 + 32 + 8 + 4..]
+    }
 }
 
 impl SerializableToBytes for ChannelUpdateMessage {
@@ -450,6 +843,7 @@ impl SerializableToBytes for ChannelUpdateMessage {
         let (fee_base_msat, data) = WireU32Int::from_bytes(data).unwrap();
         let (fee_proportional_millionths, data) = WireU32Int::from_bytes(data).unwrap();
         let (htlc_maximum_msat, data) = WireU64Int::from_bytes(data).unwrap();
+        let (extra, data) = TLVStreamElement::from_bytes(data)?;
 
         Ok((
             ChannelUpdateMessage {
@@ -464,6 +858,7 @@ impl SerializableToBytes for ChannelUpdateMessage {
                 fee_base_msat: fee_base_msat.value,
                 fee_proportional_millionths: fee_proportional_millionths.value,
                 htlc_maximum_msat: htlc_maximum_msat.value,
+                extra: extra.value,
             },
             data,
         ))
@@ -483,13 +878,199 @@ impl SerializableToBytes for ChannelUpdateMessage {
         bytes.extend(WireU32Int::new(self.fee_base_msat).to_bytes());
         bytes.extend(WireU32Int::new(self.fee_proportional_millionths).to_bytes());
         bytes.extend(WireU64Int::new(self.htlc_maximum_msat).to_bytes());
+        bytes.extend(TLVStreamElement::new(self.extra.clone()).to_bytes());
         bytes
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Serialize)]
+pub struct PeerStorageMessage {
+    #[serde(serialize_with = "hex_bytes")]
+    pub blob: Vec<u8>,
+}
+
+impl SerializableToBytes for PeerStorageMessage {
+    fn from_bytes(data: &[u8]) -> Result<(Self, &[u8]), SerializationError> {
+        let (_, data) = MessageTypeElement::from_bytes(data)?;
+        let (blob, data) = WireU16SizedBytes::from_bytes(data)?;
+        Ok((PeerStorageMessage { blob: blob.value }, data))
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend(MessageTypeElement::new(MessageType::PeerStorage).to_bytes());
+        bytes.extend(WireU16SizedBytes::new(self.blob.clone()).to_bytes());
+        bytes
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PeerStorageRetrievalMessage {
+    #[serde(serialize_with = "hex_bytes")]
+    pub blob: Vec<u8>,
+}
+
+impl SerializableToBytes for PeerStorageRetrievalMessage {
+    fn from_bytes(data: &[u8]) -> Result<(Self, &[u8]), SerializationError> {
+        let (_, data) = MessageTypeElement::from_bytes(data)?;
+        let (blob, data) = WireU16SizedBytes::from_bytes(data)?;
+        Ok((PeerStorageRetrievalMessage { blob: blob.value }, data))
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend(MessageTypeElement::new(MessageType::PeerStorageRetrieval).to_bytes());
+        bytes.extend(WireU16SizedBytes::new(self.blob.clone()).to_bytes());
+        bytes
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct StfuMessage {
+    pub channel_id: ChannelIDElement,
+    pub initiator: u8,
+}
+
+impl SerializableToBytes for StfuMessage {
+    fn from_bytes(data: &[u8]) -> Result<(Self, &[u8]), SerializationError> {
+        let (_, data) = MessageTypeElement::from_bytes(data)?;
+        let (channel_id, data) = ChannelIDElement::from_bytes(data)?;
+        let (initiator, data) = Wire1Byte::from_bytes(data)?;
+        Ok((
+            StfuMessage {
+                channel_id,
+                initiator: initiator.value,
+            },
+            data,
+        ))
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend(MessageTypeElement::new(MessageType::Stfu).to_bytes());
+        bytes.extend(self.channel_id.to_bytes());
+        bytes.extend(Wire1Byte::new(self.initiator).to_bytes());
+        bytes
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SpliceInitMessage {
+    pub channel_id: ChannelIDElement,
+    pub funding_contribution_satoshis: i64,
+    pub funding_feerate_perkw: u32,
+    pub locktime: u32,
+    pub funding_pubkey: PointElement,
+    #[serde(serialize_with = "hex_bytes")]
+    splice_init_tlvs: Vec<u8>,
+}
+
+impl SerializableToBytes for SpliceInitMessage {
+    fn from_bytes(data: &[u8]) -> Result<(Self, &[u8]), SerializationError> {
+        let (_, data) = MessageTypeElement::from_bytes(data)?;
+        let (channel_id, data) = ChannelIDElement::from_bytes(data)?;
+        let (funding_contribution_satoshis, data) = WireI64Int::from_bytes(data)?;
+        let (funding_feerate_perkw, data) = WireU32Int::from_bytes(data)?;
+        let (locktime, data) = WireU32Int::from_bytes(data)?;
+        let (funding_pubkey, data) = PointElement::from_bytes(data)?;
+        let (splice_init_tlvs, data) = TLVStreamElement::from_bytes(data)?;
+        Ok((
+            SpliceInitMessage {
+                channel_id,
+                funding_contribution_satoshis: funding_contribution_satoshis.value,
+                funding_feerate_perkw: funding_feerate_perkw.value,
+                locktime: locktime.value,
+                funding_pubkey,
+                splice_init_tlvs: splice_init_tlvs.value,
+            },
+            data,
+        ))
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend(MessageTypeElement::new(MessageType::SpliceInit).to_bytes());
+        bytes.extend(self.channel_id.to_bytes());
+        bytes.extend(WireI64Int::new(self.funding_contribution_satoshis).to_bytes());
+        bytes.extend(WireU32Int::new(self.funding_feerate_perkw).to_bytes());
+        bytes.extend(WireU32Int::new(self.locktime).to_bytes());
+        bytes.extend(self.funding_pubkey.to_bytes());
+        bytes.extend(TLVStreamElement::new(self.splice_init_tlvs.clone()).to_bytes());
+        bytes
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SpliceAckMessage {
+    pub channel_id: ChannelIDElement,
+    pub funding_contribution_satoshis: i64,
+    pub funding_pubkey: PointElement,
+    #[serde(serialize_with = "hex_bytes")]
+    splice_ack_tlvs: Vec<u8>,
+}
+
+impl SerializableToBytes for SpliceAckMessage {
+    fn from_bytes(data: &[u8]) -> Result<(Self, &[u8]), SerializationError> {
+        let (_, data) = MessageTypeElement::from_bytes(data)?;
+        let (channel_id, data) = ChannelIDElement::from_bytes(data)?;
+        let (funding_contribution_satoshis, data) = WireI64Int::from_bytes(data)?;
+        let (funding_pubkey, data) = PointElement::from_bytes(data)?;
+        let (splice_ack_tlvs, data) = TLVStreamElement::from_bytes(data)?;
+        Ok((
+            SpliceAckMessage {
+                channel_id,
+                funding_contribution_satoshis: funding_contribution_satoshis.value,
+                funding_pubkey,
+                splice_ack_tlvs: splice_ack_tlvs.value,
+            },
+            data,
+        ))
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend(MessageTypeElement::new(MessageType::SpliceAck).to_bytes());
+        bytes.extend(self.channel_id.to_bytes());
+        bytes.extend(WireI64Int::new(self.funding_contribution_satoshis).to_bytes());
+        bytes.extend(self.funding_pubkey.to_bytes());
+        bytes.extend(TLVStreamElement::new(self.splice_ack_tlvs.clone()).to_bytes());
+        bytes
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SpliceLockedMessage {
+    pub channel_id: ChannelIDElement,
+    pub splice_txid: TxIdElement,
+}
+
+impl SerializableToBytes for SpliceLockedMessage {
+    fn from_bytes(data: &[u8]) -> Result<(Self, &[u8]), SerializationError> {
+        let (_, data) = MessageTypeElement::from_bytes(data)?;
+        let (channel_id, data) = ChannelIDElement::from_bytes(data)?;
+        let (splice_txid, data) = TxIdElement::from_bytes(data)?;
+        Ok((
+            SpliceLockedMessage {
+                channel_id,
+                splice_txid,
+            },
+            data,
+        ))
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend(MessageTypeElement::new(MessageType::SpliceLocked).to_bytes());
+        bytes.extend(self.channel_id.to_bytes());
+        bytes.extend(self.splice_txid.to_bytes());
+        bytes
+    }
+}
+
+#[derive(Debug, Serialize)]
 pub struct UnknownMessage {
     type_id: u16,
+    #[serde(serialize_with = "hex_bytes")]
     data: Vec<u8>,
 }
 
@@ -523,3 +1104,30 @@ fn test_decode_init_message() {
     // check serialization
     assert_eq!([msg.to_bytes(), remainder.to_vec()].concat(), initial_bytes);
 }
+
+#[test]
+fn test_init_message_builder() {
+    use crate::serialization::{Features, FeaturesBuilder};
+
+    let features = FeaturesBuilder::new()
+        .optional(Features::DataLossProtect)
+        .optional(Features::GossipQueries)
+        .optional(Features::ProvideStorage)
+        .build();
+    let chain_hash = ChainHashElement { value: [7; 32] };
+    let remote_addr: SocketAddr = "127.0.0.1:9735".parse().unwrap();
+    let init = InitMessageBuilder::new(features)
+        .networks(vec![chain_hash.clone()])
+        .remote_addr(remote_addr)
+        .build();
+
+    let bytes = init.to_bytes();
+    let (decoded, remainder) = InitMessage::from_bytes(&bytes).unwrap();
+    assert!(remainder.is_empty());
+    assert_eq!(decoded.local_features.set_bits(), vec![1, 7, 43]);
+    // nothing above bit 13 leaks into globalfeatures
+    assert_eq!(decoded.global_features.set_bits(), vec![1, 7]);
+    assert_eq!(decoded.networks()[0].value, chain_hash.value);
+    assert_eq!(decoded.remote_addr(), Some(remote_addr));
+    assert!(decoded.feature_report().is_valid());
+}
@@ -0,0 +1,184 @@
+use std::collections::HashSet;
+use std::fmt::Write;
+use std::sync::{Arc, Mutex};
+
+use tokio::net::TcpListener;
+
+use crate::http::{read_request, respond};
+use crate::propagation::Histogram;
+
+const CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+// prometheus text exposition, every sample of a metric has to be written together
+pub struct Metrics {
+    out: String,
+    declared: HashSet<&'static str>,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Metrics {
+            out: String::new(),
+            declared: HashSet::new(),
+        }
+    }
+
+    fn declare(&mut self, name: &'static str, kind: &str, help: &str) {
+        if self.declared.insert(name) {
+            writeln!(self.out, "# HELP {} {}", name, help).unwrap();
+            writeln!(self.out, "# TYPE {} {}", name, kind).unwrap();
+        }
+    }
+
+    fn sample(&mut self, name: &str, labels: &[(&str, &str)], value: f64) {
+        self.out.push_str(name);
+        if !labels.is_empty() {
+            let labels: Vec<String> = labels
+                .iter()
+                .map(|(key, value)| format!("{}=\"{}\"", key, escape(value)))
+                .collect();
+            write!(self.out, "{{{}}}", labels.join(",")).unwrap();
+        }
+        writeln!(self.out, " {}", value).unwrap();
+    }
+
+    pub fn gauge(&mut self, name: &'static str, help: &str, labels: &[(&str, &str)], value: f64) {
+        self.declare(name, "gauge", help);
+        self.sample(name, labels, value);
+    }
+
+    pub fn counter(&mut self, name: &'static str, help: &str, labels: &[(&str, &str)], value: u64) {
+        self.declare(name, "counter", help);
+        self.sample(name, labels, value as f64);
+    }
+
+    // the histogram counts milliseconds, prometheus wants seconds
+    pub fn histogram_millis(
+        &mut self,
+        name: &'static str,
+        help: &str,
+        labels: &[(&str, &str)],
+        histogram: &Histogram,
+        sum_millis: u64,
+    ) {
+        self.declare(name, "histogram", help);
+        let mut cumulative = 0;
+        for (bound, count) in histogram.buckets() {
+            cumulative += count;
+            let le = match bound {
+                Some(bound) => (bound as f64 / 1000.0).to_string(),
+                None => "+Inf".to_string(),
+            };
+            let mut bucket_labels = labels.to_vec();
+            bucket_labels.push(("le", &le));
+            self.sample(
+                &format!("{}_bucket", name),
+                &bucket_labels,
+                cumulative as f64,
+            );
+        }
+        self.sample(&format!("{}_sum", name), labels, sum_millis as f64 / 1000.0);
+        self.sample(&format!("{}_count", name), labels, cumulative as f64);
+    }
+
+    pub fn finish(self) -> String {
+        self.out
+    }
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+// answers /metrics with whatever the event loop last rendered
+pub async fn serve(listener: TcpListener, snapshot: Arc<Mutex<String>>) {
+    loop {
+        let (mut stream, _) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                println!("Failed to accept metrics connection: {:?}", e);
+                continue;
+            }
+        };
+        let snapshot = snapshot.clone();
+        tokio::spawn(async move {
+            let request = match read_request(&mut stream).await {
+                Ok(request) => request,
+                Err(_) => return,
+            };
+            let result = match (request.method.as_str(), request.path.as_str()) {
+                ("GET", "/metrics") => {
+                    let body = snapshot.lock().unwrap().clone();
+                    respond(&mut stream, 200, CONTENT_TYPE, body.as_bytes()).await
+                }
+                (_, "/metrics") => respond(&mut stream, 405, "text/plain", b"").await,
+                _ => respond(&mut stream, 404, "text/plain", b"").await,
+            };
+            if let Err(e) = result {
+                println!("Failed to answer metrics request: {:?}", e);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::get;
+
+    #[tokio::test]
+    async fn test_serve_metrics() {
+        let mut histogram = Histogram::with_buckets(&[10, 100]);
+        histogram.record(5);
+        histogram.record(50);
+        histogram.record(500);
+        let mut metrics = Metrics::new();
+        metrics.gauge("lmprs_connected_peers", "Connected peers.", &[], 2.0);
+        metrics.counter(
+            "lmprs_messages_received_total",
+            "Messages received.",
+            &[("peer", "02ab"), ("type", "Ping")],
+            3,
+        );
+        metrics.counter(
+            "lmprs_messages_received_total",
+            "Messages received.",
+            &[("peer", "03cd"), ("type", "Ping")],
+            1,
+        );
+        metrics.histogram_millis(
+            "lmprs_ping_rtt_seconds",
+            "Ping round trip time.",
+            &[("peer", "02ab")],
+            &histogram,
+            555,
+        );
+        let snapshot = Arc::new(Mutex::new(metrics.finish()));
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = tokio::spawn(serve(listener, snapshot));
+
+        let response = get(port, "/metrics").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        let body = response.split("\r\n\r\n").nth(1).unwrap();
+        let lines: Vec<&str> = body.lines().collect();
+        assert_eq!(
+            lines
+                .iter()
+                .filter(|line| line.starts_with("# TYPE lmprs_messages_received_total"))
+                .count(),
+            1
+        );
+        assert!(lines.contains(&"lmprs_messages_received_total{peer=\"02ab\",type=\"Ping\"} 3"));
+        assert!(lines.contains(&"lmprs_ping_rtt_seconds_bucket{peer=\"02ab\",le=\"0.1\"} 2"));
+        assert!(lines.contains(&"lmprs_ping_rtt_seconds_bucket{peer=\"02ab\",le=\"+Inf\"} 3"));
+        assert!(lines.contains(&"lmprs_ping_rtt_seconds_sum{peer=\"02ab\"} 0.555"));
+
+        assert!(get(port, "/other").await.starts_with("HTTP/1.1 404"));
+        server.abort();
+    }
+}
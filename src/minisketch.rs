@@ -0,0 +1,312 @@
+// a pure rust pinsketch over 32 bit elements, using the same field as minisketch:
+// gf(2^32) modulo x^32 + x^15 + x^9 + x^7 + x^4 + x^3 + 1
+const MODULUS: u32 = 0x8299;
+// bits per element
+const FIELD_BITS: usize = 32;
+
+fn mul(mut a: u32, b: u32) -> u32 {
+    let mut result = 0;
+    for i in 0..FIELD_BITS {
+        if b & (1 << i) != 0 {
+            result ^= a;
+        }
+        a = if a & 0x8000_0000 != 0 {
+            (a << 1) ^ MODULUS
+        } else {
+            a << 1
+        };
+    }
+    result
+}
+
+fn square(a: u32) -> u32 {
+    mul(a, a)
+}
+
+// a^(2^32 - 2) is the inverse of a
+fn inverse(a: u32) -> u32 {
+    let mut result = 1;
+    let mut power = a;
+    for _ in 1..FIELD_BITS {
+        power = square(power);
+        result = mul(result, power);
+    }
+    result
+}
+
+// polynomials over the field, lowest coefficient first and without trailing zeros
+type Poly = Vec<u32>;
+
+fn trim(mut p: Poly) -> Poly {
+    while p.last() == Some(&0) {
+        p.pop();
+    }
+    p
+}
+
+fn poly_add(a: &[u32], b: &[u32]) -> Poly {
+    let mut sum = vec![0; a.len().max(b.len())];
+    for (i, c) in a.iter().enumerate() {
+        sum[i] ^= c;
+    }
+    for (i, c) in b.iter().enumerate() {
+        sum[i] ^= c;
+    }
+    trim(sum)
+}
+
+fn poly_mul(a: &[u32], b: &[u32]) -> Poly {
+    if a.is_empty() || b.is_empty() {
+        return Vec::new();
+    }
+    let mut product = vec![0; a.len() + b.len() - 1];
+    for (i, x) in a.iter().enumerate() {
+        for (j, y) in b.iter().enumerate() {
+            product[i + j] ^= mul(*x, *y);
+        }
+    }
+    trim(product)
+}
+
+// (quotient, remainder) of a / b, b must not be zero
+fn poly_divmod(a: &[u32], b: &[u32]) -> (Poly, Poly) {
+    let mut remainder = a.to_vec();
+    if a.len() < b.len() {
+        return (Vec::new(), remainder);
+    }
+    let lead_inverse = inverse(*b.last().unwrap());
+    let mut quotient = vec![0; a.len() - b.len() + 1];
+    for i in (0..quotient.len()).rev() {
+        let factor = mul(remainder[i + b.len() - 1], lead_inverse);
+        quotient[i] = factor;
+        for (j, c) in b.iter().enumerate() {
+            remainder[i + j] ^= mul(factor, *c);
+        }
+    }
+    (trim(quotient), trim(remainder))
+}
+
+fn poly_mod(a: &[u32], b: &[u32]) -> Poly {
+    poly_divmod(a, b).1
+}
+
+fn poly_gcd(a: &[u32], b: &[u32]) -> Poly {
+    let (mut a, mut b) = (a.to_vec(), b.to_vec());
+    while !b.is_empty() {
+        let r = poly_mod(&a, &b);
+        a = b;
+        b = r;
+    }
+    a
+}
+
+// the connection polynomial of the syndromes, whose roots are the inverses of the elements
+fn berlekamp_massey(syndromes: &[u32]) -> Poly {
+    let mut current = vec![1];
+    let mut previous = vec![1];
+    let mut length = 0;
+    let mut shift = 1;
+    let mut previous_discrepancy = 1;
+    for n in 0..syndromes.len() {
+        let mut discrepancy = syndromes[n];
+        for i in 1..=length.min(current.len() - 1) {
+            discrepancy ^= mul(current[i], syndromes[n - i]);
+        }
+        if discrepancy == 0 {
+            shift += 1;
+            continue;
+        }
+        let factor = mul(discrepancy, inverse(previous_discrepancy));
+        let mut correction = vec![0; shift];
+        correction.extend(previous.iter().map(|c| mul(*c, factor)));
+        let next = poly_add(&current, &correction);
+        if 2 * length <= n {
+            length = n + 1 - length;
+            previous = current;
+            previous_discrepancy = discrepancy;
+            shift = 1;
+        } else {
+            shift += 1;
+        }
+        current = next;
+    }
+    current
+}
+
+// splits a polynomial with distinct roots in the field into its roots using random trace maps
+fn find_roots(poly: &[u32], roots: &mut Vec<u32>, seed: &mut u32) -> bool {
+    let degree = poly.len() - 1;
+    if degree == 0 {
+        return true;
+    }
+    if degree == 1 {
+        roots.push(mul(poly[0], inverse(poly[1])));
+        return true;
+    }
+    for _ in 0..64 {
+        // xorshift, the randomness only has to avoid unlucky splits
+        *seed ^= *seed << 13;
+        *seed ^= *seed >> 17;
+        *seed ^= *seed << 5;
+        let beta = vec![0, *seed];
+        let mut term = poly_mod(&beta, poly);
+        let mut trace = term.clone();
+        for _ in 1..FIELD_BITS {
+            term = poly_mod(&poly_mul(&term, &term), poly);
+            trace = poly_add(&trace, &term);
+        }
+        let factor = poly_gcd(poly, &trace);
+        if factor.len() > 1 && factor.len() < poly.len() {
+            let (rest, _) = poly_divmod(poly, &factor);
+            return find_roots(&factor, roots, seed) && find_roots(&rest, roots, seed);
+        }
+    }
+    false
+}
+
+// whether the polynomial divides x^(2^32) - x, i.e. has all its roots in the field and no repeats
+fn splits(poly: &[u32]) -> bool {
+    let x = poly_mod(&[0, 1], poly);
+    let mut power = x.clone();
+    for _ in 0..FIELD_BITS {
+        power = poly_mod(&poly_mul(&power, &power), poly);
+    }
+    power == x
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Sketch {
+    // the odd power sums x^1, x^3, ..., x^(2 * capacity - 1) of all elements
+    syndromes: Vec<u32>,
+}
+
+impl Sketch {
+    pub fn new(capacity: usize) -> Self {
+        Sketch {
+            syndromes: vec![0; capacity],
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.syndromes.len()
+    }
+
+    // adding an element twice removes it again, zero can't be represented
+    pub fn add(&mut self, element: u32) {
+        if element == 0 {
+            return;
+        }
+        let element_squared = square(element);
+        let mut power = element;
+        for syndrome in self.syndromes.iter_mut() {
+            *syndrome ^= power;
+            power = mul(power, element_squared);
+        }
+    }
+
+    // afterwards the sketch holds the elements that were in only one of the two
+    pub fn merge(&mut self, other: &Sketch) {
+        for (syndrome, theirs) in self.syndromes.iter_mut().zip(other.syndromes.iter()) {
+            *syndrome ^= theirs;
+        }
+    }
+
+    // the elements in the sketch, or None if there are more than its capacity
+    pub fn decode(&self) -> Option<Vec<u32>> {
+        // the even power sums follow from the odd ones, since s(2i) = s(i)^2
+        let mut all = vec![0; 2 * self.capacity()];
+        for (i, syndrome) in self.syndromes.iter().enumerate() {
+            all[2 * i] = *syndrome;
+        }
+        for i in (1..all.len()).step_by(2) {
+            all[i] = square(all[i / 2]);
+        }
+        let connection = berlekamp_massey(&all);
+        let degree = connection.len() - 1;
+        if degree > self.capacity() {
+            return None;
+        }
+        // reversing the connection polynomial gives one with the elements themselves as roots
+        let locator: Poly = connection.into_iter().rev().collect();
+        if degree > 0 && !splits(&locator) {
+            return None;
+        }
+        let mut elements = Vec::new();
+        let mut seed = 0x2545_f491;
+        if !find_roots(&locator, &mut elements, &mut seed) {
+            return None;
+        }
+        // a sketch over the roots has to reproduce this one, otherwise we decoded garbage
+        let mut check = Sketch::new(self.capacity());
+        elements.iter().for_each(|element| check.add(*element));
+        if check != *self {
+            return None;
+        }
+        Some(elements)
+    }
+
+    // little-endian syndromes, like minisketch serializes 32 bit sketches
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.syndromes
+            .iter()
+            .flat_map(|syndrome| syndrome.to_le_bytes())
+            .collect()
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if !bytes.len().is_multiple_of(4) {
+            return None;
+        }
+        Some(Sketch {
+            syndromes: bytes
+                .chunks(4)
+                .map(|chunk| u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]))
+                .collect(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_field_inverse() {
+        for a in [1, 2, 3, 0x8299, 0xdead_beef, u32::MAX] {
+            assert_eq!(mul(a, inverse(a)), 1);
+        }
+    }
+
+    #[test]
+    fn test_sketch_decodes_difference() {
+        let mut ours = Sketch::new(8);
+        let mut theirs = Sketch::new(8);
+        for element in 1..=500u32 {
+            ours.add(element * 7919);
+            theirs.add(element * 7919);
+        }
+        let only_ours = [0xdead_beef, 42, 0x8000_0000];
+        let only_theirs = [17, 0x1234_5678, 99, 100, 0xffff_ffff];
+        only_ours.iter().for_each(|e| ours.add(*e));
+        only_theirs.iter().for_each(|e| theirs.add(*e));
+
+        let received = Sketch::from_bytes(&theirs.to_bytes()).unwrap();
+        ours.merge(&received);
+        let mut difference = ours.decode().unwrap();
+        difference.sort();
+        let mut expected = [only_ours.to_vec(), only_theirs.to_vec()].concat();
+        expected.sort();
+        assert_eq!(difference, expected);
+
+        assert_eq!(Sketch::new(8).decode(), Some(Vec::new()));
+    }
+
+    #[test]
+    fn test_sketch_fails_over_capacity() {
+        let mut sketch = Sketch::new(4);
+        for element in 1..=10u32 {
+            sketch.add(element * 104729);
+        }
+        assert_eq!(sketch.decode(), None);
+    }
+}
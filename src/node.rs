@@ -1,6 +1,6 @@
 use bitcoin::secp256k1::PublicKey;
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Node {
     pub public_key: [u8; 33],
     pub ip_address: String,
@@ -16,18 +16,14 @@ impl Node {
         let public_key = parts[0].to_string();
         let address = parts[1].to_string();
         let ip_address = address.split(':').next().unwrap().to_string();
-        let port = match address.split(':').nth(1).unwrap().parse() {
+        let port = match address.split(':').nth(1)?.parse() {
             Ok(port) => port,
             Err(_) => return None,
         };
-        let public_key = match hex::decode(public_key.clone())
-            .unwrap()
-            .as_slice()
-            .try_into()
-        {
-            Ok(key) => key,
-            Err(_) => return None,
-        };
+        let public_key = hex::decode(public_key).ok()?;
+        let public_key: [u8; 33] = public_key.as_slice().try_into().ok()?;
+        // 33 bytes aren't necessarily a point on the curve
+        PublicKey::from_slice(&public_key).ok()?;
         Some(Node {
             public_key,
             ip_address,
@@ -39,8 +35,9 @@ impl Node {
         format!("{}:{}", self.ip_address, self.port)
     }
 
-    pub fn bitcoin_public_key(&self) -> PublicKey {
-        PublicKey::from_slice(&self.public_key).unwrap()
+    // None for a key that isn't on the curve, like one taken from gossip nobody checked
+    pub fn bitcoin_public_key(&self) -> Option<PublicKey> {
+        PublicKey::from_slice(&self.public_key).ok()
     }
 
     pub fn display_str(&self) -> String {
@@ -1,20 +1,26 @@
-use crate::config::PING_INTERVAL;
+use crate::codec::{LightningCodec, Taps};
+use crate::config::{Timeouts, MAX_PING_PONG_BYTES, PING_INTERVAL, PONG_TIMEOUT};
 use crate::message_decoder::MessageContainer;
-use crate::message_decoder::MessageDecoder;
-use crate::messages::PingMessage;
-use crate::serialization::IgnoredBytesElement;
-use crate::vendor::{KeysManager, LightningError, MessageBuf, NextNoiseStep};
+use crate::messages::{GossipTimestampFilterMessage, PingMessage};
+use crate::noise::{NextNoiseStep, NoiseError, PeerChannelEncryptor, ACT_TWO_LENGTH};
+use crate::ping::PingTracker;
+use crate::serialization::{Features, IgnoredBytesElement};
+use crate::stats::{PeerReport, TrafficStats};
 use bitcoin::secp256k1::PublicKey as BitcoinPublicKey;
-use bitcoin::secp256k1::Secp256k1;
 use bitcoin::secp256k1::SecretKey;
-use bitcoin::secp256k1::SignOnly;
+use futures::{Sink, SinkExt, Stream};
+use secp256k1::rand::{rngs::OsRng, Rng};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::TcpStream;
+use tokio::time::{timeout, Instant};
+use tokio_util::codec::Framed;
 
 use crate::node::Node;
+use crate::reconciliation::GossipView;
 use crate::util::{get_current_timestamp, new_random_secret_key};
-use crate::vendor::PeerChannelEncryptor;
-use std::sync::Arc;
 
 #[allow(dead_code)]
 #[derive(Debug)]
@@ -22,43 +28,107 @@ pub enum NodeConnectionError {
     HandshakeFailed,
     NoMessageFound,
     InvalidHeaderLength,
-    DecryptionError(LightningError),
+    DecryptionError(NoiseError),
     ConnectionError(std::io::Error),
     IOError(std::io::Error),
-    LightningError(LightningError),
+    NoiseError(NoiseError),
     MessageDecodeError,
+    ConnectTimeout,
+    HandshakeTimeout,
+    // the peer finished the handshake but never sent its init
+    InitTimeout,
+    IdleTimeout,
+}
+
+impl NodeConnectionError {
+    // the stage that took too long, if this is a timeout
+    pub fn timeout_stage(&self) -> Option<&'static str> {
+        match self {
+            NodeConnectionError::ConnectTimeout => Some("connect"),
+            NodeConnectionError::HandshakeTimeout => Some("handshake"),
+            NodeConnectionError::InitTimeout => Some("init"),
+            NodeConnectionError::IdleTimeout => Some("idle"),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for NodeConnectionError {
+    fn from(err: std::io::Error) -> Self {
+        NodeConnectionError::IOError(err)
+    }
 }
 
 pub struct NodeConnection {
     pub public_key: [u8; 33],
+    pub negotiated_features: Vec<Features>,
+    // the filter the peer asked us to apply to gossip we send it
+    pub remote_gossip_filter: Option<GossipTimestampFilterMessage>,
+    // all the gossip the peer sent us, for reconciliation experiments
+    pub gossip_view: GossipView,
+    pub pings: PingTracker,
+    // gossip from this peer the graph threw out for a bad signature
+    pub signature_failures: u64,
+    address: String,
+    connected_at: u64,
+    handshake_duration: Option<Duration>,
+    last_received: Option<u64>,
     last_contacted: u64,
-    stream: TcpStream,
-    secp: Secp256k1<SignOnly>,
-    peer_encryptor: PeerChannelEncryptor,
-    km: Arc<KeysManager>,
+    timeouts: Timeouts,
+    // set once the handshake is done, until the peer's init arrives
+    init_deadline: Option<Instant>,
+    // the end of the handshake or the last message, for the idle timeout
+    last_message_at: Instant,
+    // the raw stream until the handshake is done, then encrypted frames
+    framed: Framed<TcpStream, LightningCodec>,
+    static_key: SecretKey,
 }
 
 impl NodeConnection {
-    pub async fn new(node: &Node, node_secret_key: SecretKey) -> Result<Self, NodeConnectionError> {
-        let ephemeral_key = new_random_secret_key();
-        let stream = match TcpStream::connect(node.address()).await {
-            Ok(stream) => stream,
-            Err(err) => {
+    pub async fn new(
+        node: &Node,
+        node_secret_key: SecretKey,
+        taps: Taps,
+        timeouts: Timeouts,
+    ) -> Result<Self, NodeConnectionError> {
+        let their_node_id = node
+            .bitcoin_public_key()
+            .ok_or(NodeConnectionError::NoiseError(NoiseError::InvalidKey))?;
+        let stream = match timeout(timeouts.connect, TcpStream::connect(node.address())).await {
+            Ok(Ok(stream)) => stream,
+            Ok(Err(err)) => {
                 println!("Failed to connect to {}: {}", node.address(), err);
                 return Err(NodeConnectionError::ConnectionError(err));
             }
+            Err(_) => {
+                println!("Timed out connecting to {}", node.address());
+                return Err(NodeConnectionError::ConnectTimeout);
+            }
         };
         println!("Connected to {}", node.display_str());
+        let endpoints = match (stream.local_addr(), stream.peer_addr()) {
+            (Ok(local), Ok(remote)) => Some((local, remote)),
+            _ => None,
+        };
+        let encryptor = PeerChannelEncryptor::new_outbound(their_node_id, new_random_secret_key());
+        let codec = LightningCodec::new(encryptor, node.public_key, endpoints, taps);
         Ok(NodeConnection {
             public_key: node.public_key,
+            negotiated_features: Vec::new(),
+            remote_gossip_filter: None,
+            gossip_view: GossipView::new(),
+            pings: PingTracker::new(),
+            signature_failures: 0,
+            address: node.address(),
+            connected_at: get_current_timestamp(),
+            handshake_duration: None,
+            last_received: None,
             last_contacted: get_current_timestamp(),
-            stream,
-            secp: Secp256k1::signing_only(),
-            peer_encryptor: PeerChannelEncryptor::new_outbound(
-                node.bitcoin_public_key().clone(),
-                ephemeral_key,
-            ),
-            km: Arc::new(KeysManager::new(&node_secret_key.secret_bytes(), 0, 0)),
+            timeouts,
+            init_deadline: None,
+            last_message_at: Instant::now(),
+            framed: Framed::new(stream, codec),
+            static_key: node_secret_key,
         })
     }
 
@@ -66,21 +136,29 @@ impl NodeConnection {
         self.last_contacted = get_current_timestamp();
     }
 
+    // when the connection has been quiet, or the last ping went unanswered
     pub fn ready_for_ping(&self) -> bool {
         self.last_contacted + PING_INTERVAL < get_current_timestamp()
+            || self
+                .pings
+                .overdue(Duration::from_secs(PONG_TIMEOUT), Instant::now().into_std())
     }
 
     pub async fn send_ping(&mut self) -> Result<(), NodeConnectionError> {
+        // a random length, so a pong can't be mistaken for the answer to another ping
+        let num_pong_bytes = OsRng.gen_range(0..MAX_PING_PONG_BYTES);
         let wrapped = MessageContainer::Ping(PingMessage {
-            num_pong_bytes: 100,
+            num_pong_bytes,
             ignored: IgnoredBytesElement::new([0; 10].to_vec()),
         });
         self.encrypt_and_send_message(&wrapped).await?;
+        self.pings.sent(num_pong_bytes, Instant::now().into_std());
         Ok(())
     }
 
+    // the handshake talks to the stream directly, nothing has been framed yet
     async fn write_raw_data(&mut self, data: &[u8]) -> Result<(), NodeConnectionError> {
-        match self.stream.write_all(data).await {
+        match self.framed.get_mut().write_all(data).await {
             Ok(_) => Ok(()),
             Err(err) => Err(NodeConnectionError::IOError(err)),
         }
@@ -90,8 +168,8 @@ impl NodeConnection {
         &mut self,
         num_bytes: usize,
     ) -> Result<Vec<u8>, NodeConnectionError> {
-        let mut buffer: Vec<u8> = vec![0; num_bytes as usize];
-        match self.stream.read_exact(&mut buffer).await {
+        let mut buffer: Vec<u8> = vec![0; num_bytes];
+        match self.framed.get_mut().read_exact(&mut buffer).await {
             Ok(n) => {
                 let response = buffer[..n].to_vec();
                 Ok(response)
@@ -101,23 +179,39 @@ impl NodeConnection {
     }
 
     pub async fn handshake(&mut self) -> Result<BitcoinPublicKey, NodeConnectionError> {
-        let act_one = self.peer_encryptor.get_act_one(&self.secp);
+        let started = Instant::now();
+        let public_key = match timeout(self.timeouts.handshake, self.noise_handshake()).await {
+            Ok(result) => result?,
+            Err(_) => return Err(NodeConnectionError::HandshakeTimeout),
+        };
+        self.handshake_duration = Some(started.elapsed());
+        self.init_deadline = Some(Instant::now() + self.timeouts.init);
+        self.last_message_at = Instant::now();
+        Ok(public_key)
+    }
+
+    async fn noise_handshake(&mut self) -> Result<BitcoinPublicKey, NodeConnectionError> {
+        let act_one = match self.framed.codec_mut().encryptor.get_act_one() {
+            Ok(act_one) => act_one,
+            Err(err) => return Err(NodeConnectionError::NoiseError(err)),
+        };
         match self.write_raw_data(&act_one).await {
             Ok(_) => (),
             Err(err) => return Err(err),
         }
-        let act_two = self.read_exact_n_bytes(50).await?;
-        let (act_three, public_key) = match self.peer_encryptor.process_act_two(&act_two, &self.km)
-        {
-            Ok((x, y)) => (x, y),
-            Err(err) => return Err(NodeConnectionError::LightningError(err)),
-        };
-        assert_eq!(act_three.len(), 66);
+        let act_two = self.read_exact_n_bytes(ACT_TWO_LENGTH).await?;
+        let codec = self.framed.codec_mut();
+        let (act_three, public_key) =
+            match codec.encryptor.process_act_two(&act_two, &self.static_key) {
+                Ok((x, y)) => (x, y),
+                Err(err) => return Err(NodeConnectionError::NoiseError(err)),
+            };
+        codec.start_keylog();
         match self.write_raw_data(&act_three).await {
             Ok(_) => (),
             Err(err) => return Err(err),
         };
-        match self.peer_encryptor.get_noise_step() {
+        match self.framed.codec().encryptor.get_noise_step() {
             NextNoiseStep::NoiseComplete => println!("Handshake completed with {}", public_key),
             _ => return Err(NodeConnectionError::HandshakeFailed),
         }
@@ -126,66 +220,230 @@ impl NodeConnection {
         Ok(public_key)
     }
 
-    async fn wait_for_message(&mut self) -> Result<(), NodeConnectionError> {
-        match self.stream.readable().await {
-            Ok(_) => Ok(()),
-            Err(err) => Err(NodeConnectionError::IOError(err)),
+    // the first message has to be the peer's init, after that anything will do as long as
+    // something comes in
+    pub fn timed_out(&self) -> Option<NodeConnectionError> {
+        let now = Instant::now();
+        match self.init_deadline {
+            Some(deadline) if now > deadline => Some(NodeConnectionError::InitTimeout),
+            None if now > self.last_message_at + self.timeouts.idle => {
+                Some(NodeConnectionError::IdleTimeout)
+            }
+            _ => None,
         }
     }
 
-    async fn read_next_message_bytes(&mut self) -> Result<Vec<u8>, NodeConnectionError> {
-        let mut header = match self.read_exact_n_bytes(18).await {
-            Ok(header) => header,
-            Err(err) => return Err(err),
-        };
-        if header.len() != 18 {
-            return Err(NodeConnectionError::InvalidHeaderLength);
-        }
-        match self.peer_encryptor.decrypt_message(header.as_mut()) {
-            Ok(_) => (),
-            Err(err) => return Err(NodeConnectionError::DecryptionError(err)),
-        }
-        let length = u16::from_be_bytes([header[0], header[1]]);
-        let mut message = self.read_exact_n_bytes(length as usize + 16).await?;
-        match self.peer_encryptor.decrypt_message(message.as_mut()) {
-            Ok(_) => (),
-            Err(err) => return Err(NodeConnectionError::DecryptionError(err)),
-        }
-        Ok(message)
+    // when the message the stream last handed out arrived
+    pub fn received_at(&self) -> std::time::Instant {
+        self.framed.codec().received_at
+    }
+
+    pub fn traffic(&self) -> &TrafficStats {
+        &self.framed.codec().stats
     }
 
-    pub async fn read_next_message(&mut self) -> Result<MessageContainer, NodeConnectionError> {
-        self.wait_for_message().await?;
-        let bytes = self.read_next_message_bytes().await?;
-        if bytes.is_empty() {
-            return Err(NodeConnectionError::NoMessageFound);
+    pub fn report(&self) -> PeerReport {
+        let rtt = self.pings.rtt();
+        PeerReport {
+            public_key: hex::encode(self.public_key),
+            address: self.address.clone(),
+            connected_at: self.connected_at,
+            disconnected_at: None,
+            handshake_millis: self
+                .handshake_duration
+                .map(|duration| duration.as_millis() as u64),
+            features: self
+                .negotiated_features
+                .iter()
+                .map(|feature| format!("{:?}", feature))
+                .collect(),
+            last_received: self.last_received,
+            pongs: rtt.samples,
+            rtt_mean_millis: (rtt.samples > 0).then_some(rtt.mean.as_millis() as u64),
+            traffic: self.traffic().clone(),
         }
-        let (message, _bytes) = match MessageDecoder::from_bytes(bytes.as_slice()) {
-            Ok(msg) => msg,
-            Err(_) => return Err(NodeConnectionError::MessageDecodeError),
-        };
-        self.update_last_contacted();
-        Ok(message)
     }
 
     pub async fn encrypt_and_send_bytes(
         &mut self,
         bytes: &[u8],
     ) -> Result<(), NodeConnectionError> {
-        let buf = MessageBuf::from_encoded(bytes);
-        let encrypted = self.peer_encryptor.encrypt_buffer(buf);
-        self.write_raw_data(encrypted.as_slice()).await?;
-        Ok(())
+        self.framed.send(bytes).await
     }
 
     pub async fn encrypt_and_send_message(
         &mut self,
         message: &MessageContainer,
     ) -> Result<(), NodeConnectionError> {
-        let bytes = message.to_bytes();
-        self.encrypt_and_send_bytes(bytes.as_slice()).await?;
+        self.send(message).await?;
         println!("Sent message {:?}", message);
         self.update_last_contacted();
         Ok(())
     }
 }
+
+// decrypted and decoded messages from the peer. a closed connection is an error rather than
+// the end of the stream, so whoever holds it gets to see the connection go
+impl Stream for NodeConnection {
+    type Item = Result<MessageContainer, NodeConnectionError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        match Pin::new(&mut self.framed).poll_next(cx) {
+            Poll::Ready(Some(Ok(message))) => {
+                self.update_last_contacted();
+                self.last_received = Some(self.last_contacted);
+                self.last_message_at = Instant::now();
+                if let MessageContainer::Init(_) = message {
+                    self.init_deadline = None;
+                }
+                Poll::Ready(Some(Ok(message)))
+            }
+            Poll::Ready(None) => Poll::Ready(Some(Err(NodeConnectionError::IOError(
+                std::io::ErrorKind::UnexpectedEof.into(),
+            )))),
+            poll => poll,
+        }
+    }
+}
+
+impl<'a> Sink<&'a MessageContainer> for NodeConnection {
+    type Error = NodeConnectionError;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Sink::<&'a MessageContainer>::poll_ready(Pin::new(&mut self.framed), cx)
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, item: &'a MessageContainer) -> Result<(), Self::Error> {
+        Pin::new(&mut self.framed).start_send(item)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Sink::<&'a MessageContainer>::poll_flush(Pin::new(&mut self.framed), cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Sink::<&'a MessageContainer>::poll_close(Pin::new(&mut self.framed), cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::noise::{ACT_ONE_LENGTH, ACT_THREE_LENGTH};
+    use bitcoin::secp256k1::Secp256k1;
+    use futures::StreamExt;
+    use std::time::Duration;
+    use tokio::net::TcpListener;
+
+    // a connection that finished its handshake with a peer on the other end of the socket
+    async fn connected(timeouts: Timeouts) -> (NodeConnection, TcpStream, PeerChannelEncryptor) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let responder_key = new_random_secret_key();
+        let responder_id = BitcoinPublicKey::from_secret_key(&Secp256k1::new(), &responder_key);
+        let responder = tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut encryptor = PeerChannelEncryptor::new_inbound(responder_key);
+            let mut act_one = [0u8; ACT_ONE_LENGTH];
+            socket.read_exact(&mut act_one).await.unwrap();
+            let act_two = encryptor
+                .process_act_one(&act_one, new_random_secret_key())
+                .unwrap();
+            socket.write_all(&act_two).await.unwrap();
+            let mut act_three = [0u8; ACT_THREE_LENGTH];
+            socket.read_exact(&mut act_three).await.unwrap();
+            encryptor.process_act_three(&act_three).unwrap();
+            (socket, encryptor)
+        });
+        let node = Node::from_str(&format!(
+            "{}@127.0.0.1:{}",
+            hex::encode(responder_id.serialize()),
+            port
+        ))
+        .unwrap();
+        let mut conn =
+            NodeConnection::new(&node, new_random_secret_key(), Taps::default(), timeouts)
+                .await
+                .unwrap();
+        conn.handshake().await.unwrap();
+        let (socket, encryptor) = responder.await.unwrap();
+        (conn, socket, encryptor)
+    }
+
+    // a peer that never sends its init, then one that goes quiet after it
+    #[tokio::test]
+    async fn test_init_and_idle_deadlines() {
+        let timeouts = Timeouts {
+            init: Duration::from_millis(100),
+            idle: Duration::from_millis(200),
+            ..Timeouts::default()
+        };
+        let (conn, _socket, _) = connected(timeouts).await;
+        assert!(conn.timed_out().is_none());
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        assert!(matches!(
+            conn.timed_out(),
+            Some(NodeConnectionError::InitTimeout)
+        ));
+
+        let (mut conn, mut socket, mut encryptor) = connected(timeouts).await;
+        let init = encryptor.encrypt_message(&[0, 16, 0, 0, 0, 0]).unwrap();
+        socket.write_all(&init).await.unwrap();
+        assert!(matches!(
+            conn.next().await,
+            Some(Ok(MessageContainer::Init(_)))
+        ));
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        assert!(conn.timed_out().is_none());
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(matches!(
+            conn.timed_out(),
+            Some(NodeConnectionError::IdleTimeout)
+        ));
+    }
+
+    // the event loop only notices a peer hanging up if the stream says so
+    #[tokio::test]
+    async fn test_closed_connection_is_an_error() {
+        let (mut conn, mut socket, mut encryptor) = connected(Timeouts::default()).await;
+        let pong = encryptor.encrypt_message(&[0, 19, 0, 0]).unwrap();
+        socket.write_all(&pong).await.unwrap();
+        drop(socket);
+        assert!(matches!(
+            conn.next().await,
+            Some(Ok(MessageContainer::Pong(_)))
+        ));
+        assert!(matches!(
+            conn.next().await,
+            Some(Err(NodeConnectionError::IOError(_)))
+        ));
+    }
+
+    // a peer that accepts the connection and never answers act one
+    #[tokio::test]
+    async fn test_handshake_timeout() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let silent = tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            tokio::time::sleep(Duration::from_secs(5)).await;
+            drop(socket);
+        });
+        let node = Node::from_str(&format!(
+            "02eec7245d6b7d2ccb30380bfbe2a3648cd7a942653f5aa340edcea1f283686619@127.0.0.1:{}",
+            port
+        ))
+        .unwrap();
+        let timeouts = Timeouts {
+            handshake: Duration::from_millis(100),
+            ..Timeouts::default()
+        };
+        let mut conn =
+            NodeConnection::new(&node, new_random_secret_key(), Taps::default(), timeouts)
+                .await
+                .unwrap();
+        let err = conn.handshake().await.unwrap_err();
+        assert_eq!(err.timeout_stage(), Some("handshake"));
+        silent.abort();
+    }
+}
@@ -0,0 +1,667 @@
+// BOLT 8: Noise_XK_secp256k1_ChaChaPoly_SHA256 handshake and transport
+
+use bitcoin::hashes::{sha256, Hash, HashEngine, Hmac, HmacEngine};
+use bitcoin::secp256k1::ecdh::SharedSecret;
+use bitcoin::secp256k1::{PublicKey, Secp256k1, SecretKey, SignOnly};
+use chacha20poly1305::aead::{Aead, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, KeyInit, Nonce};
+
+const PROTOCOL_NAME: &[u8] = b"Noise_XK_secp256k1_ChaChaPoly_SHA256";
+const PROLOGUE: &[u8] = b"lightning";
+pub const ACT_ONE_LENGTH: usize = 50;
+pub const ACT_TWO_LENGTH: usize = 50;
+pub const ACT_THREE_LENGTH: usize = 66;
+// encrypted length plus its mac
+pub const LENGTH_HEADER_LENGTH: usize = 18;
+pub const MAC_LENGTH: usize = 16;
+// keys rotate once they have used this many nonces
+pub const ROTATE_AFTER_NONCES: u64 = 1000;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NoiseError {
+    UnknownVersion(u8),
+    InvalidKey,
+    InvalidMac,
+    InvalidLength,
+    // a step that doesn't fit where the handshake is
+    OutOfOrder,
+    MessageTooLong,
+}
+
+// hkdf with the chaining key as salt, as BOLT 8 uses it
+pub fn hkdf(salt: &[u8; 32], ikm: &[u8]) -> ([u8; 32], [u8; 32]) {
+    let mut engine = HmacEngine::<sha256::Hash>::new(salt);
+    engine.input(ikm);
+    let prk = Hmac::<sha256::Hash>::from_engine(engine).to_byte_array();
+    let mut engine = HmacEngine::<sha256::Hash>::new(&prk);
+    engine.input(&[1]);
+    let t1 = Hmac::<sha256::Hash>::from_engine(engine).to_byte_array();
+    let mut engine = HmacEngine::<sha256::Hash>::new(&prk);
+    engine.input(&t1);
+    engine.input(&[2]);
+    let t2 = Hmac::<sha256::Hash>::from_engine(engine).to_byte_array();
+    (t1, t2)
+}
+
+// BOLT 8 nonces are 32 zero bits followed by a little endian 64 bit counter
+fn nonce_bytes(nonce: u64) -> Nonce {
+    let mut bytes = [0u8; 12];
+    bytes[4..].copy_from_slice(&nonce.to_le_bytes());
+    bytes.into()
+}
+
+// ciphertext followed by the 16 byte mac
+fn encrypt(key: &[u8; 32], nonce: u64, ad: &[u8], plaintext: &[u8]) -> Vec<u8> {
+    ChaCha20Poly1305::new(key.into())
+        .encrypt(
+            &nonce_bytes(nonce),
+            Payload {
+                msg: plaintext,
+                aad: ad,
+            },
+        )
+        .expect("plaintext fits in a single aead message")
+}
+
+// None if the mac doesn't match
+fn decrypt(key: &[u8; 32], nonce: u64, ad: &[u8], ciphertext: &[u8]) -> Option<Vec<u8>> {
+    ChaCha20Poly1305::new(key.into())
+        .decrypt(
+            &nonce_bytes(nonce),
+            Payload {
+                msg: ciphertext,
+                aad: ad,
+            },
+        )
+        .ok()
+}
+
+fn ecdh(point: &PublicKey, scalar: &SecretKey) -> [u8; 32] {
+    SharedSecret::new(point, scalar).secret_bytes()
+}
+
+// the handshake hash and chaining key both sides keep in step
+struct SymmetricState {
+    h: [u8; 32],
+    ck: [u8; 32],
+}
+
+impl SymmetricState {
+    fn new(responder_static: &PublicKey) -> Self {
+        let h = sha256::Hash::hash(PROTOCOL_NAME).to_byte_array();
+        let mut state = SymmetricState { h, ck: h };
+        state.mix_hash(PROLOGUE);
+        state.mix_hash(&responder_static.serialize());
+        state
+    }
+
+    fn mix_hash(&mut self, data: &[u8]) {
+        let mut engine = sha256::Hash::engine();
+        engine.input(&self.h);
+        engine.input(data);
+        self.h = sha256::Hash::from_engine(engine).to_byte_array();
+    }
+
+    fn mix_key(&mut self, shared_secret: &[u8; 32]) -> [u8; 32] {
+        let (ck, temp_k) = hkdf(&self.ck, shared_secret);
+        self.ck = ck;
+        temp_k
+    }
+
+    fn encrypt_and_hash(&mut self, temp_k: &[u8; 32], nonce: u64, plaintext: &[u8]) -> Vec<u8> {
+        let ciphertext = encrypt(temp_k, nonce, &self.h, plaintext);
+        self.mix_hash(&ciphertext);
+        ciphertext
+    }
+
+    fn decrypt_and_hash(
+        &mut self,
+        temp_k: &[u8; 32],
+        nonce: u64,
+        ciphertext: &[u8],
+    ) -> Result<Vec<u8>, NoiseError> {
+        let plaintext =
+            decrypt(temp_k, nonce, &self.h, ciphertext).ok_or(NoiseError::InvalidMac)?;
+        self.mix_hash(ciphertext);
+        Ok(plaintext)
+    }
+}
+
+// version byte and ephemeral key of act one or act two
+fn read_act(act: &[u8], length: usize) -> Result<(PublicKey, &[u8]), NoiseError> {
+    if act.len() != length {
+        return Err(NoiseError::InvalidLength);
+    }
+    if act[0] != 0 {
+        return Err(NoiseError::UnknownVersion(act[0]));
+    }
+    let key = PublicKey::from_slice(&act[1..34]).map_err(|_| NoiseError::InvalidKey)?;
+    Ok((key, &act[34..]))
+}
+
+// the secrets of a finished handshake, kept for the key log
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HandshakeKeys {
+    // the ephemeral key of act one, which tells sessions apart
+    pub initiator_ephemeral: PublicKey,
+    pub temp_k1: [u8; 32],
+    pub temp_k2: [u8; 32],
+    pub temp_k3: [u8; 32],
+    pub ck: [u8; 32],
+    // what we send with and what we receive with, whichever side we are
+    pub sk: [u8; 32],
+    pub rk: [u8; 32],
+}
+
+// a transport key replacing the previous one of its direction
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyRotation {
+    pub sending: bool,
+    // how many times this direction has rotated, the handshake keys being the zeroth
+    pub generation: u64,
+    pub ck: [u8; 32],
+    pub key: [u8; 32],
+}
+
+// one direction of the transport
+struct CipherState {
+    key: [u8; 32],
+    ck: [u8; 32],
+    nonce: u64,
+    generation: u64,
+}
+
+impl CipherState {
+    fn new(ck: [u8; 32], key: [u8; 32]) -> Self {
+        CipherState {
+            key,
+            ck,
+            nonce: 0,
+            generation: 0,
+        }
+    }
+
+    fn advance(&mut self) {
+        self.nonce += 1;
+        if self.nonce == ROTATE_AFTER_NONCES {
+            (self.ck, self.key) = hkdf(&self.ck, &self.key);
+            self.nonce = 0;
+            self.generation += 1;
+        }
+    }
+
+    fn rotation(&self, sending: bool) -> KeyRotation {
+        KeyRotation {
+            sending,
+            generation: self.generation,
+            ck: self.ck,
+            key: self.key,
+        }
+    }
+
+    fn encrypt(&mut self, plaintext: &[u8]) -> Vec<u8> {
+        let ciphertext = encrypt(&self.key, self.nonce, &[], plaintext);
+        self.advance();
+        ciphertext
+    }
+
+    fn decrypt(&mut self, ciphertext: &[u8]) -> Result<Vec<u8>, NoiseError> {
+        let plaintext =
+            decrypt(&self.key, self.nonce, &[], ciphertext).ok_or(NoiseError::InvalidMac)?;
+        self.advance();
+        Ok(plaintext)
+    }
+}
+
+// we only dial out for now, the responder side is there for accepting connections later
+#[allow(dead_code)]
+enum NoiseState {
+    // initiator before act one
+    InitiatorStart {
+        state: SymmetricState,
+        ephemeral_key: SecretKey,
+    },
+    // initiator waiting for act two
+    InitiatorActOneSent {
+        state: SymmetricState,
+        ephemeral_key: SecretKey,
+        temp_k1: [u8; 32],
+    },
+    // responder waiting for act one
+    ResponderStart {
+        state: SymmetricState,
+    },
+    // responder waiting for act three
+    ResponderActTwoSent {
+        state: SymmetricState,
+        ephemeral_key: SecretKey,
+        remote_ephemeral: PublicKey,
+        temp_k1: [u8; 32],
+        temp_k2: [u8; 32],
+    },
+    Finished {
+        keys: HandshakeKeys,
+        sending: CipherState,
+        receiving: CipherState,
+    },
+    // after a failed step, nothing more can be done with this connection
+    Failed,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NextNoiseStep {
+    ActOne,
+    ActTwo,
+    ActThree,
+    NoiseComplete,
+    Failed,
+}
+
+pub struct PeerChannelEncryptor {
+    secp: Secp256k1<SignOnly>,
+    #[allow(dead_code)]
+    our_static_key: Option<SecretKey>,
+    their_node_id: Option<PublicKey>,
+    state: NoiseState,
+    // rotations since they were last taken
+    rotations: Vec<KeyRotation>,
+}
+
+impl PeerChannelEncryptor {
+    pub fn new_outbound(their_node_id: PublicKey, ephemeral_key: SecretKey) -> Self {
+        PeerChannelEncryptor {
+            secp: Secp256k1::signing_only(),
+            our_static_key: None,
+            their_node_id: Some(their_node_id),
+            state: NoiseState::InitiatorStart {
+                state: SymmetricState::new(&their_node_id),
+                ephemeral_key,
+            },
+            rotations: Vec::new(),
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn new_inbound(our_static_key: SecretKey) -> Self {
+        let secp = Secp256k1::signing_only();
+        let our_node_id = PublicKey::from_secret_key(&secp, &our_static_key);
+        PeerChannelEncryptor {
+            secp,
+            our_static_key: Some(our_static_key),
+            their_node_id: None,
+            state: NoiseState::ResponderStart {
+                state: SymmetricState::new(&our_node_id),
+            },
+            rotations: Vec::new(),
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn their_node_id(&self) -> Option<PublicKey> {
+        self.their_node_id
+    }
+
+    pub fn get_noise_step(&self) -> NextNoiseStep {
+        match self.state {
+            NoiseState::InitiatorStart { .. } => NextNoiseStep::ActOne,
+            NoiseState::InitiatorActOneSent { .. } => NextNoiseStep::ActTwo,
+            NoiseState::ResponderStart { .. } => NextNoiseStep::ActOne,
+            NoiseState::ResponderActTwoSent { .. } => NextNoiseStep::ActThree,
+            NoiseState::Finished { .. } => NextNoiseStep::NoiseComplete,
+            NoiseState::Failed => NextNoiseStep::Failed,
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn is_ready_for_encryption(&self) -> bool {
+        matches!(self.state, NoiseState::Finished { .. })
+    }
+
+    // e, es
+    pub fn get_act_one(&mut self) -> Result<[u8; ACT_ONE_LENGTH], NoiseError> {
+        let (mut state, ephemeral_key) =
+            match std::mem::replace(&mut self.state, NoiseState::Failed) {
+                NoiseState::InitiatorStart {
+                    state,
+                    ephemeral_key,
+                } => (state, ephemeral_key),
+                _ => return Err(NoiseError::OutOfOrder),
+            };
+        let their_node_id = self.their_node_id.unwrap();
+        let ephemeral = PublicKey::from_secret_key(&self.secp, &ephemeral_key).serialize();
+        state.mix_hash(&ephemeral);
+        let temp_k1 = state.mix_key(&ecdh(&their_node_id, &ephemeral_key));
+        let c = state.encrypt_and_hash(&temp_k1, 0, &[]);
+
+        let mut act = [0u8; ACT_ONE_LENGTH];
+        act[1..34].copy_from_slice(&ephemeral);
+        act[34..].copy_from_slice(&c);
+        self.state = NoiseState::InitiatorActOneSent {
+            state,
+            ephemeral_key,
+            temp_k1,
+        };
+        Ok(act)
+    }
+
+    // responder side of act one, then e, ee for act two
+    #[allow(dead_code)]
+    pub fn process_act_one(
+        &mut self,
+        act_one: &[u8],
+        ephemeral_key: SecretKey,
+    ) -> Result<[u8; ACT_TWO_LENGTH], NoiseError> {
+        let mut state = match std::mem::replace(&mut self.state, NoiseState::Failed) {
+            NoiseState::ResponderStart { state } => state,
+            _ => return Err(NoiseError::OutOfOrder),
+        };
+        let our_static_key = self.our_static_key.unwrap();
+        let (remote_ephemeral, c) = read_act(act_one, ACT_ONE_LENGTH)?;
+        state.mix_hash(&remote_ephemeral.serialize());
+        let temp_k1 = state.mix_key(&ecdh(&remote_ephemeral, &our_static_key));
+        state.decrypt_and_hash(&temp_k1, 0, c)?;
+
+        let ephemeral = PublicKey::from_secret_key(&self.secp, &ephemeral_key).serialize();
+        state.mix_hash(&ephemeral);
+        let temp_k2 = state.mix_key(&ecdh(&remote_ephemeral, &ephemeral_key));
+        let c = state.encrypt_and_hash(&temp_k2, 0, &[]);
+
+        let mut act = [0u8; ACT_TWO_LENGTH];
+        act[1..34].copy_from_slice(&ephemeral);
+        act[34..].copy_from_slice(&c);
+        self.state = NoiseState::ResponderActTwoSent {
+            state,
+            ephemeral_key,
+            remote_ephemeral,
+            temp_k1,
+            temp_k2,
+        };
+        Ok(act)
+    }
+
+    // initiator side of act two, then s, se for act three. returns the peer's node id
+    pub fn process_act_two(
+        &mut self,
+        act_two: &[u8],
+        our_static_key: &SecretKey,
+    ) -> Result<([u8; ACT_THREE_LENGTH], PublicKey), NoiseError> {
+        let (mut state, ephemeral_key, temp_k1) =
+            match std::mem::replace(&mut self.state, NoiseState::Failed) {
+                NoiseState::InitiatorActOneSent {
+                    state,
+                    ephemeral_key,
+                    temp_k1,
+                } => (state, ephemeral_key, temp_k1),
+                _ => return Err(NoiseError::OutOfOrder),
+            };
+        let (remote_ephemeral, c) = read_act(act_two, ACT_TWO_LENGTH)?;
+        state.mix_hash(&remote_ephemeral.serialize());
+        let temp_k2 = state.mix_key(&ecdh(&remote_ephemeral, &ephemeral_key));
+        state.decrypt_and_hash(&temp_k2, 0, c)?;
+
+        let our_node_id = PublicKey::from_secret_key(&self.secp, our_static_key).serialize();
+        let c = state.encrypt_and_hash(&temp_k2, 1, &our_node_id);
+        let temp_k3 = state.mix_key(&ecdh(&remote_ephemeral, our_static_key));
+        let t = state.encrypt_and_hash(&temp_k3, 0, &[]);
+        let (sk, rk) = hkdf(&state.ck, &[]);
+
+        let mut act = [0u8; ACT_THREE_LENGTH];
+        act[1..50].copy_from_slice(&c);
+        act[50..].copy_from_slice(&t);
+        self.finish(HandshakeKeys {
+            initiator_ephemeral: PublicKey::from_secret_key(&self.secp, &ephemeral_key),
+            temp_k1,
+            temp_k2,
+            temp_k3,
+            ck: state.ck,
+            sk,
+            rk,
+        });
+        Ok((act, self.their_node_id.unwrap()))
+    }
+
+    // responder side of act three, returns the initiator's node id
+    #[allow(dead_code)]
+    pub fn process_act_three(&mut self, act_three: &[u8]) -> Result<PublicKey, NoiseError> {
+        let (mut state, ephemeral_key, remote_ephemeral, temp_k1, temp_k2) =
+            match std::mem::replace(&mut self.state, NoiseState::Failed) {
+                NoiseState::ResponderActTwoSent {
+                    state,
+                    ephemeral_key,
+                    remote_ephemeral,
+                    temp_k1,
+                    temp_k2,
+                } => (state, ephemeral_key, remote_ephemeral, temp_k1, temp_k2),
+                _ => return Err(NoiseError::OutOfOrder),
+            };
+        if act_three.len() != ACT_THREE_LENGTH {
+            return Err(NoiseError::InvalidLength);
+        }
+        if act_three[0] != 0 {
+            return Err(NoiseError::UnknownVersion(act_three[0]));
+        }
+        let remote_static = state.decrypt_and_hash(&temp_k2, 1, &act_three[1..50])?;
+        let remote_static =
+            PublicKey::from_slice(&remote_static).map_err(|_| NoiseError::InvalidKey)?;
+        let temp_k3 = state.mix_key(&ecdh(&remote_static, &ephemeral_key));
+        state.decrypt_and_hash(&temp_k3, 0, &act_three[50..])?;
+        let (rk, sk) = hkdf(&state.ck, &[]);
+
+        self.their_node_id = Some(remote_static);
+        self.finish(HandshakeKeys {
+            initiator_ephemeral: remote_ephemeral,
+            temp_k1,
+            temp_k2,
+            temp_k3,
+            ck: state.ck,
+            sk,
+            rk,
+        });
+        Ok(remote_static)
+    }
+
+    fn finish(&mut self, keys: HandshakeKeys) {
+        self.state = NoiseState::Finished {
+            sending: CipherState::new(keys.ck, keys.sk),
+            receiving: CipherState::new(keys.ck, keys.rk),
+            keys,
+        };
+    }
+
+    // the secrets the handshake ended with, once it has
+    pub fn handshake_keys(&self) -> Option<&HandshakeKeys> {
+        match &self.state {
+            NoiseState::Finished { keys, .. } => Some(keys),
+            _ => None,
+        }
+    }
+
+    // the key rotations since the last call, oldest first
+    pub fn take_rotations(&mut self) -> Vec<KeyRotation> {
+        std::mem::take(&mut self.rotations)
+    }
+
+    // encrypted length and its mac, then the encrypted message and its mac
+    pub fn encrypt_message(&mut self, message: &[u8]) -> Result<Vec<u8>, NoiseError> {
+        if message.len() > u16::MAX as usize {
+            return Err(NoiseError::MessageTooLong);
+        }
+        let sending = match &mut self.state {
+            NoiseState::Finished { sending, .. } => sending,
+            _ => return Err(NoiseError::OutOfOrder),
+        };
+        let generation = sending.generation;
+        let mut encrypted = sending.encrypt(&(message.len() as u16).to_be_bytes());
+        encrypted.extend(sending.encrypt(message));
+        if sending.generation != generation {
+            self.rotations.push(sending.rotation(true));
+        }
+        Ok(encrypted)
+    }
+
+    // the length of the message that follows, without its mac
+    pub fn decrypt_length_header(&mut self, header: &[u8]) -> Result<u16, NoiseError> {
+        if header.len() != LENGTH_HEADER_LENGTH {
+            return Err(NoiseError::InvalidLength);
+        }
+        let receiving = match &mut self.state {
+            NoiseState::Finished { receiving, .. } => receiving,
+            _ => return Err(NoiseError::OutOfOrder),
+        };
+        let generation = receiving.generation;
+        let length = receiving.decrypt(header)?;
+        if receiving.generation != generation {
+            self.rotations.push(receiving.rotation(false));
+        }
+        Ok(u16::from_be_bytes([length[0], length[1]]))
+    }
+
+    pub fn decrypt_message(&mut self, body: &[u8]) -> Result<Vec<u8>, NoiseError> {
+        let receiving = match &mut self.state {
+            NoiseState::Finished { receiving, .. } => receiving,
+            _ => return Err(NoiseError::OutOfOrder),
+        };
+        let generation = receiving.generation;
+        let message = receiving.decrypt(body)?;
+        if receiving.generation != generation {
+            self.rotations.push(receiving.rotation(false));
+        }
+        Ok(message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn secret(byte: u8) -> SecretKey {
+        SecretKey::from_slice(&[byte; 32]).unwrap()
+    }
+
+    fn responder_node_id() -> PublicKey {
+        PublicKey::from_slice(
+            &hex::decode("028d7500dd4c12685d1f568b4c2b5048e8534b873319f3a8daa612b469132ec7f7")
+                .unwrap(),
+        )
+        .unwrap()
+    }
+
+    const ACT_ONE: &str = "00036360e856310ce5d294e8be33fc807077dc56ac80d95d9cd4ddbd21325eff73f70df6086551151f58b8afe6c195782c6a";
+    const ACT_TWO: &str = "0002466d7fcae563e5cb09a0d1870bb580344804617879a14949cf22285f1bae3f276e2470b93aac583c9ef6eafca3f730ae";
+    const ACT_THREE: &str = "00b9e3a702e93e3a9948c2ed6e5fd7590a6e1c3a0344cfc9d5b57357049aa22355361aa02e55a8fc28fef5bd6d71ad0c38228dc68b1c466263b47fdf31e560e139ba";
+
+    fn initiator() -> PeerChannelEncryptor {
+        let mut initiator = PeerChannelEncryptor::new_outbound(responder_node_id(), secret(0x12));
+        assert_eq!(hex::encode(initiator.get_act_one().unwrap()), ACT_ONE);
+        let (act_three, node_id) = initiator
+            .process_act_two(&hex::decode(ACT_TWO).unwrap(), &secret(0x11))
+            .unwrap();
+        assert_eq!(hex::encode(act_three), ACT_THREE);
+        assert_eq!(node_id, responder_node_id());
+        initiator
+    }
+
+    // BOLT 8 appendix A, initiator and message encryption
+    #[test]
+    fn test_initiator_and_transport_vectors() {
+        let mut initiator = initiator();
+        let expected = [
+            (
+                0,
+                "cf2b30ddf0cf3f80e7c35a6e6730b59fe802473180f396d88a8fb0db8cbcf25d2f214cf9ea1d95",
+            ),
+            (
+                1,
+                "72887022101f0b6753e0c7de21657d35a4cb2a1f5cde2650528bbc8f837d0f0d7ad833b1a256a1",
+            ),
+            (
+                500,
+                "178cb9d7387190fa34db9c2d50027d21793c9bc2d40b1e14dcf30ebeeeb220f48364f7a4c68bf8",
+            ),
+            (
+                501,
+                "1b186c57d44eb6de4c057c49940d79bb838a145cb528d6e8fd26dbe50a60ca2c104b56b60e45bd",
+            ),
+            (
+                1000,
+                "4a2f3cc3b5e78ddb83dcb426d9863d9d9a723b0337c89dd0b005d89f8d3c05c52b76b29b740f09",
+            ),
+            (
+                1001,
+                "2ecd8c8a5629d0d02ab457a0fdd0f7b90a192cd46be5ecb6ca570bfc5e268338b1a16cf4ef2d36",
+            ),
+        ];
+        for i in 0..=1001 {
+            let encrypted = initiator.encrypt_message(b"hello").unwrap();
+            if let Some((_, output)) = expected.iter().find(|(n, _)| *n == i) {
+                assert_eq!(hex::encode(encrypted), *output, "message {}", i);
+            }
+        }
+    }
+
+    // BOLT 8 appendix A, responder
+    #[test]
+    fn test_responder_vectors() {
+        let mut responder = PeerChannelEncryptor::new_inbound(secret(0x21));
+        let act_two = responder
+            .process_act_one(&hex::decode(ACT_ONE).unwrap(), secret(0x22))
+            .unwrap();
+        assert_eq!(hex::encode(act_two), ACT_TWO);
+        let node_id = responder
+            .process_act_three(&hex::decode(ACT_THREE).unwrap())
+            .unwrap();
+        assert_eq!(
+            hex::encode(node_id.serialize()),
+            "034f355bdcb7cc0af728ef3cceb9615d90684bb5b2ca5f859ab0f0b704075871aa"
+        );
+        assert_eq!(responder.get_noise_step(), NextNoiseStep::NoiseComplete);
+
+        // both ends of the vectors talk to each other, across a key rotation
+        let mut initiator = initiator();
+        for i in 0..600u16 {
+            let message = i.to_be_bytes();
+            let encrypted = initiator.encrypt_message(&message).unwrap();
+            let length = responder
+                .decrypt_length_header(&encrypted[..LENGTH_HEADER_LENGTH])
+                .unwrap();
+            assert_eq!(length, 2);
+            let decrypted = responder
+                .decrypt_message(&encrypted[LENGTH_HEADER_LENGTH..])
+                .unwrap();
+            assert_eq!(decrypted, message);
+        }
+    }
+
+    // BOLT 8 appendix A, failing handshakes
+    #[test]
+    fn test_rejects_bad_acts() {
+        let mut initiator = PeerChannelEncryptor::new_outbound(responder_node_id(), secret(0x12));
+        initiator.get_act_one().unwrap();
+        let mut act_two = hex::decode(ACT_TWO).unwrap();
+        act_two[0] = 1;
+        assert_eq!(
+            initiator.process_act_two(&act_two, &secret(0x11)),
+            Err(NoiseError::UnknownVersion(1))
+        );
+        // a failed step can't be retried
+        assert_eq!(
+            initiator.process_act_two(&hex::decode(ACT_TWO).unwrap(), &secret(0x11)),
+            Err(NoiseError::OutOfOrder)
+        );
+
+        let mut responder = PeerChannelEncryptor::new_inbound(secret(0x21));
+        let mut act_one = hex::decode(ACT_ONE).unwrap();
+        act_one[49] ^= 1;
+        assert_eq!(
+            responder.process_act_one(&act_one, secret(0x22)),
+            Err(NoiseError::InvalidMac)
+        );
+        let mut responder = PeerChannelEncryptor::new_inbound(secret(0x21));
+        let mut act_one = hex::decode(ACT_ONE).unwrap();
+        act_one[1] = 4;
+        assert_eq!(
+            responder.process_act_one(&act_one, secret(0x22)),
+            Err(NoiseError::InvalidKey)
+        );
+    }
+}
@@ -0,0 +1,245 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::capture::Direction;
+
+const SECTION_HEADER_BLOCK: u32 = 0x0a0d0d0a;
+const INTERFACE_DESCRIPTION_BLOCK: u32 = 1;
+const ENHANCED_PACKET_BLOCK: u32 = 6;
+const BYTE_ORDER_MAGIC: u32 = 0x1a2b3c4d;
+const LINKTYPE_ETHERNET: u16 = 1;
+const OPT_COMMENT: u16 = 1;
+const OPT_END: u16 = 0;
+// keeps every segment under the ip length limit
+const MAX_SEGMENT: usize = 65_000;
+
+fn pad4(bytes: &mut Vec<u8>) {
+    while !bytes.len().is_multiple_of(4) {
+        bytes.push(0);
+    }
+}
+
+// block type, total length, body, total length again
+fn block(block_type: u32, body: &[u8]) -> Vec<u8> {
+    let total = (12 + body.len()) as u32;
+    let mut block = Vec::with_capacity(total as usize);
+    block.extend(block_type.to_le_bytes());
+    block.extend(total.to_le_bytes());
+    block.extend(body);
+    block.extend(total.to_le_bytes());
+    block
+}
+
+fn checksum(header: &[u8]) -> u16 {
+    let mut sum: u32 = header
+        .chunks(2)
+        .map(|pair| u16::from_be_bytes([pair[0], *pair.get(1).unwrap_or(&0)]) as u32)
+        .sum();
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+// ethernet, ip and tcp headers around one segment, checksums left out where wireshark
+// doesn't check them by default
+fn tcp_packet(src: SocketAddr, dst: SocketAddr, seq: u32, ack: u32, payload: &[u8]) -> Vec<u8> {
+    let mut tcp = Vec::with_capacity(20 + payload.len());
+    tcp.extend(src.port().to_be_bytes());
+    tcp.extend(dst.port().to_be_bytes());
+    tcp.extend(seq.to_be_bytes());
+    tcp.extend(ack.to_be_bytes());
+    // 5 words of header, psh and ack
+    tcp.extend([0x50, 0x18]);
+    tcp.extend(u16::MAX.to_be_bytes());
+    tcp.extend([0, 0, 0, 0]);
+    tcp.extend(payload);
+
+    let mut packet = vec![0u8; 12];
+    match (
+        to_ipv6_if_mixed(src.ip(), dst.ip()),
+        to_ipv6_if_mixed(dst.ip(), src.ip()),
+    ) {
+        (IpAddr::V4(src_ip), IpAddr::V4(dst_ip)) => {
+            packet.extend(0x0800u16.to_be_bytes());
+            let mut ip = Vec::with_capacity(20);
+            ip.extend([0x45, 0]);
+            ip.extend(((20 + tcp.len()) as u16).to_be_bytes());
+            // id, don't fragment, ttl 64, tcp, checksum
+            ip.extend([0, 0, 0x40, 0, 64, 6, 0, 0]);
+            ip.extend(src_ip.octets());
+            ip.extend(dst_ip.octets());
+            let sum = checksum(&ip);
+            ip[10..12].copy_from_slice(&sum.to_be_bytes());
+            packet.extend(ip);
+        }
+        (IpAddr::V6(src_ip), IpAddr::V6(dst_ip)) => {
+            packet.extend(0x86ddu16.to_be_bytes());
+            packet.extend([0x60, 0, 0, 0]);
+            packet.extend((tcp.len() as u16).to_be_bytes());
+            // tcp, hop limit 64
+            packet.extend([6, 64]);
+            packet.extend(src_ip.octets());
+            packet.extend(dst_ip.octets());
+        }
+        _ => unreachable!(),
+    }
+    packet.extend(tcp);
+    packet
+}
+
+fn to_ipv6_if_mixed(ip: IpAddr, other: IpAddr) -> IpAddr {
+    match (ip, other) {
+        (IpAddr::V4(v4), IpAddr::V6(_)) => IpAddr::V6(v4.to_ipv6_mapped()),
+        _ => ip,
+    }
+}
+
+// writes decrypted messages as tcp segments in a pcapng file, one message per segment,
+// so wireshark shows each session as plain lightning messages between the real endpoints
+pub struct PcapWriter {
+    file: File,
+    // next sequence number of each direction of each connection
+    sequences: HashMap<(SocketAddr, SocketAddr), u32>,
+}
+
+impl PcapWriter {
+    pub fn create(path: &Path) -> io::Result<Self> {
+        let mut file = File::create(path)?;
+        let mut section = Vec::new();
+        section.extend(BYTE_ORDER_MAGIC.to_le_bytes());
+        // version 1.0, unknown section length
+        section.extend(1u16.to_le_bytes());
+        section.extend(0u16.to_le_bytes());
+        section.extend(u64::MAX.to_le_bytes());
+        file.write_all(&block(SECTION_HEADER_BLOCK, &section))?;
+        // timestamps in microseconds, the default resolution
+        let mut interface = Vec::new();
+        interface.extend(LINKTYPE_ETHERNET.to_le_bytes());
+        interface.extend(0u16.to_le_bytes());
+        interface.extend(0u32.to_le_bytes());
+        file.write_all(&block(INTERFACE_DESCRIPTION_BLOCK, &interface))?;
+        file.flush()?;
+        Ok(PcapWriter {
+            file,
+            sequences: HashMap::new(),
+        })
+    }
+
+    pub fn write(
+        &mut self,
+        local: SocketAddr,
+        remote: SocketAddr,
+        node_public_key: &[u8; 33],
+        direction: Direction,
+        bytes: &[u8],
+    ) -> io::Result<()> {
+        let (src, dst) = match direction {
+            Direction::Inbound => (remote, local),
+            Direction::Outbound => (local, remote),
+        };
+        let micros = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_micros() as u64;
+        let comment = format!("{:?} {}", direction, hex::encode(node_public_key));
+        for segment in bytes.chunks(MAX_SEGMENT) {
+            let ack = *self.sequences.get(&(dst, src)).unwrap_or(&0);
+            let seq = self.sequences.entry((src, dst)).or_insert(0);
+            let packet = tcp_packet(src, dst, *seq, ack, segment);
+            *seq = seq.wrapping_add(segment.len() as u32);
+
+            let mut body = Vec::with_capacity(20 + packet.len() + comment.len() + 12);
+            body.extend(0u32.to_le_bytes());
+            body.extend(((micros >> 32) as u32).to_le_bytes());
+            body.extend((micros as u32).to_le_bytes());
+            body.extend((packet.len() as u32).to_le_bytes());
+            body.extend((packet.len() as u32).to_le_bytes());
+            body.extend(&packet);
+            pad4(&mut body);
+            body.extend(OPT_COMMENT.to_le_bytes());
+            body.extend((comment.len() as u16).to_le_bytes());
+            body.extend(comment.as_bytes());
+            pad4(&mut body);
+            body.extend(OPT_END.to_le_bytes());
+            body.extend(0u16.to_le_bytes());
+            self.file.write_all(&block(ENHANCED_PACKET_BLOCK, &body))?;
+        }
+        self.file.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // (block type, body) of every block in the file
+    fn blocks(bytes: &[u8]) -> Vec<(u32, Vec<u8>)> {
+        let mut blocks = Vec::new();
+        let mut rest = bytes;
+        while !rest.is_empty() {
+            let block_type = u32::from_le_bytes(rest[..4].try_into().unwrap());
+            let total = u32::from_le_bytes(rest[4..8].try_into().unwrap()) as usize;
+            assert!(total.is_multiple_of(4));
+            assert_eq!(&rest[total - 4..total], &rest[4..8]);
+            blocks.push((block_type, rest[8..total - 4].to_vec()));
+            rest = &rest[total..];
+        }
+        blocks
+    }
+
+    #[test]
+    fn test_write_pcapng() {
+        let path = std::env::temp_dir().join("lmprs_pcap_test.pcapng");
+        let local: SocketAddr = "10.0.0.1:50000".parse().unwrap();
+        let remote: SocketAddr = "10.0.0.2:9735".parse().unwrap();
+        let alice = [2u8; 33];
+        let mut writer = PcapWriter::create(&path).unwrap();
+        writer
+            .write(
+                local,
+                remote,
+                &alice,
+                Direction::Outbound,
+                &[0, 18, 0, 1, 0, 0],
+            )
+            .unwrap();
+        writer
+            .write(local, remote, &alice, Direction::Inbound, &[0, 19, 0, 1, 0])
+            .unwrap();
+        drop(writer);
+
+        let blocks = blocks(&std::fs::read(&path).unwrap());
+        let types: Vec<u32> = blocks.iter().map(|(block_type, _)| *block_type).collect();
+        assert_eq!(
+            types,
+            vec![
+                SECTION_HEADER_BLOCK,
+                INTERFACE_DESCRIPTION_BLOCK,
+                ENHANCED_PACKET_BLOCK,
+                ENHANCED_PACKET_BLOCK
+            ]
+        );
+
+        // the pong comes from the peer's port and acks the ping
+        let body = &blocks[3].1;
+        let length = u32::from_le_bytes(body[12..16].try_into().unwrap()) as usize;
+        let packet = &body[20..20 + length];
+        assert_eq!(length, 14 + 20 + 20 + 5);
+        assert_eq!(checksum(&packet[14..34]), 0);
+        let tcp = &packet[34..];
+        assert_eq!(u16::from_be_bytes([tcp[0], tcp[1]]), 9735);
+        assert_eq!(u32::from_be_bytes(tcp[8..12].try_into().unwrap()), 6);
+        assert_eq!(&tcp[20..], &[0, 19, 0, 1, 0]);
+        let comment = format!("Inbound {}", hex::encode(alice));
+        assert!(body
+            .windows(comment.len())
+            .any(|window| window == comment.as_bytes()));
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
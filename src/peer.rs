@@ -1,108 +1,979 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
 use bitcoin::secp256k1::SecretKey;
 
+use serde_json::{json, Value};
+use tokio::sync::{broadcast, mpsc, oneshot};
+use tokio::time::MissedTickBehavior;
+use tokio_stream::{StreamExt, StreamMap};
+
 use crate::{
-    config::DO_CONNECT_TO_NEW_NODES,
-    message_decoder::MessageContainer,
-    messages::{ChannelAnnouncementMessage, InitMessage, NodeAnnouncementMessage, PongMessage},
+    api::{ApiCall, ApiError, ApiRequest, ApiResponse},
+    capture::{CaptureReader, CaptureWriter, Direction},
+    codec::Taps,
+    config::{
+        Config, GossipFilterPolicy, Timeouts, DO_CONNECT_TO_NEW_NODES,
+        HOUSEKEEPING_INTERVAL_MILLIS, LOCAL_FEATURES, MAX_MISSED_PONGS, MESSAGE_TAIL_LENGTH,
+        PEER_REPORT_FILE, PEER_STORAGE_DIR, PROPAGATION_EXPORT_FILE, PROVIDE_STORAGE,
+        RECONCILE_CAPACITY, RECONCILE_LIVE, RELAY_GOSSIP, STATS_INTERVAL,
+    },
+    feed::{message_json, FeedMessage},
+    gossip_queries::{reply_channel_range, reply_short_channel_ids},
+    graph::{ChannelInfo, NetworkGraph},
+    keylog::KeyLog,
+    message_decoder::{MessageContainer, MessageDecoder},
+    messages::{
+        GossipTimestampFilterMessage, InitMessageBuilder, PeerStorageRetrievalMessage, PongMessage,
+        QueryChannelRangeMessage,
+    },
+    metrics::Metrics,
     node::Node,
     node_connection::{NodeConnection, NodeConnectionError},
-    serialization::{PointElement, SerializableToBytes, ShortChannelIDElement},
+    pcap::PcapWriter,
+    peer_storage::PeerStorage,
+    ping::PongResult,
+    propagation::PropagationTracker,
+    reconciliation::{gossip_timestamp, simulate, Reconciler},
+    relay::GossipRelay,
+    serialization::{ChainHashElement, Features, FeaturesBuilder, FeaturesElement, PointElement},
+    stats::{print_table, write_report, PeerReport},
+    util::{get_current_timestamp, get_current_timestamp_millis},
 };
 
+#[cfg(feature = "sqlite")]
+use crate::store::GossipStore;
+
 #[allow(dead_code)]
 #[derive(Debug)]
 pub enum MessageHandlerError {
     NodeConnectionError(NodeConnectionError),
     NodeHandshakeError(NodeConnectionError),
+    UnsupportedFeatures(Vec<usize>),
+    NoCommonNetwork,
+}
+
+// a connection made off the event loop, with whoever asked for it over the api
+struct ConnectAttempt {
+    node: Node,
+    result: Result<NodeConnection, MessageHandlerError>,
+    reply: Option<oneshot::Sender<ApiResponse>>,
 }
 
 pub struct MiniPeer {
     secret_key: SecretKey,
-    node_connections: HashMap<[u8; 33], NodeConnection>,
-    // eventually make a channel type not just the announcement message
-    known_channels: HashMap<ShortChannelIDElement, ChannelAnnouncementMessage>,
-    known_nodes: HashMap<PointElement, NodeAnnouncementMessage>,
+    chain_hash: ChainHashElement,
+    gossip_filter: GossipFilterPolicy,
+    query_channel_range: bool,
+    // connect to nodes we learn about from node announcements
+    connect_to_new_nodes: bool,
+    local_features: FeaturesElement,
+    node_connections: StreamMap<[u8; 33], NodeConnection>,
+    // connects and handshakes run in their own tasks and send the connection back here
+    connects: mpsc::UnboundedSender<ConnectAttempt>,
+    connected: mpsc::UnboundedReceiver<ConnectAttempt>,
+    connecting: HashSet<[u8; 33]>,
+    graph: NetworkGraph,
+    relay: GossipRelay,
+    reconciler: Reconciler,
+    propagation: PropagationTracker,
+    peer_storage: PeerStorage,
+    wrong_chain_messages: u64,
+    taps: Taps,
+    timeouts: Timeouts,
+    // connections that timed out, by stage
+    timeouts_by_stage: HashMap<&'static str, u64>,
+    // sessions that have ended, kept for the report
+    closed_sessions: Vec<PeerReport>,
+    last_stats: u64,
+    connection_attempts: HashMap<[u8; 33], u64>,
+    // rendered prometheus metrics, served by the metrics endpoint
+    metrics: Option<Arc<Mutex<String>>>,
+    // queries from the api, answered by the event loop as they come in
+    api: Option<mpsc::Receiver<ApiCall>>,
+    // (unix millis, node, bytes) of the last messages we received
+    recent_messages: VecDeque<(u64, [u8; 33], Vec<u8>)>,
+    // subscribers of the websocket feed
+    feed: Option<broadcast::Sender<FeedMessage>>,
+    json_output: bool,
+    #[cfg(feature = "sqlite")]
+    store: Option<GossipStore>,
 }
 
 impl MiniPeer {
-    pub fn new(secret_key: SecretKey) -> Self {
-        MiniPeer {
+    pub fn new(secret_key: SecretKey, config: Config) -> Self {
+        let mut features = FeaturesBuilder::new();
+        for (feature, flag) in LOCAL_FEATURES {
+            features = features.set(feature.clone(), *flag);
+        }
+        if PROVIDE_STORAGE {
+            features = features.optional(Features::ProvideStorage);
+        }
+        #[cfg(feature = "sqlite")]
+        let store = config
+            .db_path
+            .as_ref()
+            .and_then(|path| match GossipStore::open(path) {
+                Ok(store) => Some(store),
+                Err(e) => {
+                    println!("Failed to open {}: {:?}", path.display(), e);
+                    None
+                }
+            });
+        let capture =
+            config
+                .capture_path
+                .as_ref()
+                .and_then(|path| match CaptureWriter::open(path) {
+                    Ok(capture) => Some(Arc::new(Mutex::new(capture))),
+                    Err(e) => {
+                        println!("Failed to open {}: {:?}", path.display(), e);
+                        None
+                    }
+                });
+        let pcap = config
+            .pcap_path
+            .as_ref()
+            .and_then(|path| match PcapWriter::create(path) {
+                Ok(pcap) => Some(Arc::new(Mutex::new(pcap))),
+                Err(e) => {
+                    println!("Failed to create {}: {:?}", path.display(), e);
+                    None
+                }
+            });
+        let keylog = config
+            .keylog_path
+            .as_ref()
+            .and_then(|path| match KeyLog::open(path) {
+                Ok(keylog) => {
+                    println!("Logging session keys to {}", path.display());
+                    Some(Arc::new(Mutex::new(keylog)))
+                }
+                Err(e) => {
+                    println!("Failed to open {}: {:?}", path.display(), e);
+                    None
+                }
+            });
+        let (connects, connected) = mpsc::unbounded_channel();
+        #[allow(unused_mut)]
+        let mut peer = MiniPeer {
             secret_key,
-            node_connections: HashMap::new(),
-            known_channels: HashMap::new(),
-            known_nodes: HashMap::new(),
+            chain_hash: ChainHashElement::from_network(config.network),
+            gossip_filter: config.gossip_filter,
+            query_channel_range: config.query_channel_range,
+            connect_to_new_nodes: DO_CONNECT_TO_NEW_NODES,
+            local_features: features.build(),
+            node_connections: StreamMap::new(),
+            connects,
+            connected,
+            connecting: HashSet::new(),
+            graph: NetworkGraph::new(),
+            relay: GossipRelay::new(),
+            reconciler: Reconciler::new(),
+            propagation: PropagationTracker::new(),
+            peer_storage: PeerStorage::new(PathBuf::from(PEER_STORAGE_DIR)),
+            wrong_chain_messages: 0,
+            taps: Taps {
+                capture,
+                pcap,
+                keylog,
+            },
+            timeouts: config.timeouts,
+            timeouts_by_stage: HashMap::new(),
+            closed_sessions: Vec::new(),
+            last_stats: get_current_timestamp(),
+            connection_attempts: HashMap::new(),
+            metrics: None,
+            api: None,
+            recent_messages: VecDeque::new(),
+            feed: None,
+            json_output: config.json_output,
+            #[cfg(feature = "sqlite")]
+            store,
+        };
+        #[cfg(feature = "sqlite")]
+        peer.reload_graph();
+        peer
+    }
+
+    // picks up where the last run left off
+    #[cfg(feature = "sqlite")]
+    fn reload_graph(&mut self) {
+        let messages = match &self.store {
+            Some(store) => match store.load_latest() {
+                Ok(messages) => messages,
+                Err(e) => {
+                    println!("Failed to load stored gossip: {:?}", e);
+                    return;
+                }
+            },
+            None => return,
+        };
+        for message in messages {
+            let _ = match message {
+                MessageContainer::ChannelAnnouncement(msg) => {
+                    self.graph.handle_channel_announcement(&msg)
+                }
+                MessageContainer::NodeAnnouncement(msg) => {
+                    self.graph.handle_node_announcement(&msg)
+                }
+                MessageContainer::ChannelUpdate(msg) => self.graph.handle_channel_update(&msg),
+                _ => Ok(()),
+            };
+        }
+        println!(
+            "Loaded {} channels and {} nodes from the database",
+            self.graph.channels.len(),
+            self.graph.nodes.len()
+        );
+    }
+
+    // feeds the inbound messages of a capture through the decoder and the graph, no network
+    pub fn replay(&mut self, path: &Path) -> std::io::Result<()> {
+        let reader = CaptureReader::open(path)?;
+        println!(
+            "Replaying {}, captured from unix time {}ms",
+            path.display(),
+            reader.start_ms
+        );
+        let (mut records, mut decode_errors, mut accepted) = (0, 0, 0);
+        let reader_start_ms = reader.start_ms;
+        for record in reader {
+            let record = record?;
+            records += 1;
+            if record.direction != Direction::Inbound {
+                continue;
+            }
+            let message = match MessageDecoder::from_bytes(&record.bytes) {
+                Ok((message, _)) => message,
+                Err(e) => {
+                    decode_errors += 1;
+                    println!(
+                        "Failed to decode message from {} at {}us: {:?} {}",
+                        hex::encode(record.node_public_key),
+                        record.micros,
+                        e,
+                        hex::encode(&record.bytes)
+                    );
+                    continue;
+                }
+            };
+            if let Some(chain_hash) = message.chain_hash() {
+                if *chain_hash != self.chain_hash {
+                    self.wrong_chain_messages += 1;
+                    continue;
+                }
+            }
+            // graph timestamps and spam budgets follow the capture's clock, not ours
+            let received_at = (reader_start_ms + record.micros / 1000) / 1000;
+            if self.handle_gossip(&message, received_at).is_some() {
+                accepted += 1;
+            }
         }
+        println!(
+            "Replayed {} records: {} decode errors, {} wrong chain, {} gossip accepted",
+            records, decode_errors, self.wrong_chain_messages, accepted
+        );
+        println!(
+            "Known channels: {}, known nodes: {}",
+            self.graph.channels.len(),
+            self.graph.nodes.len()
+        );
+        Ok(())
     }
 
     pub fn num_connections(&self) -> usize {
         self.node_connections.len()
     }
 
+    // every connection is polled together with the api and a housekeeping timer, so a quiet
+    // peer never holds up the others
     pub async fn event_loop(&mut self) {
+        let mut housekeeping =
+            tokio::time::interval(Duration::from_millis(HOUSEKEEPING_INTERVAL_MILLIS));
+        housekeeping.set_missed_tick_behavior(MissedTickBehavior::Delay);
         loop {
-            let mut inbounds = Vec::new();
-            let mut disconnects = Vec::new();
-            for node_conn in &mut self.node_connections.values_mut() {
-                match node_conn.read_next_message().await {
-                    Ok(wrapped_message) => {
-                        inbounds.push((wrapped_message, node_conn.public_key.clone()));
-                    }
-                    Err(err) => {
-                        match err {
-                            NodeConnectionError::IOError(_) => {
-                                disconnects.push(node_conn.public_key.clone());
-                            }
-                            _ => {
+            tokio::select! {
+                Some((node_public_key, result)) = self.node_connections.next(),
+                    if !self.node_connections.is_empty() =>
+                {
+                    match result {
+                        Ok(message) => self.handle_message(message, node_public_key).await,
+                        Err(err) => {
+                            if !matches!(err, NodeConnectionError::IOError(_)) {
                                 println!("Failed to read: {:?}", err);
                             }
+                            self.disconnect(&node_public_key);
                         }
-                        continue;
                     }
                 }
-                if node_conn.ready_for_ping() {
-                    node_conn.send_ping().await.unwrap();
+                Some(attempt) = self.connected.recv() => self.finish_connect(attempt),
+                call = next_api_call(&mut self.api) => match call {
+                    // answered once the connection is up
+                    Some(ApiCall { request: ApiRequest::Connect(node), reply }) => {
+                        self.connect_on_request(node, reply)
+                    }
+                    Some(call) => {
+                        let response = self.answer(call.request).await;
+                        let _ = call.reply.send(response);
+                    }
+                    // the server is gone, nobody will ask again
+                    None => self.api = None,
+                },
+                _ = housekeeping.tick() => self.housekeeping().await,
+            }
+        }
+    }
+
+    async fn handle_message(&mut self, message: MessageContainer, node_public_key: [u8; 33]) {
+        match self.handle_inbound_message(message, node_public_key).await {
+            Ok(_) => (),
+            Err(MessageHandlerError::UnsupportedFeatures(bits)) => {
+                println!(
+                    "Disconnecting from {}: unsupported required feature bits {:?}",
+                    hex::encode(node_public_key),
+                    bits
+                );
+                self.disconnect(&node_public_key);
+            }
+            Err(MessageHandlerError::NoCommonNetwork) => {
+                println!(
+                    "Disconnecting from {}: no common network",
+                    hex::encode(node_public_key)
+                );
+                self.disconnect(&node_public_key);
+            }
+            Err(err) => println!("Failed to handle message: {:?}", err),
+        }
+    }
+
+    // pings, timeouts and the periodic jobs
+    async fn housekeeping(&mut self) {
+        let mut disconnects = Vec::new();
+        let mut timeouts = Vec::new();
+        for node_conn in self.node_connections.values_mut() {
+            if let Some(err) = node_conn.timed_out() {
+                timeouts.push((node_conn.public_key, err));
+                continue;
+            }
+            if node_conn.ready_for_ping() {
+                if let Err(e) = node_conn.send_ping().await {
+                    println!("Failed to send ping: {:?}", e);
+                    disconnects.push(node_conn.public_key);
+                    continue;
+                }
+            }
+            if node_conn.pings.missed() >= MAX_MISSED_PONGS {
+                println!(
+                    "Disconnecting from {}: {} pongs missed",
+                    hex::encode(node_conn.public_key),
+                    node_conn.pings.missed()
+                );
+                disconnects.push(node_conn.public_key);
+            }
+        }
+        for (node_public_key, err) in timeouts {
+            self.record_timeout(node_public_key, &err);
+            disconnects.push(node_public_key);
+        }
+        for node_public_key in disconnects {
+            self.disconnect(&node_public_key);
+        }
+        self.flush_relay().await;
+        self.flush_store();
+        self.run_reconciliation().await;
+        self.export_propagation();
+        self.print_stats();
+        self.publish_metrics();
+        if self.graph.prune_due() {
+            let report = self.graph.prune_stale(get_current_timestamp());
+            println!("Pruned stale channels: {:?}", report);
+        }
+    }
+
+    pub fn set_feed(&mut self, messages: broadcast::Sender<FeedMessage>) {
+        self.feed = Some(messages);
+    }
+
+    pub fn set_api(&mut self, calls: mpsc::Receiver<ApiCall>) {
+        self.api = Some(calls);
+    }
+
+    async fn answer(&mut self, request: ApiRequest) -> ApiResponse {
+        match request {
+            ApiRequest::Peers => {
+                let reports: Vec<PeerReport> = self
+                    .node_connections
+                    .values()
+                    .map(|node_conn| node_conn.report())
+                    .collect();
+                Ok(json!(reports))
+            }
+            ApiRequest::GossipFilter => {
+                Ok(json!({ "gossip_filter": self.gossip_filter.to_string() }))
+            }
+            ApiRequest::AutoConnect => Ok(json!({ "auto_connect": self.connect_to_new_nodes })),
+            ApiRequest::Connect(_) => unreachable!("connects are answered by connect_on_request"),
+            ApiRequest::Disconnect(node_public_key) => {
+                if !self.node_connections.contains_key(&node_public_key) {
+                    return Err(ApiError::NotFound);
+                }
+                println!(
+                    "Disconnecting from {} on request",
+                    hex::encode(node_public_key)
+                );
+                self.disconnect(&node_public_key);
+                Ok(json!({ "disconnected": hex::encode(node_public_key) }))
+            }
+            ApiRequest::SetGossipFilter(gossip_filter) => {
+                println!("Switching gossip filter to {}", gossip_filter);
+                self.set_gossip_filter(gossip_filter).await;
+                Ok(json!({ "gossip_filter": self.gossip_filter.to_string() }))
+            }
+            ApiRequest::SetAutoConnect(enabled) => {
+                self.connect_to_new_nodes = enabled;
+                Ok(json!({ "auto_connect": self.connect_to_new_nodes }))
+            }
+            request => self.query(request),
+        }
+    }
+
+    // the read-only requests, straight from the graph and the connections
+    fn query(&self, request: ApiRequest) -> ApiResponse {
+        let to_json = |value: Result<Value, serde_json::Error>| {
+            value.map_err(|e| ApiError::BadRequest(e.to_string()))
+        };
+        match request {
+            ApiRequest::Node(node_id) => {
+                let node_id = PointElement { value: node_id };
+                let announcement = self.graph.nodes.get(&node_id).ok_or(ApiError::NotFound)?;
+                to_json(serde_json::to_value(announcement))
+            }
+            ApiRequest::NodeChannels(node_id) => {
+                let node_id = PointElement { value: node_id };
+                let channels: Vec<&ChannelInfo> = self
+                    .graph
+                    .channels
+                    .values()
+                    .filter(|channel| {
+                        channel.announcement.node_id_1 == node_id
+                            || channel.announcement.node_id_2 == node_id
+                    })
+                    .collect();
+                if channels.is_empty() && !self.graph.nodes.contains_key(&node_id) {
+                    return Err(ApiError::NotFound);
+                }
+                to_json(serde_json::to_value(channels))
+            }
+            ApiRequest::Channel(short_channel_id) => {
+                let channel = self
+                    .graph
+                    .channels
+                    .get(&short_channel_id)
+                    .ok_or(ApiError::NotFound)?;
+                to_json(serde_json::to_value(channel))
+            }
+            ApiRequest::Connections => to_json(serde_json::to_value(self.peer_reports())),
+            ApiRequest::Messages(limit) => {
+                let skip = self.recent_messages.len().saturating_sub(limit);
+                let messages: Vec<Value> = self
+                    .recent_messages
+                    .iter()
+                    .skip(skip)
+                    .filter_map(|(received_at, node_public_key, bytes)| {
+                        let (message, _) = MessageDecoder::from_bytes(bytes).ok()?;
+                        Some(message_json(*received_at, node_public_key, &message))
+                    })
+                    .collect();
+                Ok(Value::Array(messages))
+            }
+            // control requests are all answered above
+            _ => Err(ApiError::NotFound),
+        }
+    }
+
+    fn disconnect(&mut self, node_public_key: &[u8; 33]) {
+        if let Some(node_conn) = self.node_connections.remove(node_public_key) {
+            let mut report = node_conn.report();
+            report.disconnected_at = Some(get_current_timestamp());
+            self.closed_sessions.push(report);
+        }
+    }
+
+    // every session so far, closed ones first
+    pub fn peer_reports(&self) -> Vec<PeerReport> {
+        let mut reports = self.closed_sessions.clone();
+        reports.extend(
+            self.node_connections
+                .values()
+                .map(|node_conn| node_conn.report()),
+        );
+        reports
+    }
+
+    fn print_stats(&mut self) {
+        let now = get_current_timestamp();
+        if self.last_stats + STATS_INTERVAL > now {
+            return;
+        }
+        self.last_stats = now;
+        print_table(&self.peer_reports(), now);
+    }
+
+    pub fn set_metrics(&mut self, snapshot: Arc<Mutex<String>>) {
+        self.metrics = Some(snapshot);
+    }
+
+    fn publish_metrics(&self) {
+        if let Some(snapshot) = &self.metrics {
+            *snapshot.lock().unwrap() = self.render_metrics();
+        }
+    }
+
+    pub fn render_metrics(&self) -> String {
+        let mut metrics = Metrics::new();
+        let peers: Vec<(String, &NodeConnection)> = self
+            .node_connections
+            .values()
+            .map(|node_conn| (hex::encode(node_conn.public_key), node_conn))
+            .collect();
+        metrics.gauge(
+            "lmprs_connected_peers",
+            "Peers we have a connection to.",
+            &[],
+            peers.len() as f64,
+        );
+        for (peer, node_conn) in &peers {
+            for (message_type, count) in &node_conn.traffic().messages_in {
+                metrics.counter(
+                    "lmprs_messages_received_total",
+                    "Messages received, by peer and type.",
+                    &[("peer", peer), ("type", message_type)],
+                    *count,
+                );
+            }
+        }
+        for (peer, node_conn) in &peers {
+            for (message_type, count) in &node_conn.traffic().messages_out {
+                metrics.counter(
+                    "lmprs_messages_sent_total",
+                    "Messages sent, by peer and type.",
+                    &[("peer", peer), ("type", message_type)],
+                    *count,
+                );
+            }
+        }
+        for (peer, node_conn) in &peers {
+            metrics.counter(
+                "lmprs_bytes_received_total",
+                "Bytes received on the wire.",
+                &[("peer", peer)],
+                node_conn.traffic().bytes_in,
+            );
+        }
+        for (peer, node_conn) in &peers {
+            metrics.counter(
+                "lmprs_bytes_sent_total",
+                "Bytes sent on the wire.",
+                &[("peer", peer)],
+                node_conn.traffic().bytes_out,
+            );
+        }
+        for (peer, node_conn) in &peers {
+            metrics.counter(
+                "lmprs_decode_errors_total",
+                "Messages that decrypted but could not be decoded.",
+                &[("peer", peer)],
+                node_conn.traffic().decode_errors,
+            );
+        }
+        for (peer, node_conn) in &peers {
+            metrics.counter(
+                "lmprs_signature_failures_total",
+                "Gossip with an invalid signature.",
+                &[("peer", peer)],
+                node_conn.signature_failures,
+            );
+        }
+        for (peer, node_conn) in &peers {
+            let (histogram, total) = node_conn.pings.rtt_histogram();
+            metrics.histogram_millis(
+                "lmprs_ping_rtt_seconds",
+                "Round trip time of our pings.",
+                &[("peer", peer)],
+                histogram,
+                total.as_millis() as u64,
+            );
+        }
+        for (peer, node_conn) in &peers {
+            metrics.gauge(
+                "lmprs_missed_pongs",
+                "Pings in a row without the right pong.",
+                &[("peer", peer)],
+                node_conn.pings.missed() as f64,
+            );
+        }
+        for (node_public_key, attempts) in &self.connection_attempts {
+            metrics.counter(
+                "lmprs_connection_attempts_total",
+                "Times we tried to connect to a peer, everything after the first is a reconnect.",
+                &[("peer", &hex::encode(node_public_key))],
+                *attempts,
+            );
+        }
+        for stage in ["connect", "handshake", "init", "idle"] {
+            metrics.counter(
+                "lmprs_timeouts_total",
+                "Connections that timed out, by stage.",
+                &[("stage", stage)],
+                self.timeouts_by_stage.get(stage).copied().unwrap_or(0),
+            );
+        }
+        let channel_updates = self
+            .graph
+            .channels
+            .values()
+            .map(|channel| channel.updates.iter().flatten().count())
+            .sum::<usize>();
+        metrics.gauge(
+            "lmprs_graph_nodes",
+            "Nodes with an announcement in the graph.",
+            &[],
+            self.graph.nodes.len() as f64,
+        );
+        metrics.gauge(
+            "lmprs_graph_channels",
+            "Channels in the graph.",
+            &[],
+            self.graph.channels.len() as f64,
+        );
+        metrics.gauge(
+            "lmprs_graph_channel_updates",
+            "Channel directions with an update in the graph.",
+            &[],
+            channel_updates as f64,
+        );
+        metrics.gauge(
+            "lmprs_graph_zombie_channels",
+            "Pruned channels waiting for a fresh update.",
+            &[],
+            self.graph.zombies.len() as f64,
+        );
+        metrics.counter(
+            "lmprs_graph_signature_failures_total",
+            "Gossip with an invalid signature, from any peer.",
+            &[],
+            self.graph.signature_failures,
+        );
+        metrics.counter(
+            "lmprs_spam_dropped_total",
+            "Gossip dropped by the spam filter.",
+            &[],
+            self.graph.spam.dropped,
+        );
+        metrics.counter(
+            "lmprs_wrong_chain_messages_total",
+            "Messages for a chain we are not on.",
+            &[],
+            self.wrong_chain_messages,
+        );
+        metrics.finish()
+    }
+
+    // writes the gossip stored this pass in one transaction
+    pub fn flush_store(&mut self) {
+        #[cfg(feature = "sqlite")]
+        if let Some(store) = &mut self.store {
+            if let Err(e) = store.flush() {
+                println!("Failed to store gossip: {:?}", e);
+            }
+        }
+    }
+
+    pub fn write_peer_report(&self) {
+        let reports = self.peer_reports();
+        let now = get_current_timestamp();
+        print_table(&reports, now);
+        match write_report(Path::new(PEER_REPORT_FILE), &reports, now) {
+            Ok(()) => println!(
+                "Wrote {} peer sessions to {}",
+                reports.len(),
+                PEER_REPORT_FILE
+            ),
+            Err(e) => println!("Failed to write peer report: {:?}", e),
+        }
+    }
+
+    async fn flush_relay(&mut self) {
+        let batch = match self.relay.take_batch() {
+            Some(batch) => batch,
+            None => return,
+        };
+        for node_conn in self.node_connections.values_mut() {
+            let supports_gossip_queries = node_conn
+                .negotiated_features
+                .contains(&Features::GossipQueries);
+            let mut sent = 0;
+            for item in &batch {
+                if !item.should_send_to(
+                    &node_conn.public_key,
+                    node_conn.remote_gossip_filter.as_ref(),
+                    supports_gossip_queries,
+                ) {
+                    continue;
+                }
+                if let Err(e) = node_conn.encrypt_and_send_bytes(&item.bytes).await {
+                    println!("Failed to relay gossip: {:?}", e);
+                    break;
                 }
+                sent += 1;
+            }
+            println!(
+                "Relayed {} gossip messages to {}",
+                sent,
+                hex::encode(node_conn.public_key)
+            );
+        }
+    }
+
+    fn export_propagation(&mut self) {
+        if !self.propagation.export_due() {
+            return;
+        }
+        self.propagation.prune(get_current_timestamp_millis());
+        match self.propagation.export(Path::new(PROPAGATION_EXPORT_FILE)) {
+            Ok(_) => println!(
+                "Wrote gossip propagation for {} peers to {}",
+                self.propagation.peers.len(),
+                PROPAGATION_EXPORT_FILE
+            ),
+            Err(e) => println!("Failed to write gossip propagation: {:?}", e),
+        }
+    }
+
+    async fn run_reconciliation(&mut self) {
+        if !self.reconciler.due() {
+            return;
+        }
+        let (first_timestamp, timestamp_range) = Reconciler::window();
+        let peers: Vec<&NodeConnection> = self.node_connections.values().collect();
+        for (i, a) in peers.iter().enumerate() {
+            for b in &peers[i + 1..] {
+                let report = simulate(
+                    &a.gossip_view,
+                    &b.gossip_view,
+                    first_timestamp,
+                    timestamp_range,
+                    RECONCILE_CAPACITY,
+                );
+                println!(
+                    "Reconciling {} with {}: {:?}",
+                    hex::encode(a.public_key),
+                    hex::encode(b.public_key),
+                    report
+                );
             }
-            for node_public_key in disconnects {
-                self.node_connections.remove(&node_public_key);
+        }
+        for node_conn in self.node_connections.values_mut() {
+            node_conn.gossip_view.prune(first_timestamp);
+        }
+        if !RECONCILE_LIVE {
+            return;
+        }
+        let sketch =
+            MessageContainer::GossipSketch(self.reconciler.sketch_message(&self.chain_hash));
+        for node_conn in self.node_connections.values_mut() {
+            if let Err(e) = node_conn.encrypt_and_send_message(&sketch).await {
+                println!("Failed to send gossip sketch: {:?}", e);
+            }
+        }
+    }
+
+    fn gossip_filter_message(
+        chain_hash: &ChainHashElement,
+        gossip_filter: &GossipFilterPolicy,
+    ) -> MessageContainer {
+        let (first_timestamp, timestamp_range) = gossip_filter.timestamps();
+        MessageContainer::GossipTimestampFilter(GossipTimestampFilterMessage::new(
+            chain_hash.clone(),
+            first_timestamp,
+            timestamp_range,
+        ))
+    }
+
+    // switch policy and send the new filter to every peer that understands it
+    pub async fn set_gossip_filter(&mut self, gossip_filter: GossipFilterPolicy) {
+        self.gossip_filter = gossip_filter;
+        let filter = Self::gossip_filter_message(&self.chain_hash, &self.gossip_filter);
+        for node_conn in self.node_connections.values_mut() {
+            if !node_conn
+                .negotiated_features
+                .contains(&Features::GossipQueries)
+            {
+                continue;
             }
-            for (message, node_public_key) in inbounds {
-                self.handle_inbound_message(message, node_public_key)
-                    .await
-                    .unwrap();
+            if let Err(e) = node_conn.encrypt_and_send_message(&filter).await {
+                println!("Failed to send gossip filter: {:?}", e);
             }
-            tokio::time::sleep(std::time::Duration::from_millis(500)).await;
         }
     }
 
     pub async fn open_node_connection(&mut self, node: &Node) -> Result<(), MessageHandlerError> {
-        let mut node_connection = match NodeConnection::new(node, self.secret_key).await {
-            Ok(conn) => conn,
+        *self.connection_attempts.entry(node.public_key).or_insert(0) += 1;
+        let init = self.init_message(node);
+        match establish(
+            node,
+            self.secret_key,
+            self.taps.clone(),
+            self.timeouts,
+            init,
+        )
+        .await
+        {
+            Ok(node_connection) => {
+                self.node_connections
+                    .insert(node.public_key, node_connection);
+                Ok(())
+            }
             Err(err) => {
-                println!("Failed to create node connection: {:?}", err);
-                return Err(MessageHandlerError::NodeConnectionError(err));
+                self.record_failed_connect(node.public_key, &err);
+                Err(err)
+            }
+        }
+    }
+
+    // the same as open_node_connection, without holding up the event loop
+    fn spawn_connect(&mut self, node: Node, reply: Option<oneshot::Sender<ApiResponse>>) {
+        *self.connection_attempts.entry(node.public_key).or_insert(0) += 1;
+        self.connecting.insert(node.public_key);
+        let init = self.init_message(&node);
+        let secret_key = self.secret_key;
+        let taps = self.taps.clone();
+        let timeouts = self.timeouts;
+        let connects = self.connects.clone();
+        tokio::spawn(async move {
+            let result = establish(&node, secret_key, taps, timeouts, init).await;
+            let _ = connects.send(ConnectAttempt {
+                node,
+                result,
+                reply,
+            });
+        });
+    }
+
+    fn connect_on_request(&mut self, node: Node, reply: oneshot::Sender<ApiResponse>) {
+        if self.node_connections.contains_key(&node.public_key)
+            || self.connecting.contains(&node.public_key)
+        {
+            let _ = reply.send(Err(ApiError::BadRequest(format!(
+                "Already connected to {}",
+                hex::encode(node.public_key)
+            ))));
+            return;
+        }
+        println!("Connecting to {} on request", node.display_str());
+        self.spawn_connect(node, Some(reply));
+    }
+
+    fn finish_connect(&mut self, attempt: ConnectAttempt) {
+        let ConnectAttempt {
+            node,
+            result,
+            reply,
+        } = attempt;
+        self.connecting.remove(&node.public_key);
+        let response = match result {
+            Ok(node_connection) => {
+                let report = node_connection.report();
+                self.node_connections
+                    .insert(node.public_key, node_connection);
+                Ok(json!(report))
             }
-        };
-        match node_connection.handshake().await {
-            Ok(_) => (),
             Err(err) => {
-                println!("Failed to handshake: {:?}", err);
-                return Err(MessageHandlerError::NodeHandshakeError(err));
+                self.record_failed_connect(node.public_key, &err);
+                Err(ApiError::Failed(format!("{:?}", err)))
             }
         };
-        println!("Connected to node: {}", node.address());
-        let init = b"\x00\x10\x00\x00\x00\x01\xaa";
-        let (im, _) = InitMessage::from_bytes(init).unwrap();
-        let wrapped = MessageContainer::Init(im);
-        node_connection
-            .encrypt_and_send_message(&wrapped)
-            .await
-            .unwrap();
-        self.node_connections
-            .insert(node.public_key.clone(), node_connection);
-        Ok(())
+        if let Some(reply) = reply {
+            let _ = reply.send(response);
+        }
+    }
+
+    fn init_message(&self, node: &Node) -> MessageContainer {
+        let mut init = InitMessageBuilder::new(self.local_features.clone())
+            .networks(vec![self.chain_hash.clone()]);
+        if let Ok(remote_addr) = node.address().parse() {
+            init = init.remote_addr(remote_addr);
+        }
+        MessageContainer::Init(init.build())
+    }
+
+    fn record_failed_connect(&mut self, node_public_key: [u8; 33], err: &MessageHandlerError) {
+        match err {
+            MessageHandlerError::NodeConnectionError(err)
+            | MessageHandlerError::NodeHandshakeError(err) => {
+                self.record_timeout(node_public_key, err)
+            }
+            _ => {}
+        }
+    }
+
+    fn record_timeout(&mut self, node_public_key: [u8; 33], err: &NodeConnectionError) {
+        let stage = match err.timeout_stage() {
+            Some(stage) => stage,
+            None => return,
+        };
+        let count = self.timeouts_by_stage.entry(stage).or_insert(0);
+        *count += 1;
+        println!(
+            "Timed out waiting for {} from {} ({} {} timeouts so far)",
+            stage,
+            hex::encode(node_public_key),
+            count,
+            stage
+        );
+    }
+
+    // runs gossip through the graph, returns the timestamp to relay it with if it was new
+    fn handle_gossip(&mut self, message: &MessageContainer, now: u64) -> Option<u32> {
+        let (result, timestamp) = match message {
+            MessageContainer::NodeAnnouncement(announcement) => {
+                let result = self.graph.handle_node_announcement_at(announcement, now);
+                match &result {
+                    Ok(_) => {
+                        println!("Found new node: {:?}", announcement.node_id.clone());
+                        println!("Known nodes: {}", self.graph.nodes.len());
+                    }
+                    Err(e) => println!("Not storing node announcement: {:?}", e),
+                }
+                (result, announcement.timestamp)
+            }
+            MessageContainer::ChannelAnnouncement(msg) => {
+                let result = self.graph.handle_channel_announcement_at(msg, now);
+                match &result {
+                    Ok(_) => {
+                        println!("Found new channel: {:?}", msg.short_channel_id.clone());
+                        println!("Known channels: {}", self.graph.channels.len());
+                    }
+                    Err(e) => println!("Not storing channel announcement: {:?}", e),
+                }
+                // announcements carry no timestamp, so filters see when we got it
+                (result, now as u32)
+            }
+            MessageContainer::ChannelUpdate(msg) => {
+                let result = self.graph.handle_channel_update_at(msg, now);
+                if let Err(e) = &result {
+                    println!("Not storing channel update: {:?}", e);
+                }
+                (result, msg.timestamp)
+            }
+            _ => return None,
+        };
+        result.ok()?;
+        self.reconciler.record(message.to_bytes(), timestamp);
+        Some(timestamp)
     }
 
     pub async fn handle_inbound_message(
@@ -110,36 +981,217 @@ impl MiniPeer {
         wrapped: MessageContainer,
         node_public_key: [u8; 33],
     ) -> Result<(), MessageHandlerError> {
-        println!("Received message: {:?}", wrapped);
-        let node_conn = self.node_connections.get_mut(&node_public_key).unwrap();
+        let received_at = get_current_timestamp_millis();
+        if self.json_output {
+            println!("{}", message_json(received_at, &node_public_key, &wrapped));
+        } else {
+            println!("Received message: {:?}", wrapped);
+        }
+        if self.recent_messages.len() == MESSAGE_TAIL_LENGTH {
+            self.recent_messages.pop_front();
+        }
+        self.recent_messages
+            .push_back((received_at, node_public_key, wrapped.to_bytes()));
+        if let Some(feed) = &self.feed {
+            // nobody to encode it for otherwise
+            if feed.receiver_count() > 0 {
+                let _ = feed.send(FeedMessage::new(received_at, node_public_key, &wrapped));
+            }
+        }
+        if let Some(report) = wrapped.feature_report() {
+            if !report.is_valid() {
+                println!(
+                    "Invalid features from {}: {:?}",
+                    hex::encode(node_public_key),
+                    report
+                );
+            }
+        }
+        if let Some(chain_hash) = wrapped.chain_hash() {
+            if *chain_hash != self.chain_hash {
+                self.wrong_chain_messages += 1;
+                println!(
+                    "Rejecting message for chain {:?} ({} rejected so far)",
+                    chain_hash, self.wrong_chain_messages
+                );
+                return Ok(());
+            }
+        }
+        let signature_failures = self.graph.signature_failures;
+        #[cfg(feature = "sqlite")]
+        let spam_dropped = self.graph.spam.dropped;
+        let accepted = self.handle_gossip(&wrapped, get_current_timestamp());
+        if let Some(timestamp) = accepted {
+            if RELAY_GOSSIP {
+                self.relay
+                    .queue(wrapped.to_bytes(), node_public_key, timestamp);
+            }
+        }
+        // rejected gossip is kept too, marked so it isn't reloaded, except spam which is only counted
+        #[cfg(feature = "sqlite")]
+        if let Some(store) = self
+            .store
+            .as_mut()
+            .filter(|_| self.graph.spam.dropped == spam_dropped)
+        {
+            store.record(
+                &wrapped,
+                &node_public_key,
+                get_current_timestamp(),
+                accepted.is_some(),
+            );
+        }
+        let node_conn = self
+            .node_connections
+            .iter_mut()
+            .find(|(key, _)| *key == node_public_key)
+            .map(|(_, node_conn)| node_conn)
+            .unwrap();
+        node_conn.signature_failures += self.graph.signature_failures - signature_failures;
+        if let Some(timestamp) = gossip_timestamp(&wrapped) {
+            let bytes = wrapped.to_bytes();
+            node_conn.gossip_view.add(&bytes, timestamp);
+            self.propagation
+                .record(&bytes, node_public_key, get_current_timestamp_millis());
+        }
         match wrapped {
+            MessageContainer::Init(init) => {
+                let report = init.feature_report();
+                if !report.unknown_required_bits.is_empty() {
+                    return Err(MessageHandlerError::UnsupportedFeatures(
+                        report.unknown_required_bits,
+                    ));
+                }
+                let remote_features = init.features();
+                node_conn.negotiated_features = self.local_features.negotiate(&remote_features);
+                println!("Negotiated features: {:?}", node_conn.negotiated_features);
+                println!(
+                    "Peer networks: {:?}, sees us at: {:?}",
+                    init.networks(),
+                    init.remote_addr()
+                );
+                let networks = init.networks();
+                if !networks.is_empty() && !networks.contains(&self.chain_hash) {
+                    return Err(MessageHandlerError::NoCommonNetwork);
+                }
+                if node_conn
+                    .negotiated_features
+                    .contains(&Features::GossipQueries)
+                {
+                    let filter = Self::gossip_filter_message(&self.chain_hash, &self.gossip_filter);
+                    match node_conn.encrypt_and_send_message(&filter).await {
+                        Ok(_) => (),
+                        Err(e) => return Err(MessageHandlerError::NodeConnectionError(e)),
+                    };
+                    if self.query_channel_range {
+                        // every block, so the replies cover all the channels the peer knows of
+                        let query = MessageContainer::QueryChannelRange(
+                            QueryChannelRangeMessage::new(self.chain_hash.clone(), 0, u32::MAX),
+                        );
+                        match node_conn.encrypt_and_send_message(&query).await {
+                            Ok(_) => (),
+                            Err(e) => return Err(MessageHandlerError::NodeConnectionError(e)),
+                        };
+                    }
+                }
+                // hand back whatever the peer stored with us during a previous connection
+                if let Some(blob) = self.peer_storage.load(&node_public_key) {
+                    let retrieval =
+                        MessageContainer::PeerStorageRetrieval(PeerStorageRetrievalMessage {
+                            blob,
+                        });
+                    match node_conn.encrypt_and_send_message(&retrieval).await {
+                        Ok(_) => (),
+                        Err(e) => return Err(MessageHandlerError::NodeConnectionError(e)),
+                    };
+                }
+            }
+            MessageContainer::PeerStorage(msg) => {
+                if PROVIDE_STORAGE {
+                    match self.peer_storage.store(&node_public_key, &msg.blob) {
+                        Ok(_) => println!(
+                            "Stored {} byte blob for peer {}",
+                            msg.blob.len(),
+                            hex::encode(node_public_key)
+                        ),
+                        Err(e) => println!("Failed to store peer blob: {:?}", e),
+                    }
+                } else {
+                    println!("Ignoring peer storage because PROVIDE_STORAGE=false.");
+                }
+                println!(
+                    "Peer storage blobs received: {} ({} bytes)",
+                    self.peer_storage.blobs_received, self.peer_storage.bytes_received
+                );
+            }
+            MessageContainer::PeerStorageRetrieval(msg) => {
+                println!(
+                    "Peer {} returned a {} byte blob we never stored",
+                    hex::encode(node_public_key),
+                    msg.blob.len()
+                );
+            }
             MessageContainer::Ping(ping) => {
+                if !ping.expects_pong() {
+                    println!("Not answering ping for {} bytes", ping.num_pong_bytes);
+                    return Ok(());
+                }
                 let pong = MessageContainer::Pong(PongMessage::from_ping(ping));
                 match node_conn.encrypt_and_send_message(&pong).await {
                     Ok(_) => (),
                     Err(e) => return Err(MessageHandlerError::NodeConnectionError(e)),
                 };
             }
+            MessageContainer::Pong(pong) => {
+                match node_conn
+                    .pings
+                    .pong(pong.num_bytes(), node_conn.received_at())
+                {
+                    PongResult::Answered(rtt) => {
+                        let stats = node_conn.pings.rtt();
+                        println!(
+                            "Pong from {} after {:?} (min {:?}, mean {:?}, max {:?} over {})",
+                            hex::encode(node_public_key),
+                            rtt,
+                            stats.min,
+                            stats.mean,
+                            stats.max,
+                            stats.samples
+                        );
+                    }
+                    PongResult::Late(rtt) => println!(
+                        "Late pong from {} after {:?} ({} missed)",
+                        hex::encode(node_public_key),
+                        rtt,
+                        node_conn.pings.missed()
+                    ),
+                    PongResult::WrongLength { expected, received } => println!(
+                        "Pong from {} has {} bytes, we asked for {} ({} missed)",
+                        hex::encode(node_public_key),
+                        received,
+                        expected,
+                        node_conn.pings.missed()
+                    ),
+                    PongResult::Unsolicited => println!(
+                        "Unsolicited pong from {} ({} so far)",
+                        hex::encode(node_public_key),
+                        node_conn.pings.unsolicited()
+                    ),
+                }
+            }
             MessageContainer::NodeAnnouncement(announcement) => {
                 if !self
                     .node_connections
                     .contains_key(&announcement.node_id.value)
+                    && !self.connecting.contains(&announcement.node_id.value)
                 {
-                    if !self.known_nodes.contains_key(&announcement.node_id) {
-                        self.known_nodes
-                            .insert(announcement.node_id.clone(), announcement.clone());
-                        println!("Found new node: {:?}", announcement.node_id.clone());
-                        println!("Known nodes: {}", self.known_nodes.len())
-                    }
                     match announcement.as_node() {
                         Some(node) => {
                             println!("Found new node: {}", node.address());
-                            if DO_CONNECT_TO_NEW_NODES {
-                                self.open_node_connection(&node).await.unwrap();
+                            if self.connect_to_new_nodes {
+                                self.spawn_connect(node, None);
                             } else {
-                                println!(
-                                   "Not connecting to new node because DO_CONNECT_TO_NEW_NODES=false."
-                               );
+                                println!("Not connecting to new node because auto-connect is off.");
                             }
                         }
                         None => {
@@ -150,30 +1202,115 @@ impl MiniPeer {
                     println!("Already connected to node.");
                 }
             }
-            MessageContainer::ChannelAnnouncement(msg) => {
-                if !self
-                    .known_channels
-                    .contains_key(&msg.short_channel_id.clone())
-                {
-                    self.known_channels
-                        .insert(msg.short_channel_id.clone(), msg.clone());
-                    println!("Found new channel: {:?}", msg.short_channel_id.clone());
-                    println!("Known channels: {}", self.known_channels.len())
-                } else {
+            MessageContainer::GossipTimestampFilter(gtf) => {
+                // this only limits what we send them, our own filter went out after init
+                node_conn.remote_gossip_filter = Some(gtf);
+            }
+            MessageContainer::QueryChannelRange(_) | MessageContainer::QueryShortChannelIds(_)
+                if !node_conn
+                    .negotiated_features
+                    .contains(&Features::GossipQueries) =>
+            {
+                println!(
+                    "Ignoring gossip query from {}, gossip_queries wasn't negotiated",
+                    hex::encode(node_public_key)
+                );
+            }
+            MessageContainer::QueryChannelRange(query) => {
+                for reply in reply_channel_range(&self.graph, &query) {
+                    let reply = MessageContainer::ReplyChannelRange(reply);
+                    match node_conn.encrypt_and_send_message(&reply).await {
+                        Ok(_) => (),
+                        Err(e) => return Err(MessageHandlerError::NodeConnectionError(e)),
+                    };
                 }
             }
-            MessageContainer::GossipTimestampFilter(gtf) => {
-                let mut our_filter = gtf.clone();
-                // we ask for all the gossip
-                our_filter.first_timestamp = 0;
-                let response = MessageContainer::GossipTimestampFilter(our_filter);
-                match node_conn.encrypt_and_send_message(&response).await {
-                    Ok(_) => (),
-                    Err(e) => return Err(MessageHandlerError::NodeConnectionError(e)),
+            MessageContainer::GossipSketch(_) if !RECONCILE_LIVE => {
+                println!(
+                    "Ignoring gossip sketch from {}, RECONCILE_LIVE is off",
+                    hex::encode(node_public_key)
+                );
+            }
+            MessageContainer::GossipSketch(msg) => {
+                let (to_send, missing) = match self.reconciler.handle_sketch(&msg) {
+                    Some(difference) => difference,
+                    None => {
+                        println!(
+                            "Could not reconcile gossip sketch from {} ({} undecodable, {} oversized so far)",
+                            hex::encode(node_public_key),
+                            self.reconciler.decode_failures,
+                            self.reconciler.oversized_sketches
+                        );
+                        return Ok(());
+                    }
+                };
+                println!(
+                    "Reconciled with {}: sending {} messages, missing {}",
+                    hex::encode(node_public_key),
+                    to_send.len(),
+                    missing
+                );
+                for bytes in to_send {
+                    match node_conn.encrypt_and_send_bytes(&bytes).await {
+                        Ok(_) => (),
+                        Err(e) => return Err(MessageHandlerError::NodeConnectionError(e)),
+                    };
+                }
+            }
+            MessageContainer::QueryShortChannelIds(query) => {
+                let replies = match reply_short_channel_ids(&self.graph, &query) {
+                    Ok(replies) => replies,
+                    Err(e) => {
+                        println!("Could not decode short channel ids: {:?}", e);
+                        return Ok(());
+                    }
                 };
+                for reply in replies {
+                    match node_conn.encrypt_and_send_message(&reply).await {
+                        Ok(_) => (),
+                        Err(e) => return Err(MessageHandlerError::NodeConnectionError(e)),
+                    };
+                }
             }
             _ => {}
         }
         Ok(())
     }
 }
+
+// the connection, handshake and init, everything a new connection needs before the event loop
+async fn establish(
+    node: &Node,
+    secret_key: SecretKey,
+    taps: Taps,
+    timeouts: Timeouts,
+    init: MessageContainer,
+) -> Result<NodeConnection, MessageHandlerError> {
+    let mut node_connection = NodeConnection::new(node, secret_key, taps, timeouts)
+        .await
+        .map_err(|err| {
+            println!("Failed to create node connection: {:?}", err);
+            MessageHandlerError::NodeConnectionError(err)
+        })?;
+    node_connection.handshake().await.map_err(|err| {
+        println!("Failed to handshake: {:?}", err);
+        MessageHandlerError::NodeHandshakeError(err)
+    })?;
+    println!("Connected to node: {}", node.address());
+    node_connection
+        .encrypt_and_send_message(&init)
+        .await
+        .map_err(|err| {
+            println!("Failed to send init: {:?}", err);
+            MessageHandlerError::NodeConnectionError(err)
+        })?;
+    Ok(node_connection)
+}
+
+// never resolves once there is no api to listen to
+async fn next_api_call(api: &mut Option<mpsc::Receiver<ApiCall>>) -> Option<ApiCall> {
+    match api {
+        Some(api) => api.recv().await,
+        None => std::future::pending().await,
+    }
+}
@@ -0,0 +1,107 @@
+use std::fs;
+use std::path::PathBuf;
+
+use crate::config::{PEER_STORAGE_MAX_BLOB_SIZE, PEER_STORAGE_QUOTA};
+
+#[allow(dead_code)]
+#[derive(Debug)]
+pub enum PeerStorageError {
+    BlobTooLarge(usize),
+    QuotaExceeded,
+    IOError(std::io::Error),
+}
+
+// stores the latest peer_storage blob of every peer as a file named after its public key
+pub struct PeerStorage {
+    dir: PathBuf,
+    max_blob_size: usize,
+    quota: u64,
+    pub blobs_received: u64,
+    pub bytes_received: u64,
+}
+
+impl PeerStorage {
+    pub fn new(dir: PathBuf) -> Self {
+        PeerStorage {
+            dir,
+            max_blob_size: PEER_STORAGE_MAX_BLOB_SIZE,
+            quota: PEER_STORAGE_QUOTA,
+            blobs_received: 0,
+            bytes_received: 0,
+        }
+    }
+
+    fn blob_path(&self, node_public_key: &[u8; 33]) -> PathBuf {
+        self.dir
+            .join(format!("{}.bin", hex::encode(node_public_key)))
+    }
+
+    fn used_bytes(&self) -> u64 {
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(_) => return 0,
+        };
+        entries
+            .filter_map(|entry| entry.ok())
+            .filter_map(|entry| entry.metadata().ok())
+            .map(|metadata| metadata.len())
+            .sum()
+    }
+
+    pub fn store(
+        &mut self,
+        node_public_key: &[u8; 33],
+        blob: &[u8],
+    ) -> Result<(), PeerStorageError> {
+        self.blobs_received += 1;
+        self.bytes_received += blob.len() as u64;
+        if blob.len() > self.max_blob_size {
+            return Err(PeerStorageError::BlobTooLarge(blob.len()));
+        }
+        let path = self.blob_path(node_public_key);
+        // the peer's previous blob is replaced, so it doesn't count against the quota
+        let previous = fs::metadata(&path).map(|m| m.len()).unwrap_or(0);
+        if self.used_bytes() - previous + blob.len() as u64 > self.quota {
+            return Err(PeerStorageError::QuotaExceeded);
+        }
+        fs::create_dir_all(&self.dir).map_err(PeerStorageError::IOError)?;
+        fs::write(&path, blob).map_err(PeerStorageError::IOError)?;
+        Ok(())
+    }
+
+    pub fn load(&self, node_public_key: &[u8; 33]) -> Option<Vec<u8>> {
+        fs::read(self.blob_path(node_public_key)).ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_store_and_load_respects_quota() {
+        let dir = std::env::temp_dir().join("lmprs_peer_storage_test");
+        let _ = fs::remove_dir_all(&dir);
+        let mut storage = PeerStorage::new(dir.clone());
+        storage.quota = 100;
+        let alice = [2u8; 33];
+        let bob = [3u8; 33];
+
+        storage.store(&alice, &[1; 60]).unwrap();
+        // replacing our own blob only counts the difference
+        storage.store(&alice, &[2; 80]).unwrap();
+        assert_eq!(storage.load(&alice).unwrap(), vec![2; 80]);
+        assert!(matches!(
+            storage.store(&bob, &[3; 30]),
+            Err(PeerStorageError::QuotaExceeded)
+        ));
+        assert!(storage.load(&bob).is_none());
+        assert!(matches!(
+            storage.store(&bob, &[0; PEER_STORAGE_MAX_BLOB_SIZE + 1]),
+            Err(PeerStorageError::BlobTooLarge(_))
+        ));
+        assert_eq!(storage.blobs_received, 4);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
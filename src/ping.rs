@@ -0,0 +1,183 @@
+use std::time::{Duration, Instant};
+
+use crate::propagation::Histogram;
+
+// upper bounds of the round trip time buckets in milliseconds
+const RTT_BUCKETS_MS: &[u64] = &[5, 10, 25, 50, 100, 250, 500, 1_000, 2_500, 5_000];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PongResult {
+    Answered(Duration),
+    // the answer to the ping before, which was already counted as missed
+    Late(Duration),
+    // the peer answered with the wrong number of bytes, counts as missed
+    WrongLength { expected: u16, received: usize },
+    // a pong we never asked for
+    Unsolicited,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RttStats {
+    pub samples: u64,
+    pub last: Duration,
+    pub min: Duration,
+    pub max: Duration,
+    pub mean: Duration,
+}
+
+// the ping we're waiting on and the round trip times of the ones that came back
+#[derive(Debug)]
+pub struct PingTracker {
+    // (num_pong_bytes, sent at)
+    outstanding: Option<(u16, Instant)>,
+    // the ping the outstanding one replaced, so its pong isn't taken for a wrong answer
+    superseded: Option<(u16, Instant)>,
+    // pings in a row that got no pong of the right length
+    missed: u32,
+    unsolicited: u64,
+    rtt: RttStats,
+    rtt_total: Duration,
+    rtt_histogram: Histogram,
+}
+
+impl PingTracker {
+    pub fn new() -> Self {
+        PingTracker {
+            outstanding: None,
+            superseded: None,
+            missed: 0,
+            unsolicited: 0,
+            rtt: RttStats::default(),
+            rtt_total: Duration::ZERO,
+            rtt_histogram: Histogram::with_buckets(RTT_BUCKETS_MS),
+        }
+    }
+
+    // a ping still outstanding when the next one goes out was missed
+    pub fn sent(&mut self, num_pong_bytes: u16, now: Instant) {
+        if self.outstanding.is_some() {
+            self.missed += 1;
+        }
+        self.superseded = self.outstanding.replace((num_pong_bytes, now));
+    }
+
+    pub fn pong(&mut self, num_bytes: usize, now: Instant) -> PongResult {
+        let (expected, sent) = match self.outstanding {
+            Some(outstanding) => outstanding,
+            None => {
+                self.unsolicited += 1;
+                return PongResult::Unsolicited;
+            }
+        };
+        if num_bytes == expected as usize {
+            self.outstanding = None;
+            self.superseded = None;
+            self.missed = 0;
+            return PongResult::Answered(self.record_rtt(now.saturating_duration_since(sent)));
+        }
+        // still waiting for the outstanding one, this one was missed already
+        if let Some((expected, sent)) = self.superseded {
+            if num_bytes == expected as usize {
+                self.superseded = None;
+                return PongResult::Late(self.record_rtt(now.saturating_duration_since(sent)));
+            }
+        }
+        self.outstanding = None;
+        self.missed += 1;
+        PongResult::WrongLength {
+            expected,
+            received: num_bytes,
+        }
+    }
+
+    fn record_rtt(&mut self, rtt: Duration) -> Duration {
+        self.rtt_total += rtt;
+        self.rtt_histogram.record(rtt.as_millis() as u64);
+        self.rtt.samples += 1;
+        self.rtt.last = rtt;
+        self.rtt.min = if self.rtt.samples == 1 {
+            rtt
+        } else {
+            self.rtt.min.min(rtt)
+        };
+        self.rtt.max = self.rtt.max.max(rtt);
+        self.rtt.mean = self.rtt_total / self.rtt.samples as u32;
+        rtt
+    }
+
+    // true when a ping has gone unanswered for longer than timeout
+    pub fn overdue(&self, timeout: Duration, now: Instant) -> bool {
+        match self.outstanding {
+            Some((_, sent)) => now.saturating_duration_since(sent) > timeout,
+            None => false,
+        }
+    }
+
+    pub fn missed(&self) -> u32 {
+        self.missed
+    }
+
+    pub fn unsolicited(&self) -> u64 {
+        self.unsolicited
+    }
+
+    pub fn rtt(&self) -> RttStats {
+        self.rtt
+    }
+
+    // with the sum of all round trip times, for prometheus
+    pub fn rtt_histogram(&self) -> (&Histogram, Duration) {
+        (&self.rtt_histogram, self.rtt_total)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ping_tracker() {
+        let start = Instant::now();
+        let mut pings = PingTracker::new();
+        assert_eq!(pings.pong(4, start), PongResult::Unsolicited);
+
+        pings.sent(4, start);
+        assert!(!pings.overdue(Duration::from_secs(30), start));
+        assert!(pings.overdue(Duration::from_secs(30), start + Duration::from_secs(31)));
+        pings.sent(8, start + Duration::from_secs(31));
+        assert_eq!(pings.missed(), 1);
+        // the first ping's answer, late but no wrong answer to the second
+        assert_eq!(
+            pings.pong(4, start + Duration::from_secs(32)),
+            PongResult::Late(Duration::from_secs(32))
+        );
+        assert_eq!(pings.missed(), 1);
+        assert_eq!(
+            pings.pong(3, start + Duration::from_secs(33)),
+            PongResult::WrongLength {
+                expected: 8,
+                received: 3
+            }
+        );
+        assert_eq!(pings.missed(), 2);
+        assert_eq!(
+            pings.pong(8, start + Duration::from_secs(34)),
+            PongResult::Unsolicited
+        );
+
+        pings.sent(2, start + Duration::from_secs(40));
+        assert_eq!(
+            pings.pong(2, start + Duration::from_millis(40_100)),
+            PongResult::Answered(Duration::from_millis(100))
+        );
+        pings.sent(2, start + Duration::from_secs(50));
+        pings.pong(2, start + Duration::from_millis(50_300));
+        assert_eq!(pings.missed(), 0);
+        let rtt = pings.rtt();
+        assert_eq!(rtt.samples, 3);
+        assert_eq!(rtt.min, Duration::from_millis(100));
+        assert_eq!(rtt.max, Duration::from_secs(32));
+        assert_eq!(rtt.mean, Duration::from_millis(32_400) / 3);
+        assert_eq!(rtt.last, Duration::from_millis(300));
+    }
+}
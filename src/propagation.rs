@@ -0,0 +1,222 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+use bitcoin::hashes::{sha256, Hash};
+
+use crate::config::{PROPAGATION_EXPORT_INTERVAL, PROPAGATION_WINDOW};
+use crate::util::get_current_timestamp;
+
+// upper bounds of the histogram buckets in milliseconds, the last bucket takes the rest
+const BUCKETS_MS: &[u64] = &[
+    100, 250, 500, 1_000, 2_500, 5_000, 10_000, 30_000, 60_000, 300_000,
+];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Histogram {
+    bounds: &'static [u64],
+    counts: Vec<u64>,
+}
+
+impl Histogram {
+    pub fn new() -> Self {
+        Histogram::with_buckets(BUCKETS_MS)
+    }
+
+    pub fn with_buckets(bounds: &'static [u64]) -> Self {
+        Histogram {
+            bounds,
+            counts: vec![0; bounds.len() + 1],
+        }
+    }
+
+    pub fn record(&mut self, millis: u64) {
+        let bucket = self
+            .bounds
+            .iter()
+            .position(|bound| millis <= *bound)
+            .unwrap_or(self.bounds.len());
+        self.counts[bucket] += 1;
+    }
+
+    // (upper bound, count) per bucket, None being the overflow bucket
+    pub fn buckets(&self) -> Vec<(Option<u64>, u64)> {
+        self.counts
+            .iter()
+            .enumerate()
+            .map(|(i, count)| (self.bounds.get(i).copied(), *count))
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct PeerLag {
+    // messages this peer sent us
+    pub arrivals: u64,
+    // of those, the ones no other peer had sent us before
+    pub first: u64,
+    pub total_lag_ms: u64,
+    // how long after the first peer this one sent each message
+    pub lag: Histogram,
+}
+
+impl PeerLag {
+    pub fn mean_lag_ms(&self) -> u64 {
+        match self.arrivals {
+            0 => 0,
+            n => self.total_lag_ms / n,
+        }
+    }
+}
+
+struct Arrivals {
+    first_seen_ms: u64,
+    last_seen_ms: u64,
+    peers: Vec<[u8; 33]>,
+}
+
+// tracks when each peer first sent us each gossip message
+pub struct PropagationTracker {
+    messages: HashMap<[u8; 32], Arrivals>,
+    pub peers: HashMap<[u8; 33], PeerLag>,
+    // time between the first and the last peer sending a message
+    pub spread: Histogram,
+    last_export: u64,
+}
+
+impl PropagationTracker {
+    pub fn new() -> Self {
+        PropagationTracker {
+            messages: HashMap::new(),
+            peers: HashMap::new(),
+            spread: Histogram::new(),
+            last_export: get_current_timestamp(),
+        }
+    }
+
+    pub fn record(&mut self, bytes: &[u8], node_public_key: [u8; 33], now_ms: u64) {
+        let hash = sha256::Hash::hash(bytes).to_byte_array();
+        let arrivals = self.messages.entry(hash).or_insert(Arrivals {
+            first_seen_ms: now_ms,
+            last_seen_ms: now_ms,
+            peers: Vec::new(),
+        });
+        if arrivals.peers.contains(&node_public_key) {
+            return;
+        }
+        // wall clock time, which can step back
+        let lag_ms = now_ms.saturating_sub(arrivals.first_seen_ms);
+        let peer = self.peers.entry(node_public_key).or_insert(PeerLag {
+            arrivals: 0,
+            first: 0,
+            total_lag_ms: 0,
+            lag: Histogram::new(),
+        });
+        peer.arrivals += 1;
+        if arrivals.peers.is_empty() {
+            peer.first += 1;
+        }
+        peer.total_lag_ms += lag_ms;
+        peer.lag.record(lag_ms);
+        arrivals.peers.push(node_public_key);
+        arrivals.last_seen_ms = arrivals.last_seen_ms.max(now_ms);
+    }
+
+    // messages older than the window won't arrive from anyone new, so their spread is final
+    pub fn prune(&mut self, now_ms: u64) {
+        let cutoff = now_ms.saturating_sub(PROPAGATION_WINDOW * 1000);
+        let spread = &mut self.spread;
+        self.messages.retain(|_, arrivals| {
+            if arrivals.first_seen_ms >= cutoff {
+                return true;
+            }
+            // a message only one peer sent us says nothing about propagation
+            if arrivals.peers.len() > 1 {
+                spread.record(arrivals.last_seen_ms.saturating_sub(arrivals.first_seen_ms));
+            }
+            false
+        });
+    }
+
+    // whether to write the histograms out, at most once per export interval
+    pub fn export_due(&mut self) -> bool {
+        let now = get_current_timestamp();
+        if self.last_export + PROPAGATION_EXPORT_INTERVAL > now {
+            return false;
+        }
+        self.last_export = now;
+        true
+    }
+
+    // csv rows of metric,peer,le_ms,value
+    pub fn to_csv(&self) -> String {
+        let mut csv = String::from("metric,peer,le_ms,value\n");
+        for (bound, count) in self.spread.buckets() {
+            csv += &format!("spread,,{},{}\n", bucket_label(bound), count);
+        }
+        let mut peers: Vec<_> = self.peers.iter().collect();
+        peers.sort_by_key(|(_, peer)| peer.mean_lag_ms());
+        for (node_public_key, peer) in peers {
+            let node = hex::encode(node_public_key);
+            csv += &format!("arrivals,{},,{}\n", node, peer.arrivals);
+            csv += &format!("first,{},,{}\n", node, peer.first);
+            csv += &format!("mean_lag_ms,{},,{}\n", node, peer.mean_lag_ms());
+            for (bound, count) in peer.lag.buckets() {
+                csv += &format!("lag,{},{},{}\n", node, bucket_label(bound), count);
+            }
+        }
+        csv
+    }
+
+    pub fn export(&self, path: &Path) -> std::io::Result<()> {
+        fs::write(path, self.to_csv())
+    }
+}
+
+fn bucket_label(bound: Option<u64>) -> String {
+    match bound {
+        Some(bound) => bound.to_string(),
+        None => "inf".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tracks_spread_and_lag() {
+        let fast = [2u8; 33];
+        let slow = [3u8; 33];
+        let mut tracker = PropagationTracker::new();
+        tracker.record(&[1, 2, 3], fast, 1_000);
+        tracker.record(&[1, 2, 3], fast, 1_100);
+        tracker.record(&[1, 2, 3], slow, 4_000);
+        tracker.record(&[4, 5, 6], slow, 5_000);
+
+        assert_eq!(tracker.peers[&fast].arrivals, 1);
+        assert_eq!(tracker.peers[&fast].first, 1);
+        assert_eq!(tracker.peers[&fast].mean_lag_ms(), 0);
+        assert_eq!(tracker.peers[&slow].arrivals, 2);
+        assert_eq!(tracker.peers[&slow].first, 1);
+        assert_eq!(tracker.peers[&slow].mean_lag_ms(), 1_500);
+
+        // nothing is final until the window has passed
+        tracker.prune(5_000);
+        assert_eq!(tracker.spread, Histogram::new());
+        tracker.prune(5_001 + PROPAGATION_WINDOW * 1000);
+        let mut expected = Histogram::new();
+        expected.record(3_000);
+        assert_eq!(tracker.spread, expected);
+        assert!(tracker.messages.is_empty());
+
+        let csv = tracker.to_csv();
+        assert!(csv.contains("spread,,5000,1\n"));
+        assert!(csv.contains(&format!("lag,{},inf,0\n", hex::encode(slow))));
+
+        // a clock that stepped back counts as no lag
+        tracker.record(&[7, 8, 9], fast, 10_000);
+        tracker.record(&[7, 8, 9], slow, 9_000);
+        assert_eq!(tracker.peers[&slow].mean_lag_ms(), 1_000);
+    }
+}
@@ -0,0 +1,284 @@
+use std::collections::{HashMap, HashSet};
+
+use bitcoin::hashes::{sha256, Hash};
+
+use crate::config::{RECONCILE_CAPACITY, RECONCILE_INTERVAL, RECONCILE_WINDOW};
+use crate::message_decoder::MessageContainer;
+use crate::messages::GossipSketchMessage;
+use crate::minisketch::Sketch;
+use crate::serialization::ChainHashElement;
+use crate::util::get_current_timestamp;
+
+// 32 bits of the message hash, zero is reserved by the sketch so it becomes one
+pub fn short_id(bytes: &[u8]) -> u32 {
+    let hash = sha256::Hash::hash(bytes).to_byte_array();
+    u32::from_be_bytes([hash[0], hash[1], hash[2], hash[3]]).max(1)
+}
+
+// the timestamp a gossip message is windowed by, announcements have none so we use now
+pub fn gossip_timestamp(message: &MessageContainer) -> Option<u32> {
+    match message {
+        MessageContainer::ChannelAnnouncement(_) => Some(get_current_timestamp() as u32),
+        MessageContainer::NodeAnnouncement(msg) => Some(msg.timestamp),
+        MessageContainer::ChannelUpdate(msg) => Some(msg.timestamp),
+        _ => None,
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct GossipRecord {
+    pub timestamp: u32,
+    pub size: usize,
+}
+
+// the gossip one side has seen, by short id
+#[derive(Default)]
+pub struct GossipView {
+    records: HashMap<u32, GossipRecord>,
+}
+
+impl GossipView {
+    pub fn new() -> Self {
+        GossipView::default()
+    }
+
+    pub fn add(&mut self, bytes: &[u8], timestamp: u32) -> u32 {
+        let id = short_id(bytes);
+        self.records.insert(
+            id,
+            GossipRecord {
+                timestamp,
+                size: bytes.len(),
+            },
+        );
+        id
+    }
+
+    pub fn in_window(&self, first_timestamp: u32, timestamp_range: u32) -> HashMap<u32, usize> {
+        let first = first_timestamp as u64;
+        let end = first + timestamp_range as u64;
+        self.records
+            .iter()
+            .filter(|(_, record)| (first..end).contains(&(record.timestamp as u64)))
+            .map(|(id, record)| (*id, record.size))
+            .collect()
+    }
+
+    pub fn sketch(&self, first_timestamp: u32, timestamp_range: u32, capacity: usize) -> Sketch {
+        let mut sketch = Sketch::new(capacity);
+        for id in self.in_window(first_timestamp, timestamp_range).keys() {
+            sketch.add(*id);
+        }
+        sketch
+    }
+
+    // keeps memory bounded, nothing older than the window gets reconciled anyway
+    pub fn prune(&mut self, before: u32) {
+        self.records.retain(|_, record| record.timestamp >= before);
+    }
+}
+
+#[allow(dead_code)]
+#[derive(Debug)]
+pub struct ReconciliationReport {
+    pub set_sizes: (usize, usize),
+    pub difference: usize,
+    pub capacity: usize,
+    pub decoded: bool,
+    pub sketch_bytes: usize,
+    // the sketch plus the missing messages, or plus flooding everything when decoding failed
+    pub reconciliation_bytes: usize,
+    // both sides sending each other everything in the window
+    pub flooding_bytes: usize,
+}
+
+// what reconciling two recorded views over a window would have cost
+pub fn simulate(
+    a: &GossipView,
+    b: &GossipView,
+    first_timestamp: u32,
+    timestamp_range: u32,
+    capacity: usize,
+) -> ReconciliationReport {
+    let a_window = a.in_window(first_timestamp, timestamp_range);
+    let b_window = b.in_window(first_timestamp, timestamp_range);
+    let a_ids: HashSet<u32> = a_window.keys().copied().collect();
+    let b_ids: HashSet<u32> = b_window.keys().copied().collect();
+    let difference: HashSet<u32> = a_ids.symmetric_difference(&b_ids).copied().collect();
+
+    let mut sketch = a.sketch(first_timestamp, timestamp_range, capacity);
+    sketch.merge(&b.sketch(first_timestamp, timestamp_range, capacity));
+    let decoded = match sketch.decode() {
+        Some(ids) => ids.into_iter().collect::<HashSet<u32>>() == difference,
+        None => false,
+    };
+
+    let flooding_bytes = a_window.values().sum::<usize>() + b_window.values().sum::<usize>();
+    let sketch_bytes = sketch.to_bytes().len();
+    let difference_bytes: usize = difference
+        .iter()
+        .map(|id| a_window.get(id).or(b_window.get(id)).unwrap())
+        .sum();
+    ReconciliationReport {
+        set_sizes: (a_ids.len(), b_ids.len()),
+        difference: difference.len(),
+        capacity,
+        decoded,
+        sketch_bytes,
+        reconciliation_bytes: sketch_bytes
+            + if decoded {
+                difference_bytes
+            } else {
+                flooding_bytes
+            },
+        flooding_bytes,
+    }
+}
+
+pub struct Reconciler {
+    // the gossip we accepted, with the bytes so we can hand it to peers that lack it
+    pub local: GossipView,
+    messages: HashMap<u32, Vec<u8>>,
+    pub decode_failures: u64,
+    // sketches bigger than we would ever send, too slow to decode
+    pub oversized_sketches: u64,
+    last_run: u64,
+}
+
+impl Reconciler {
+    pub fn new() -> Self {
+        Reconciler {
+            local: GossipView::new(),
+            messages: HashMap::new(),
+            decode_failures: 0,
+            oversized_sketches: 0,
+            last_run: get_current_timestamp(),
+        }
+    }
+
+    pub fn record(&mut self, bytes: Vec<u8>, timestamp: u32) {
+        let id = self.local.add(&bytes, timestamp);
+        self.messages.insert(id, bytes);
+    }
+
+    // whether a reconciliation round is due, at most once per interval
+    pub fn due(&mut self) -> bool {
+        let now = get_current_timestamp();
+        if self.last_run + RECONCILE_INTERVAL > now {
+            return false;
+        }
+        self.last_run = now;
+        let before = (now as u32).saturating_sub(RECONCILE_WINDOW);
+        self.local.prune(before);
+        let local = &self.local;
+        self.messages.retain(|id, _| local.records.contains_key(id));
+        true
+    }
+
+    // (first_timestamp, timestamp_range) of the current window
+    pub fn window() -> (u32, u32) {
+        let now = get_current_timestamp() as u32;
+        (now.saturating_sub(RECONCILE_WINDOW), RECONCILE_WINDOW)
+    }
+
+    pub fn sketch_message(&self, chain_hash: &ChainHashElement) -> GossipSketchMessage {
+        let (first_timestamp, timestamp_range) = Self::window();
+        GossipSketchMessage {
+            chain_hash: chain_hash.clone(),
+            first_timestamp,
+            timestamp_range,
+            sketch: self
+                .local
+                .sketch(first_timestamp, timestamp_range, RECONCILE_CAPACITY)
+                .to_bytes(),
+        }
+    }
+
+    // the messages the peer is missing and how many we are missing, or None if the
+    // difference was too large to decode or the sketch too large to try
+    pub fn handle_sketch(&mut self, msg: &GossipSketchMessage) -> Option<(Vec<Vec<u8>>, usize)> {
+        let mut sketch = Sketch::from_bytes(&msg.sketch)?;
+        // decoding is quadratic in the capacity, which is the peer's to choose
+        if sketch.capacity() > RECONCILE_CAPACITY {
+            self.oversized_sketches += 1;
+            return None;
+        }
+        sketch.merge(&self.local.sketch(
+            msg.first_timestamp,
+            msg.timestamp_range,
+            sketch.capacity(),
+        ));
+        let ids = match sketch.decode() {
+            Some(ids) => ids,
+            None => {
+                self.decode_failures += 1;
+                return None;
+            }
+        };
+        let theirs_missing: Vec<Vec<u8>> = ids
+            .iter()
+            .filter_map(|id| self.messages.get(id).cloned())
+            .collect();
+        let ours_missing = ids.len() - theirs_missing.len();
+        Some((theirs_missing, ours_missing))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_simulate_reports_against_flooding() {
+        let mut a = GossipView::new();
+        let mut b = GossipView::new();
+        for i in 0..200u32 {
+            let message = i.to_be_bytes().repeat(50);
+            a.add(&message, 1000 + i);
+            // b misses every 20th message
+            if i % 20 != 0 {
+                b.add(&message, 1000 + i);
+            }
+        }
+        // outside the window, so it doesn't count
+        b.add(&[9; 200], 5000);
+
+        let report = simulate(&a, &b, 1000, 200, 16);
+        assert_eq!(report.set_sizes, (200, 190));
+        assert_eq!(report.difference, 10);
+        assert!(report.decoded);
+        assert_eq!(report.sketch_bytes, 16 * 4);
+        assert_eq!(report.reconciliation_bytes, 16 * 4 + 10 * 200);
+        assert_eq!(report.flooding_bytes, 390 * 200);
+
+        let report = simulate(&a, &b, 1000, 200, 4);
+        assert!(!report.decoded);
+        assert_eq!(report.reconciliation_bytes, 4 * 4 + 390 * 200);
+    }
+
+    #[test]
+    fn test_handle_sketch_finds_what_peer_lacks() {
+        let (first_timestamp, timestamp_range) = Reconciler::window();
+        let mut ours = Reconciler::new();
+        let mut theirs = Reconciler::new();
+        for i in 0..50u8 {
+            ours.record(vec![i; 100], first_timestamp + 5);
+            if i >= 3 {
+                theirs.record(vec![i; 100], first_timestamp + 5);
+            }
+        }
+        theirs.record(vec![99; 100], first_timestamp + timestamp_range - 1);
+
+        let chain_hash = ChainHashElement { value: [0; 32] };
+        let msg = theirs.sketch_message(&chain_hash);
+        let (to_send, missing) = ours.handle_sketch(&msg).unwrap();
+        assert_eq!(to_send.len(), 3);
+        assert!(to_send.contains(&vec![0; 100]));
+        assert_eq!(missing, 1);
+
+        let mut msg = theirs.sketch_message(&chain_hash);
+        msg.sketch = Sketch::new(RECONCILE_CAPACITY * 4).to_bytes();
+        assert!(ours.handle_sketch(&msg).is_none());
+        assert_eq!((ours.oversized_sketches, ours.decode_failures), (1, 0));
+    }
+}
@@ -0,0 +1,133 @@
+use std::collections::{HashSet, VecDeque};
+
+use bitcoin::hashes::{sha256, Hash};
+
+use crate::config::{RELAY_FLUSH_INTERVAL, RELAY_SEEN_CAPACITY};
+use crate::messages::GossipTimestampFilterMessage;
+use crate::util::get_current_timestamp;
+
+#[derive(Debug, Clone)]
+pub struct RelayItem {
+    pub bytes: Vec<u8>,
+    // the peer we got it from, which never gets it back
+    pub origin: [u8; 33],
+    pub timestamp: u32,
+}
+
+impl RelayItem {
+    pub fn should_send_to(
+        &self,
+        node_public_key: &[u8; 33],
+        filter: Option<&GossipTimestampFilterMessage>,
+        supports_gossip_queries: bool,
+    ) -> bool {
+        if self.origin == *node_public_key {
+            return false;
+        }
+        match filter {
+            Some(filter) => {
+                let first = filter.first_timestamp as u64;
+                let end = first + filter.timestamp_range as u64;
+                (first..end).contains(&(self.timestamp as u64))
+            }
+            // gossip_queries peers get nothing until they send a filter
+            None => !supports_gossip_queries,
+        }
+    }
+}
+
+pub struct GossipRelay {
+    queue: Vec<RelayItem>,
+    relayed: HashSet<[u8; 32]>,
+    // the same hashes in the order they came in
+    relayed_order: VecDeque<[u8; 32]>,
+    capacity: usize,
+    last_flush: u64,
+}
+
+impl GossipRelay {
+    pub fn new() -> Self {
+        GossipRelay::with_capacity(RELAY_SEEN_CAPACITY)
+    }
+
+    fn with_capacity(capacity: usize) -> Self {
+        GossipRelay {
+            queue: Vec::new(),
+            relayed: HashSet::new(),
+            relayed_order: VecDeque::new(),
+            capacity,
+            last_flush: get_current_timestamp(),
+        }
+    }
+
+    pub fn queue(&mut self, bytes: Vec<u8>, origin: [u8; 33], timestamp: u32) {
+        let hash = sha256::Hash::hash(&bytes).to_byte_array();
+        if !self.relayed.insert(hash) {
+            return;
+        }
+        self.relayed_order.push_back(hash);
+        if self.relayed_order.len() > self.capacity {
+            if let Some(oldest) = self.relayed_order.pop_front() {
+                self.relayed.remove(&oldest);
+            }
+        }
+        self.queue.push(RelayItem {
+            bytes,
+            origin,
+            timestamp,
+        });
+    }
+
+    // hands out everything queued once per flush interval
+    pub fn take_batch(&mut self) -> Option<Vec<RelayItem>> {
+        let now = get_current_timestamp();
+        if self.queue.is_empty() || self.last_flush + RELAY_FLUSH_INTERVAL > now {
+            return None;
+        }
+        self.last_flush = now;
+        Some(std::mem::take(&mut self.queue))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::serialization::ChainHashElement;
+
+    #[test]
+    fn test_relay_filtering_and_duplicates() {
+        let origin = [2u8; 33];
+        let other = [3u8; 33];
+        let mut relay = GossipRelay::new();
+        relay.queue(vec![1, 2, 3], origin, 1000);
+        relay.queue(vec![1, 2, 3], other, 1000);
+        relay.last_flush = 0;
+        let batch = relay.take_batch().unwrap();
+        assert_eq!(batch.len(), 1);
+        assert!(relay.take_batch().is_none());
+
+        let item = &batch[0];
+        assert!(!item.should_send_to(&origin, None, false));
+        assert!(item.should_send_to(&other, None, false));
+        assert!(!item.should_send_to(&other, None, true));
+        let chain_hash = ChainHashElement { value: [0; 32] };
+        let filter = GossipTimestampFilterMessage::new(chain_hash.clone(), 500, 501);
+        assert!(item.should_send_to(&other, Some(&filter), true));
+        let filter = GossipTimestampFilterMessage::new(chain_hash, 500, 500);
+        assert!(!item.should_send_to(&other, Some(&filter), true));
+    }
+
+    #[test]
+    fn test_relay_forgets_oldest() {
+        let origin = [2u8; 33];
+        let mut relay = GossipRelay::with_capacity(2);
+        relay.queue(vec![1], origin, 1000);
+        relay.queue(vec![2], origin, 1000);
+        relay.queue(vec![3], origin, 1000);
+        assert_eq!(relay.relayed.len(), 2);
+        // the first one was forgotten, so it goes out again
+        relay.queue(vec![1], origin, 1000);
+        relay.queue(vec![3], origin, 1000);
+        assert_eq!(relay.queue.len(), 4);
+    }
+}
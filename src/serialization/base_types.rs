@@ -1,5 +1,11 @@
-use std::collections::HashMap;
 use std::fmt;
+use std::sync::OnceLock;
+
+use bitcoin::constants::ChainHash;
+use bitcoin::secp256k1::ecdsa::Signature;
+
+use bitcoin::secp256k1::{Message, PublicKey, Secp256k1, VerifyOnly};
+use bitcoin::Network;
 
 use crate::messages::MessageType;
 use crate::serialization::{SerializableToBytes, SerializationError};
@@ -50,6 +56,9 @@ impl SerializableToBytes for WireU16SizedBytes {
             return Err(SerializationError::TooFewBytes);
         }
         let num_bytes = u16::from_be_bytes([data[0], data[1]]);
+        if data.len() < 2 + num_bytes as usize {
+            return Err(SerializationError::TooFewBytes);
+        }
         let our_data = data[2..2 + num_bytes as usize].to_vec();
         Ok((
             WireU16SizedBytes {
@@ -67,128 +76,6 @@ impl SerializableToBytes for WireU16SizedBytes {
     }
 }
 
-#[derive(Debug)]
-pub enum FeatureFlag {
-    Unset,
-    Optional,
-    Compulsory,
-}
-
-#[derive(Clone, Debug, Eq, PartialEq, Hash)]
-pub enum Features {
-    DataLossProtect,
-    UpfrontShutdownScript,
-    GossipQueries,
-    OnionOptin,
-    GossipQueriesEx,
-    StaticRemoteKey,
-    PaymentSecret,
-    BasicMPP,
-    SupportLargeChannel,
-    Anchors,
-    RouteBlinding,
-    ShutdownAnySegWit,
-    DualFund,
-    Quiesce,
-    OnionMessages,
-    ProvideStorage,
-    ChannelType,
-    SCIDAlias,
-    PaymentMetadata,
-    ZeroConf,
-    SimpleClose,
-}
-
-const FEATURE_BITS: &[(usize, Features)] = &[
-    (0, Features::DataLossProtect),
-    (4, Features::UpfrontShutdownScript),
-    (6, Features::GossipQueries),
-    (8, Features::OnionOptin),
-    (10, Features::GossipQueriesEx),
-    (12, Features::StaticRemoteKey),
-    (14, Features::PaymentSecret),
-    (16, Features::BasicMPP),
-    (18, Features::SupportLargeChannel),
-    (22, Features::Anchors),
-    (24, Features::RouteBlinding),
-    (26, Features::ShutdownAnySegWit),
-    (28, Features::DualFund),
-    (34, Features::Quiesce),
-    (38, Features::OnionMessages),
-    (42, Features::ProvideStorage),
-    (44, Features::ChannelType),
-    (46, Features::SCIDAlias),
-    (48, Features::PaymentMetadata),
-    (50, Features::ZeroConf),
-    (60, Features::SimpleClose),
-];
-
-#[derive(Clone)]
-pub struct FeaturesElement {
-    pub value: WireU16SizedBytes,
-}
-
-impl FeaturesElement {
-    #[allow(dead_code)]
-    pub fn is_empty(&self) -> bool {
-        self.value.value.is_empty()
-    }
-
-    pub fn feature_status(&self, feature: &Features) -> FeatureFlag {
-        let bit = FEATURE_BITS.iter().find(|(_, f)| f == feature).unwrap().0;
-        if self.is_bit_set(bit) {
-            FeatureFlag::Compulsory
-        } else if self.is_bit_set(bit + 1) {
-            FeatureFlag::Optional
-        } else {
-            FeatureFlag::Unset
-        }
-    }
-
-    fn is_bit_set(&self, bit: usize) -> bool {
-        if (bit / 8) + 1 > self.value.value.len() {
-            return false;
-        }
-        // false
-        self.value.value[bit / 8] & (1 << (bit % 8)) != 0
-    }
-
-    pub fn features_list(&self) -> HashMap<Features, FeatureFlag> {
-        let mut features = HashMap::new();
-        for (_, feature) in FEATURE_BITS {
-            // this is not efficient because it loops twice, but it's not a big deal for now
-            let status = self.feature_status(feature);
-            match status {
-                FeatureFlag::Compulsory => {
-                    features.insert(feature.clone(), status);
-                }
-                FeatureFlag::Optional => {
-                    features.insert(feature.clone(), status);
-                }
-                FeatureFlag::Unset => (),
-            };
-        }
-        features
-    }
-}
-
-impl fmt::Debug for FeaturesElement {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "(features: {:?})", self.features_list())
-    }
-}
-
-impl SerializableToBytes for FeaturesElement {
-    fn from_bytes(data: &[u8]) -> Result<(Self, &[u8]), SerializationError> {
-        let (value, rest) = WireU16SizedBytes::from_bytes(data).unwrap();
-        Ok((FeaturesElement { value }, rest))
-    }
-
-    fn to_bytes(&self) -> Vec<u8> {
-        self.value.to_bytes()
-    }
-}
-
 #[derive(Clone)]
 pub struct IgnoredBytesElement {
     pub value: WireU16SizedBytes,
@@ -430,6 +317,31 @@ impl SerializableToBytes for WireU64Int {
     }
 }
 
+#[derive(Debug)]
+pub struct WireI64Int {
+    pub value: i64,
+}
+
+impl WireI64Int {
+    pub fn new(value: i64) -> Self {
+        WireI64Int { value }
+    }
+}
+
+impl SerializableToBytes for WireI64Int {
+    fn from_bytes(data: &[u8]) -> Result<(Self, &[u8]), SerializationError> {
+        if data.len() < 8 {
+            return Err(SerializationError::TooFewBytes);
+        }
+        let value = i64::from_be_bytes(data[..8].try_into().unwrap());
+        Ok((WireI64Int { value }, &data[8..]))
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        self.value.to_be_bytes().to_vec()
+    }
+}
+
 fn decode_64_bytes(data: &[u8]) -> Result<([u8; 64], &[u8]), SerializationError> {
     if data.len() < 64 {
         return Err(SerializationError::TooFewBytes);
@@ -455,11 +367,35 @@ impl SerializableToBytes for Wire64Bytes {
     }
 }
 
+// building a context is expensive, every signature check shares this one
+fn verification_context() -> &'static Secp256k1<VerifyOnly> {
+    static CONTEXT: OnceLock<Secp256k1<VerifyOnly>> = OnceLock::new();
+    CONTEXT.get_or_init(Secp256k1::verification_only)
+}
+
 #[derive(Clone)]
 pub struct SignatureElement {
     value: [u8; 64],
 }
 
+impl SignatureElement {
+    // lightning signs the double-sha256 of the message with compact ecdsa signatures
+    pub fn verify(&self, digest: &[u8; 32], public_key: &PointElement) -> bool {
+        let signature = match Signature::from_compact(&self.value) {
+            Ok(signature) => signature,
+            Err(_) => return false,
+        };
+        let public_key = match PublicKey::from_slice(&public_key.value) {
+            Ok(public_key) => public_key,
+            Err(_) => return false,
+        };
+        let message = Message::from_digest(*digest);
+        verification_context()
+            .verify_ecdsa(&message, &signature, &public_key)
+            .is_ok()
+    }
+}
+
 impl SerializableToBytes for SignatureElement {
     fn from_bytes(data: &[u8]) -> Result<(Self, &[u8]), SerializationError> {
         let (bytes, data) = decode_64_bytes(data)?;
@@ -531,11 +467,19 @@ impl SerializableToBytes for NodeAliasElement {
     }
 }
 
-#[derive(Clone)]
+#[derive(Clone, PartialEq, Eq, Hash)]
 pub struct ChainHashElement {
     pub value: [u8; 32],
 }
 
+impl ChainHashElement {
+    pub fn from_network(network: Network) -> Self {
+        ChainHashElement {
+            value: ChainHash::using_genesis_block_const(network).to_bytes(),
+        }
+    }
+}
+
 impl fmt::Debug for ChainHashElement {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "{}", hex::encode(&self.value))
@@ -553,6 +497,28 @@ impl SerializableToBytes for ChainHashElement {
     }
 }
 
+#[derive(Clone, Eq, PartialEq, Hash)]
+pub struct Sha256Element {
+    pub value: [u8; 32],
+}
+
+impl fmt::Debug for Sha256Element {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", hex::encode(self.value))
+    }
+}
+
+impl SerializableToBytes for Sha256Element {
+    fn from_bytes(data: &[u8]) -> Result<(Self, &[u8]), SerializationError> {
+        let (data, remainder) = decode_32_bytes(data)?;
+        Ok((Sha256Element { value: data }, remainder))
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        self.value.to_vec()
+    }
+}
+
 fn decode_33_bytes(data: &[u8]) -> Result<([u8; 33], &[u8]), SerializationError> {
     if data.len() < 33 {
         return Err(SerializationError::TooFewBytes);
@@ -627,7 +593,37 @@ pub struct ShortChannelIDElement {
     pub output_index: u16,
 }
 
-impl ShortChannelIDElement {}
+impl ShortChannelIDElement {
+    // scids sort by where they were mined, which is the order gossip queries use
+    pub fn sort_key(&self) -> (u32, u32, u16) {
+        (self.block_height, self.tx_index, self.output_index)
+    }
+}
+
+// encoded_short_ids with encoding type 0, a plain array of short channel ids
+pub fn encode_short_ids(short_ids: &[ShortChannelIDElement]) -> Vec<u8> {
+    let mut bytes = vec![0u8];
+    for short_id in short_ids {
+        bytes.extend(short_id.to_bytes());
+    }
+    bytes
+}
+
+pub fn decode_short_ids(data: &[u8]) -> Result<Vec<ShortChannelIDElement>, SerializationError> {
+    let mut short_ids = Vec::new();
+    let mut data = match data.split_first() {
+        None => return Ok(short_ids),
+        Some((0, rest)) => rest,
+        // zlib (type 1) is deprecated and nobody should send it
+        Some(_) => return Err(SerializationError::InvalidValue),
+    };
+    while !data.is_empty() {
+        let (short_id, rest) = ShortChannelIDElement::from_bytes(data)?;
+        short_ids.push(short_id);
+        data = rest;
+    }
+    Ok(short_ids)
+}
 
 impl SerializableToBytes for ShortChannelIDElement {
     fn from_bytes(data: &[u8]) -> Result<(Self, &[u8]), SerializationError> {
@@ -691,6 +687,114 @@ impl SerializableToBytes for Wire3Bytes {
     }
 }
 
+#[derive(Debug)]
+pub struct BigSizeElement {
+    pub value: u64,
+}
+
+impl BigSizeElement {
+    pub fn new(value: u64) -> Self {
+        BigSizeElement { value }
+    }
+}
+
+impl SerializableToBytes for BigSizeElement {
+    fn from_bytes(data: &[u8]) -> Result<(Self, &[u8]), SerializationError> {
+        if data.is_empty() {
+            return Err(SerializationError::TooFewBytes);
+        }
+        let (value, rest) = match data[0] {
+            0xfd => {
+                let (n, rest) = WireU16Int::from_bytes(&data[1..])?;
+                if n.value < 0xfd {
+                    return Err(SerializationError::InvalidValue);
+                }
+                (n.value as u64, rest)
+            }
+            0xfe => {
+                let (n, rest) = WireU32Int::from_bytes(&data[1..])?;
+                if n.value < 0x10000 {
+                    return Err(SerializationError::InvalidValue);
+                }
+                (n.value as u64, rest)
+            }
+            0xff => {
+                let (n, rest) = WireU64Int::from_bytes(&data[1..])?;
+                if n.value < 0x100000000 {
+                    return Err(SerializationError::InvalidValue);
+                }
+                (n.value, rest)
+            }
+            n => (n as u64, &data[1..]),
+        };
+        Ok((BigSizeElement { value }, rest))
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        match self.value {
+            0..0xfd => vec![self.value as u8],
+            0xfd..0x10000 => [vec![0xfd], (self.value as u16).to_be_bytes().to_vec()].concat(),
+            0x10000..0x100000000 => {
+                [vec![0xfe], (self.value as u32).to_be_bytes().to_vec()].concat()
+            }
+            _ => [vec![0xff], self.value.to_be_bytes().to_vec()].concat(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TLVRecord {
+    pub tlv_type: u64,
+    pub value: Vec<u8>,
+}
+
+impl TLVRecord {
+    pub fn new(tlv_type: u64, value: Vec<u8>) -> Self {
+        TLVRecord { tlv_type, value }
+    }
+}
+
+impl SerializableToBytes for TLVRecord {
+    fn from_bytes(data: &[u8]) -> Result<(Self, &[u8]), SerializationError> {
+        let (tlv_type, data) = BigSizeElement::from_bytes(data)?;
+        let (length, data) = BigSizeElement::from_bytes(data)?;
+        if (data.len() as u64) < length.value {
+            return Err(SerializationError::TooFewBytes);
+        }
+        let (value, rest) = data.split_at(length.value as usize);
+        Ok((TLVRecord::new(tlv_type.value, value.to_vec()), rest))
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = BigSizeElement::new(self.tlv_type).to_bytes();
+        bytes.extend(BigSizeElement::new(self.value.len() as u64).to_bytes());
+        bytes.extend(self.value.clone());
+        bytes
+    }
+}
+
+pub fn decode_tlv_stream(mut data: &[u8]) -> Result<Vec<TLVRecord>, SerializationError> {
+    let mut records: Vec<TLVRecord> = Vec::new();
+    while !data.is_empty() {
+        let (record, rest) = TLVRecord::from_bytes(data)?;
+        // types must be strictly increasing
+        if let Some(last) = records.last() {
+            if record.tlv_type <= last.tlv_type {
+                return Err(SerializationError::InvalidValue);
+            }
+        }
+        records.push(record);
+        data = rest;
+    }
+    Ok(records)
+}
+
+pub fn encode_tlv_stream(records: &[TLVRecord]) -> Vec<u8> {
+    let mut sorted = records.to_vec();
+    sorted.sort_by_key(|record| record.tlv_type);
+    sorted.iter().flat_map(|record| record.to_bytes()).collect()
+}
+
 #[derive(Debug)]
 pub struct RemainderTypeWire {
     pub value: Vec<u8>,
@@ -721,3 +825,5 @@ pub type NumPongBytesElement = WireU16Int;
 pub type TimestampElement = WireU32Int;
 pub type TimestampRangeElement = WireU32Int;
 pub type TLVStreamElement = RemainderTypeWire;
+pub type ChannelIDElement = Sha256Element;
+pub type TxIdElement = Sha256Element;
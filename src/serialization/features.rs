@@ -0,0 +1,367 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::serialization::{SerializableToBytes, SerializationError, WireU16SizedBytes};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FeatureFlag {
+    Unset,
+    Optional,
+    Compulsory,
+}
+
+// the places a feature bit may be presented in, as tagged in bolt 9
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FeatureContext {
+    Init,
+    NodeAnnouncement,
+    ChannelAnnouncement,
+    Bolt11Invoice,
+    Bolt12Invoice,
+}
+
+impl FeatureContext {
+    pub fn as_char(&self) -> char {
+        match self {
+            FeatureContext::Init => 'I',
+            FeatureContext::NodeAnnouncement => 'N',
+            FeatureContext::ChannelAnnouncement => 'C',
+            FeatureContext::Bolt11Invoice => '9',
+            FeatureContext::Bolt12Invoice => 'B',
+        }
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Hash)]
+pub enum Features {
+    DataLossProtect,
+    InitialRoutingSync,
+    UpfrontShutdownScript,
+    GossipQueries,
+    OnionOptin,
+    GossipQueriesEx,
+    StaticRemoteKey,
+    PaymentSecret,
+    BasicMPP,
+    SupportLargeChannel,
+    AnchorOutputs,
+    Anchors,
+    RouteBlinding,
+    ShutdownAnySegWit,
+    DualFund,
+    Quiesce,
+    AttributionData,
+    OnionMessages,
+    ProvideStorage,
+    ChannelType,
+    SCIDAlias,
+    PaymentMetadata,
+    ZeroConf,
+    SimpleClose,
+    Splice,
+    Taproot,
+}
+
+// (compulsory bit, feature, bolt 9 contexts)
+const FEATURE_BITS: &[(usize, Features, &str)] = &[
+    (0, Features::DataLossProtect, "IN"),
+    (2, Features::InitialRoutingSync, "I"),
+    (4, Features::UpfrontShutdownScript, "IN"),
+    (6, Features::GossipQueries, "IN"),
+    (8, Features::OnionOptin, "IN9"),
+    (10, Features::GossipQueriesEx, "IN"),
+    (12, Features::StaticRemoteKey, "IN"),
+    (14, Features::PaymentSecret, "IN9"),
+    (16, Features::BasicMPP, "IN9B"),
+    (18, Features::SupportLargeChannel, "IN"),
+    (20, Features::AnchorOutputs, "IN"),
+    // option_anchors_zero_fee_htlc_tx
+    (22, Features::Anchors, "IN"),
+    (24, Features::RouteBlinding, "IN9"),
+    (26, Features::ShutdownAnySegWit, "IN"),
+    (28, Features::DualFund, "IN"),
+    (34, Features::Quiesce, "IN"),
+    (36, Features::AttributionData, "IN9"),
+    (38, Features::OnionMessages, "IN"),
+    (42, Features::ProvideStorage, "IN"),
+    (44, Features::ChannelType, "IN"),
+    (46, Features::SCIDAlias, "IN"),
+    (48, Features::PaymentMetadata, "9"),
+    (50, Features::ZeroConf, "IN"),
+    (60, Features::SimpleClose, "IN"),
+    (62, Features::Splice, "IN"),
+    (80, Features::Taproot, "IN"),
+];
+
+fn find_feature(feature: &Features) -> &'static (usize, Features, &'static str) {
+    // every variant has an entry, so this can't fail
+    FEATURE_BITS.iter().find(|(_, f, _)| f == feature).unwrap()
+}
+
+fn find_bit(bit: usize) -> Option<&'static (usize, Features, &'static str)> {
+    // both the compulsory and the optional bit belong to the same feature
+    FEATURE_BITS.iter().find(|(b, _, _)| *b == bit - bit % 2)
+}
+
+impl Features {
+    pub fn compulsory_bit(&self) -> usize {
+        find_feature(self).0
+    }
+
+    pub fn is_legal_in(&self, context: FeatureContext) -> bool {
+        find_feature(self).2.contains(context.as_char())
+    }
+}
+
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct FeatureReport {
+    pub unknown_required_bits: Vec<usize>,
+    pub unknown_optional_bits: Vec<usize>,
+    pub illegal_in_context: Vec<Features>,
+}
+
+impl FeatureReport {
+    pub fn is_valid(&self) -> bool {
+        self.unknown_required_bits.is_empty() && self.illegal_in_context.is_empty()
+    }
+}
+
+#[derive(Clone)]
+pub struct FeaturesElement {
+    pub value: WireU16SizedBytes,
+}
+
+impl FeaturesElement {
+    pub fn new(bytes: Vec<u8>) -> Self {
+        FeaturesElement {
+            value: WireU16SizedBytes::new(bytes),
+        }
+    }
+
+    pub fn empty() -> Self {
+        FeaturesElement::new(Vec::new())
+    }
+
+    // feature vectors are big-endian, so bit 0 lives in the last byte
+    pub fn set_bit(&mut self, bit: usize) {
+        let mut bytes = self.value.value.clone();
+        let num_bytes = bit / 8 + 1;
+        if bytes.len() < num_bytes {
+            let mut padded = vec![0; num_bytes - bytes.len()];
+            padded.extend(bytes);
+            bytes = padded;
+        }
+        let index = bytes.len() - 1 - bit / 8;
+        bytes[index] |= 1 << (bit % 8);
+        self.value = WireU16SizedBytes::new(bytes);
+    }
+
+    pub fn set_feature(&mut self, feature: &Features, flag: FeatureFlag) {
+        let bit = feature.compulsory_bit();
+        match flag {
+            FeatureFlag::Compulsory => self.set_bit(bit),
+            FeatureFlag::Optional => self.set_bit(bit + 1),
+            FeatureFlag::Unset => (),
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn is_empty(&self) -> bool {
+        self.value.value.is_empty()
+    }
+
+    pub fn feature_status(&self, feature: &Features) -> FeatureFlag {
+        let bit = feature.compulsory_bit();
+        if self.is_bit_set(bit) {
+            FeatureFlag::Compulsory
+        } else if self.is_bit_set(bit + 1) {
+            FeatureFlag::Optional
+        } else {
+            FeatureFlag::Unset
+        }
+    }
+
+    pub fn is_bit_set(&self, bit: usize) -> bool {
+        let bytes = &self.value.value;
+        if (bit / 8) + 1 > bytes.len() {
+            return false;
+        }
+        bytes[bytes.len() - 1 - bit / 8] & (1 << (bit % 8)) != 0
+    }
+
+    pub fn set_bits(&self) -> Vec<usize> {
+        (0..self.value.value.len() * 8)
+            .filter(|bit| self.is_bit_set(*bit))
+            .collect()
+    }
+
+    pub fn unknown_bits(&self) -> Vec<usize> {
+        self.set_bits()
+            .into_iter()
+            .filter(|bit| find_bit(*bit).is_none())
+            .collect()
+    }
+
+    pub fn validate(&self, context: FeatureContext) -> FeatureReport {
+        let mut report = FeatureReport::default();
+        for bit in self.set_bits() {
+            match find_bit(bit) {
+                Some((_, feature, _)) => {
+                    if !feature.is_legal_in(context) && !report.illegal_in_context.contains(feature)
+                    {
+                        report.illegal_in_context.push(feature.clone());
+                    }
+                }
+                // even bits are compulsory, so an unknown even bit is one we can't support
+                None if bit % 2 == 0 => report.unknown_required_bits.push(bit),
+                None => report.unknown_optional_bits.push(bit),
+            }
+        }
+        report
+    }
+
+    pub fn union(&self, other: &FeaturesElement) -> FeaturesElement {
+        let mut features = self.clone();
+        for bit in other.set_bits() {
+            features.set_bit(bit);
+        }
+        features
+    }
+
+    // features that both sides have set, either as optional or compulsory
+    pub fn negotiate(&self, other: &FeaturesElement) -> Vec<Features> {
+        FEATURE_BITS
+            .iter()
+            .filter(|(_, f, _)| self.feature_status(f) != FeatureFlag::Unset)
+            .filter(|(_, f, _)| other.feature_status(f) != FeatureFlag::Unset)
+            .map(|(_, f, _)| f.clone())
+            .collect()
+    }
+
+    pub fn features_list(&self) -> HashMap<Features, FeatureFlag> {
+        let mut features = HashMap::new();
+        for (_, feature, _) in FEATURE_BITS {
+            // this is not efficient because it loops twice, but it's not a big deal for now
+            let status = self.feature_status(feature);
+            match status {
+                FeatureFlag::Compulsory => {
+                    features.insert(feature.clone(), status);
+                }
+                FeatureFlag::Optional => {
+                    features.insert(feature.clone(), status);
+                }
+                FeatureFlag::Unset => (),
+            };
+        }
+        features
+    }
+}
+
+impl fmt::Debug for FeaturesElement {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let unknown_bits = self.unknown_bits();
+        if unknown_bits.is_empty() {
+            write!(f, "(features: {:?})", self.features_list())
+        } else {
+            write!(
+                f,
+                "(features: {:?}, unknown bits: {:?})",
+                self.features_list(),
+                unknown_bits
+            )
+        }
+    }
+}
+
+impl SerializableToBytes for FeaturesElement {
+    fn from_bytes(data: &[u8]) -> Result<(Self, &[u8]), SerializationError> {
+        let (value, rest) = WireU16SizedBytes::from_bytes(data)?;
+        Ok((FeaturesElement { value }, rest))
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        self.value.to_bytes()
+    }
+}
+
+#[derive(Default)]
+pub struct FeaturesBuilder {
+    features: Vec<(Features, FeatureFlag)>,
+}
+
+impl FeaturesBuilder {
+    pub fn new() -> Self {
+        FeaturesBuilder::default()
+    }
+
+    pub fn set(mut self, feature: Features, flag: FeatureFlag) -> Self {
+        self.features.push((feature, flag));
+        self
+    }
+
+    pub fn optional(self, feature: Features) -> Self {
+        self.set(feature, FeatureFlag::Optional)
+    }
+
+    #[allow(dead_code)]
+    pub fn compulsory(self, feature: Features) -> Self {
+        self.set(feature, FeatureFlag::Compulsory)
+    }
+
+    pub fn build(self) -> FeaturesElement {
+        let mut element = FeaturesElement::empty();
+        for (feature, flag) in self.features {
+            element.set_feature(&feature, flag);
+        }
+        element
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bits_are_big_endian() {
+        let features = FeaturesElement::new(vec![0x02, 0x00, 0x01]);
+        assert_eq!(features.set_bits(), vec![0, 17]);
+        assert_eq!(
+            features.feature_status(&Features::DataLossProtect),
+            FeatureFlag::Compulsory
+        );
+        assert_eq!(
+            features.feature_status(&Features::BasicMPP),
+            FeatureFlag::Optional
+        );
+
+        let mut built = FeaturesElement::empty();
+        built.set_bit(0);
+        built.set_bit(17);
+        assert_eq!(built.value.value, vec![0x02, 0x00, 0x01]);
+    }
+
+    #[test]
+    fn test_validate_by_context() {
+        let mut features = FeaturesBuilder::new()
+            .optional(Features::GossipQueries)
+            .optional(Features::PaymentMetadata)
+            .optional(Features::Taproot)
+            .build();
+        features.set_bit(100);
+        features.set_bit(103);
+
+        let report = features.validate(FeatureContext::Init);
+        assert_eq!(report.unknown_required_bits, vec![100]);
+        assert_eq!(report.unknown_optional_bits, vec![103]);
+        assert_eq!(report.illegal_in_context, vec![Features::PaymentMetadata]);
+        assert!(!report.is_valid());
+
+        // nothing is defined for channel_announcement yet
+        let report = FeaturesBuilder::new()
+            .optional(Features::GossipQueries)
+            .build()
+            .validate(FeatureContext::ChannelAnnouncement);
+        assert_eq!(report.illegal_in_context, vec![Features::GossipQueries]);
+    }
+}
@@ -0,0 +1,113 @@
+use std::collections::BTreeMap;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddrV4};
+
+use serde::ser::SerializeStruct;
+use serde::{Serialize, Serializer};
+
+use crate::serialization::{
+    ChainHashElement, FeaturesElement, IgnoredBytesElement, NodeAddressesElement, NodeAliasElement,
+    PointElement, Sha256Element, ShortChannelIDElement, SignatureElement,
+};
+
+// how messages look as json: keys, hashes and signatures in hex, short channel ids as
+// blockxtxxoutput like everyone else prints them
+
+// for the raw byte fields of messages, tlv streams and the like
+pub fn hex_bytes<T: AsRef<[u8]>, S: Serializer>(
+    bytes: &T,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&hex::encode(bytes.as_ref()))
+}
+
+impl Serialize for SignatureElement {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&format!("{:?}", self))
+    }
+}
+
+impl Serialize for ChainHashElement {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&hex::encode(self.value))
+    }
+}
+
+impl Serialize for Sha256Element {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&hex::encode(self.value))
+    }
+}
+
+impl Serialize for PointElement {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&hex::encode(self.value))
+    }
+}
+
+impl Serialize for ShortChannelIDElement {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&format!(
+            "{}x{}x{}",
+            self.block_height, self.tx_index, self.output_index
+        ))
+    }
+}
+
+impl Serialize for NodeAliasElement {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let alias = &self.value.value;
+        let end = alias
+            .iter()
+            .position(|byte| *byte == 0)
+            .unwrap_or(alias.len());
+        serializer.serialize_str(&String::from_utf8_lossy(&alias[..end]))
+    }
+}
+
+// only the length, the bytes themselves mean nothing
+impl Serialize for IgnoredBytesElement {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u64(self.value.value.len() as u64)
+    }
+}
+
+impl Serialize for FeaturesElement {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let known: BTreeMap<String, String> = self
+            .features_list()
+            .into_iter()
+            .map(|(feature, flag)| (format!("{:?}", feature), format!("{:?}", flag)))
+            .collect();
+        let mut features = serializer.serialize_struct("Features", 2)?;
+        features.serialize_field("known", &known)?;
+        features.serialize_field("unknown_bits", &self.unknown_bits())?;
+        features.end()
+    }
+}
+
+// ipv4 addresses with their ports, tor addresses and hostnames as they are on the wire
+impl Serialize for NodeAddressesElement {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut addresses: Vec<String> = Vec::new();
+        for address in &self.ipv4_addresses {
+            let ip = Ipv4Addr::new(address[0], address[1], address[2], address[3]);
+            addresses.push(
+                SocketAddrV4::new(ip, u16::from_be_bytes([address[4], address[5]])).to_string(),
+            );
+        }
+        // the decoder keeps 16 bytes of these, so there is no port
+        for address in &self.ipv6_addresses {
+            addresses.push(Ipv6Addr::from(*address).to_string());
+        }
+        for address in &self.torv2_addresses {
+            addresses.push(format!("torv2:{}", hex::encode(address)));
+        }
+        for address in &self.torv3_addresses {
+            addresses.push(format!("torv3:{}", hex::encode(address)));
+        }
+        if !self.dns_hostname.is_empty() {
+            addresses.push(format!("dns:{}", hex::encode(&self.dns_hostname)));
+        }
+        addresses.serialize(serializer)
+    }
+}
@@ -1,6 +1,11 @@
 pub use crate::serialization::base_types::*;
+pub use crate::serialization::features::*;
 
 mod base_types;
+mod features;
+mod json;
+
+pub use crate::serialization::json::hex_bytes;
 
 #[derive(Debug, Clone)]
 pub enum SerializationError {
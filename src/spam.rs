@@ -0,0 +1,240 @@
+use std::collections::HashMap;
+
+use crate::config::{
+    CHANNEL_UPDATE_BURST, CHANNEL_UPDATE_INTERVAL, FLAP_LIMIT, FLAP_WINDOW,
+    NEAR_DUPLICATE_INTERVAL, NODE_ANNOUNCEMENT_BURST, NODE_ANNOUNCEMENT_INTERVAL,
+};
+use crate::messages::{ChannelUpdateMessage, NodeAnnouncementMessage};
+use crate::serialization::{PointElement, ShortChannelIDElement};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpamReason {
+    RateLimited,
+    // the same content again with only a newer timestamp
+    NearDuplicate,
+    // the disabled bit keeps toggling
+    Flapping,
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct Offender {
+    pub rate_limited: u64,
+    pub near_duplicates: u64,
+    pub flaps: u64,
+}
+
+impl Offender {
+    pub fn total(&self) -> u64 {
+        self.rate_limited + self.near_duplicates + self.flaps
+    }
+}
+
+struct TokenBucket {
+    tokens: u32,
+    last_refill: u64,
+}
+
+impl TokenBucket {
+    fn new(burst: u32, now: u64) -> Self {
+        TokenBucket {
+            tokens: burst,
+            last_refill: now,
+        }
+    }
+
+    fn take(&mut self, burst: u32, interval: u64, now: u64) -> bool {
+        let refill = now.saturating_sub(self.last_refill) / interval;
+        if refill > 0 {
+            self.tokens = (self.tokens as u64 + refill).min(burst as u64) as u32;
+            self.last_refill += refill * interval;
+        }
+        if self.tokens == 0 {
+            return false;
+        }
+        self.tokens -= 1;
+        true
+    }
+}
+
+// decides which correctly signed gossip is too much, and who sent it
+#[derive(Default)]
+pub struct SpamFilter {
+    channel_buckets: HashMap<(ShortChannelIDElement, usize), TokenBucket>,
+    node_buckets: HashMap<PointElement, TokenBucket>,
+    toggles: HashMap<(ShortChannelIDElement, usize), Vec<u64>>,
+    pub offenders: HashMap<PointElement, Offender>,
+    pub dropped: u64,
+}
+
+impl SpamFilter {
+    // previous is the update we have for the same direction, node_id the node that signed it
+    pub fn check_channel_update(
+        &mut self,
+        msg: &ChannelUpdateMessage,
+        previous: Option<&ChannelUpdateMessage>,
+        node_id: &PointElement,
+        now: u64,
+    ) -> Result<(), SpamReason> {
+        let key = (msg.short_channel_id.clone(), msg.direction());
+        if let Some(previous) = previous {
+            if msg.same_policy(previous)
+                && msg.timestamp.saturating_sub(previous.timestamp) < NEAR_DUPLICATE_INTERVAL
+            {
+                return Err(self.report(node_id, SpamReason::NearDuplicate));
+            }
+            if msg.is_disabled() != previous.is_disabled() {
+                let toggles = self.toggles.entry(key.clone()).or_default();
+                toggles.retain(|at| at + FLAP_WINDOW > now);
+                toggles.push(now);
+                if toggles.len() > FLAP_LIMIT {
+                    return Err(self.report(node_id, SpamReason::Flapping));
+                }
+            }
+        }
+        let bucket = self
+            .channel_buckets
+            .entry(key)
+            .or_insert_with(|| TokenBucket::new(CHANNEL_UPDATE_BURST, now));
+        if !bucket.take(CHANNEL_UPDATE_BURST, CHANNEL_UPDATE_INTERVAL, now) {
+            return Err(self.report(node_id, SpamReason::RateLimited));
+        }
+        Ok(())
+    }
+
+    pub fn check_node_announcement(
+        &mut self,
+        msg: &NodeAnnouncementMessage,
+        previous: Option<&NodeAnnouncementMessage>,
+        now: u64,
+    ) -> Result<(), SpamReason> {
+        if let Some(previous) = previous {
+            if msg.same_content(previous)
+                && msg.timestamp.saturating_sub(previous.timestamp) < NEAR_DUPLICATE_INTERVAL
+            {
+                return Err(self.report(&msg.node_id, SpamReason::NearDuplicate));
+            }
+        }
+        let bucket = self
+            .node_buckets
+            .entry(msg.node_id.clone())
+            .or_insert_with(|| TokenBucket::new(NODE_ANNOUNCEMENT_BURST, now));
+        if !bucket.take(NODE_ANNOUNCEMENT_BURST, NODE_ANNOUNCEMENT_INTERVAL, now) {
+            return Err(self.report(&msg.node_id, SpamReason::RateLimited));
+        }
+        Ok(())
+    }
+
+    fn report(&mut self, node_id: &PointElement, reason: SpamReason) -> SpamReason {
+        self.dropped += 1;
+        let offender = self.offenders.entry(node_id.clone()).or_default();
+        if offender.total() == 0 {
+            println!("Reporting node {:?} for gossip spam: {:?}", node_id, reason);
+        }
+        match reason {
+            SpamReason::RateLimited => offender.rate_limited += 1,
+            SpamReason::NearDuplicate => offender.near_duplicates += 1,
+            SpamReason::Flapping => offender.flaps += 1,
+        }
+        reason
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message_decoder::{example_messages, MessageContainer};
+    use crate::serialization::SerializableToBytes;
+
+    fn example_update() -> ChannelUpdateMessage {
+        example_messages()
+            .into_iter()
+            .find_map(|message| match message {
+                MessageContainer::ChannelUpdate(msg) => Some(msg),
+                _ => None,
+            })
+            .unwrap()
+    }
+
+    // the same update with other values, the signature doesn't matter here
+    fn modified(
+        update: &ChannelUpdateMessage,
+        timestamp: u32,
+        channel_flags: u8,
+        fee: u8,
+    ) -> ChannelUpdateMessage {
+        let mut bytes = update.to_bytes();
+        bytes[2 + 64 + 32 + 8..2 + 64 + 32 + 8 + 4].copy_from_slice(&timestamp.to_be_bytes());
+        bytes[2 + 64 + 32 + 8 + 4 + 1] = channel_flags;
+        bytes[2 + 64 + 32 + 8 + 4 + 2 + 2 + 8 + 3] = fee;
+        ChannelUpdateMessage::from_bytes(&bytes).unwrap().0
+    }
+
+    #[test]
+    fn test_channel_update_limits() {
+        let update = example_update();
+        let node_id = PointElement { value: [2; 33] };
+        let mut spam = SpamFilter::default();
+        let base = update.timestamp;
+
+        // same policy again an hour later is a near-duplicate, a day later a refresh
+        let again = modified(&update, base + 3600, update.channel_flags, 0);
+        let previous = modified(&update, base, update.channel_flags, 0);
+        assert_eq!(
+            spam.check_channel_update(&again, Some(&previous), &node_id, 0),
+            Err(SpamReason::NearDuplicate)
+        );
+        let refresh = modified(
+            &update,
+            base + NEAR_DUPLICATE_INTERVAL,
+            update.channel_flags,
+            0,
+        );
+        assert_eq!(
+            spam.check_channel_update(&refresh, Some(&previous), &node_id, 0),
+            Ok(())
+        );
+
+        // the burst runs out, then one more per interval
+        let mut spam = SpamFilter::default();
+        for i in 0..CHANNEL_UPDATE_BURST {
+            let next = modified(&update, base + i + 1, update.channel_flags, i as u8 + 1);
+            assert_eq!(spam.check_channel_update(&next, None, &node_id, 0), Ok(()));
+        }
+        let next = modified(&update, base + 100, update.channel_flags, 100);
+        assert_eq!(
+            spam.check_channel_update(&next, None, &node_id, 0),
+            Err(SpamReason::RateLimited)
+        );
+        assert_eq!(
+            spam.check_channel_update(&next, None, &node_id, CHANNEL_UPDATE_INTERVAL),
+            Ok(())
+        );
+        assert_eq!(spam.offenders[&node_id].rate_limited, 1);
+        assert_eq!(spam.dropped, 1);
+    }
+
+    #[test]
+    fn test_flapping_disabled_bit() {
+        let update = example_update();
+        let node_id = PointElement { value: [2; 33] };
+        let mut spam = SpamFilter::default();
+        let direction = update.channel_flags & 1;
+        let mut previous = modified(&update, update.timestamp, direction, 0);
+        let mut results = Vec::new();
+        for i in 0..FLAP_LIMIT as u32 + 1 {
+            let flags = direction | (!previous.channel_flags & 2);
+            let next = modified(&update, update.timestamp + i + 1, flags, 0);
+            results.push(spam.check_channel_update(&next, Some(&previous), &node_id, i as u64));
+            previous = next;
+        }
+        assert!(results[..FLAP_LIMIT].iter().all(|r| r.is_ok()));
+        assert_eq!(results[FLAP_LIMIT], Err(SpamReason::Flapping));
+        // toggling again after the window is fine
+        let flags = direction | (!previous.channel_flags & 2);
+        let next = modified(&update, update.timestamp + 100, flags, 0);
+        assert_eq!(
+            spam.check_channel_update(&next, Some(&previous), &node_id, FLAP_WINDOW + 10),
+            Ok(())
+        );
+    }
+}
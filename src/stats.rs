@@ -0,0 +1,157 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+use serde::Serialize;
+
+use crate::capture::Direction;
+use crate::messages::MessageType;
+
+// traffic of one connection as the codec sees it
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct TrafficStats {
+    // on the wire, with length headers and macs
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    pub messages_in: BTreeMap<String, u64>,
+    pub messages_out: BTreeMap<String, u64>,
+    // messages that decrypted fine but didn't decode, they're still counted above
+    pub decode_errors: u64,
+}
+
+impl TrafficStats {
+    pub fn record(&mut self, direction: Direction, message: &[u8]) {
+        let counts = match direction {
+            Direction::Inbound => &mut self.messages_in,
+            Direction::Outbound => &mut self.messages_out,
+        };
+        *counts.entry(message_type_name(message)).or_insert(0) += 1;
+    }
+
+    pub fn total_in(&self) -> u64 {
+        self.messages_in.values().sum()
+    }
+
+    pub fn total_out(&self) -> u64 {
+        self.messages_out.values().sum()
+    }
+}
+
+fn message_type_name(message: &[u8]) -> String {
+    if message.len() < 2 {
+        return "truncated".to_string();
+    }
+    let id = u16::from_be_bytes([message[0], message[1]]);
+    match MessageType::from_int(id) {
+        Some(message_type) => <&'static str>::from(message_type).to_string(),
+        None => format!("unknown_{}", id),
+    }
+}
+
+// one session with a peer, open or closed
+#[derive(Debug, Clone, Serialize)]
+pub struct PeerReport {
+    pub public_key: String,
+    pub address: String,
+    // unix seconds
+    pub connected_at: u64,
+    pub disconnected_at: Option<u64>,
+    pub handshake_millis: Option<u64>,
+    pub features: Vec<String>,
+    pub last_received: Option<u64>,
+    pub pongs: u64,
+    pub rtt_mean_millis: Option<u64>,
+    #[serde(flatten)]
+    pub traffic: TrafficStats,
+}
+
+#[derive(Serialize)]
+struct Report<'a> {
+    generated_at: u64,
+    peers: &'a [PeerReport],
+}
+
+pub fn print_table(reports: &[PeerReport], now: u64) {
+    println!(
+        "{:<18} {:>8} {:>8} {:>12} {:>12} {:>8} {:>8} {:>6} {:>8} {:>8}",
+        "peer",
+        "up",
+        "hs ms",
+        "bytes in",
+        "bytes out",
+        "msgs in",
+        "msgs out",
+        "errs",
+        "idle",
+        "rtt ms"
+    );
+    for report in reports {
+        let end = report.disconnected_at.unwrap_or(now);
+        let idle = match report.last_received {
+            Some(last_received) => format!("{}s", end.saturating_sub(last_received)),
+            None => "-".to_string(),
+        };
+        let optional = |value: Option<u64>| match value {
+            Some(value) => value.to_string(),
+            None => "-".to_string(),
+        };
+        println!(
+            "{:<18} {:>7}s {:>8} {:>12} {:>12} {:>8} {:>8} {:>6} {:>8} {:>8}",
+            &report.public_key[..16],
+            end.saturating_sub(report.connected_at),
+            optional(report.handshake_millis),
+            report.traffic.bytes_in,
+            report.traffic.bytes_out,
+            report.traffic.total_in(),
+            report.traffic.total_out(),
+            report.traffic.decode_errors,
+            idle,
+            optional(report.rtt_mean_millis)
+        );
+    }
+}
+
+pub fn write_report(path: &Path, reports: &[PeerReport], now: u64) -> io::Result<()> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    let report = Report {
+        generated_at: now,
+        peers: reports,
+    };
+    serde_json::to_writer_pretty(&mut writer, &report)?;
+    writer.write_all(b"\n")?;
+    writer.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_peer_report_json() {
+        let mut traffic = TrafficStats::default();
+        traffic.record(Direction::Inbound, &[0, 18, 0, 4, 0, 0]);
+        traffic.record(Direction::Inbound, &[0, 18, 0, 4, 0, 0]);
+        traffic.record(Direction::Inbound, &[0xff, 0xf0]);
+        traffic.record(Direction::Outbound, &[0, 16, 0, 0, 0, 0]);
+        assert_eq!(traffic.total_in(), 3);
+        let report = PeerReport {
+            public_key: hex::encode([2u8; 33]),
+            address: "127.0.0.1:9735".to_string(),
+            connected_at: 1000,
+            disconnected_at: None,
+            handshake_millis: Some(12),
+            features: vec!["GossipQueries".to_string()],
+            last_received: Some(1100),
+            pongs: 0,
+            rtt_mean_millis: None,
+            traffic,
+        };
+        let json = serde_json::to_value(&report).unwrap();
+        assert_eq!(json["messages_in"]["Ping"], 2);
+        assert_eq!(json["messages_in"]["unknown_65520"], 1);
+        assert_eq!(json["messages_out"]["Init"], 1);
+        assert_eq!(json["handshake_millis"], 12);
+        assert!(json["disconnected_at"].is_null());
+    }
+}
@@ -0,0 +1,255 @@
+use std::path::Path;
+
+use bitcoin::hashes::{sha256, Hash};
+use rusqlite::{params, Connection};
+
+use crate::message_decoder::{MessageContainer, MessageDecoder};
+use crate::serialization::{PointElement, ShortChannelIDElement};
+
+// every gossip message we received, raw and with the fields worth querying by
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS gossip (
+    hash BLOB PRIMARY KEY,
+    type INTEGER NOT NULL,
+    raw BLOB NOT NULL,
+    short_channel_id TEXT,
+    node_id_1 TEXT,
+    node_id_2 TEXT,
+    timestamp INTEGER,
+    direction INTEGER,
+    first_seen INTEGER NOT NULL,
+    last_seen INTEGER NOT NULL,
+    times_seen INTEGER NOT NULL,
+    source_peer TEXT NOT NULL,
+    -- whether the graph took any copy of it, rejected gossip is kept but not reloaded
+    accepted INTEGER NOT NULL DEFAULT 0
+);
+CREATE INDEX IF NOT EXISTS gossip_short_channel_id ON gossip (short_channel_id);
+CREATE INDEX IF NOT EXISTS gossip_node_id_1 ON gossip (node_id_1);
+";
+
+const RECORD_GOSSIP: &str = "
+INSERT INTO gossip (hash, type, raw, short_channel_id, node_id_1, node_id_2,
+    timestamp, direction, first_seen, last_seen, times_seen, source_peer, accepted)
+VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?9, 1, ?10, ?11)
+ON CONFLICT (hash) DO UPDATE SET
+    last_seen = excluded.last_seen, times_seen = times_seen + 1,
+    accepted = MAX(accepted, excluded.accepted)
+";
+
+// the latest accepted channel_update per direction and node_announcement per node, after
+// the announcements they depend on
+const LATEST_GOSSIP: &str = "
+SELECT raw FROM (
+    SELECT raw, type FROM gossip WHERE type = 256 AND accepted
+    UNION ALL
+    SELECT raw, type FROM (
+        SELECT raw, type, MAX(timestamp) FROM gossip WHERE type = 257 AND accepted
+        GROUP BY node_id_1
+    )
+    UNION ALL
+    SELECT raw, type FROM (
+        SELECT raw, type, MAX(timestamp) FROM gossip WHERE type = 258 AND accepted
+        GROUP BY short_channel_id, direction
+    )
+)
+ORDER BY type
+";
+
+fn scid_string(short_channel_id: &ShortChannelIDElement) -> String {
+    format!(
+        "{}x{}x{}",
+        short_channel_id.block_height, short_channel_id.tx_index, short_channel_id.output_index
+    )
+}
+
+fn node_string(node_id: &PointElement) -> String {
+    hex::encode(node_id.value)
+}
+
+// (type, short_channel_id, node_id_1, node_id_2, timestamp, direction)
+type GossipFields = (
+    u16,
+    Option<String>,
+    Option<String>,
+    Option<String>,
+    Option<u32>,
+    Option<usize>,
+);
+
+fn gossip_fields(message: &MessageContainer) -> Option<GossipFields> {
+    match message {
+        MessageContainer::ChannelAnnouncement(msg) => Some((
+            256,
+            Some(scid_string(&msg.short_channel_id)),
+            Some(node_string(&msg.node_id_1)),
+            Some(node_string(&msg.node_id_2)),
+            None,
+            None,
+        )),
+        MessageContainer::NodeAnnouncement(msg) => Some((
+            257,
+            None,
+            Some(node_string(&msg.node_id)),
+            None,
+            Some(msg.timestamp),
+            None,
+        )),
+        MessageContainer::ChannelUpdate(msg) => Some((
+            258,
+            Some(scid_string(&msg.short_channel_id)),
+            None,
+            None,
+            Some(msg.timestamp),
+            Some(msg.direction()),
+        )),
+        _ => None,
+    }
+}
+
+struct PendingGossip {
+    raw: Vec<u8>,
+    fields: GossipFields,
+    source_peer: [u8; 33],
+    now: u64,
+    accepted: bool,
+}
+
+pub struct GossipStore {
+    connection: Connection,
+    // written together by flush, one transaction per pass of the event loop
+    pending: Vec<PendingGossip>,
+}
+
+impl GossipStore {
+    pub fn open(path: &Path) -> rusqlite::Result<Self> {
+        let connection = Connection::open(path)?;
+        // appends instead of rewriting pages, and no fsync for every commit
+        connection.pragma_update(None, "journal_mode", "WAL")?;
+        connection.pragma_update(None, "synchronous", "NORMAL")?;
+        connection.execute_batch(SCHEMA)?;
+        Ok(GossipStore {
+            connection,
+            pending: Vec::new(),
+        })
+    }
+
+    // keeps the first source and first_seen, later copies only bump last_seen
+    pub fn record(
+        &mut self,
+        message: &MessageContainer,
+        source_peer: &[u8; 33],
+        now: u64,
+        accepted: bool,
+    ) {
+        if let Some(fields) = gossip_fields(message) {
+            self.pending.push(PendingGossip {
+                raw: message.to_bytes(),
+                fields,
+                source_peer: *source_peer,
+                now,
+                accepted,
+            });
+        }
+    }
+
+    pub fn flush(&mut self) -> rusqlite::Result<()> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        let transaction = self.connection.transaction()?;
+        {
+            let mut statement = transaction.prepare_cached(RECORD_GOSSIP)?;
+            for gossip in &self.pending {
+                let (message_type, short_channel_id, node_id_1, node_id_2, timestamp, direction) =
+                    &gossip.fields;
+                let hash = sha256::Hash::hash(&gossip.raw).to_byte_array();
+                statement.execute(params![
+                    hash.as_slice(),
+                    message_type,
+                    gossip.raw,
+                    short_channel_id,
+                    node_id_1,
+                    node_id_2,
+                    timestamp,
+                    direction,
+                    gossip.now as i64,
+                    hex::encode(gossip.source_peer),
+                    gossip.accepted,
+                ])?;
+            }
+        }
+        transaction.commit()?;
+        self.pending.clear();
+        Ok(())
+    }
+
+    // the gossip needed to rebuild the graph, in an order the graph accepts
+    pub fn load_latest(&self) -> rusqlite::Result<Vec<MessageContainer>> {
+        let mut statement = self.connection.prepare(LATEST_GOSSIP)?;
+        let rows = statement.query_map([], |row| row.get::<_, Vec<u8>>(0))?;
+        let mut messages = Vec::new();
+        for raw in rows {
+            match MessageDecoder::from_bytes(&raw?) {
+                Ok((message, _)) => messages.push(message),
+                Err(e) => println!("Skipping undecodable stored gossip: {:?}", e),
+            }
+        }
+        Ok(messages)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message_decoder::example_messages;
+
+    #[test]
+    fn test_record_and_load_latest() {
+        let path = std::env::temp_dir().join("lmprs_store_test.sqlite");
+        let _ = std::fs::remove_file(&path);
+        let mut store = GossipStore::open(&path).unwrap();
+        let alice = [2u8; 33];
+        let bob = [3u8; 33];
+        let messages = example_messages();
+        for message in &messages {
+            store.record(message, &alice, 1000, false);
+            // alice's copies were rejected, bob's accepted
+            store.record(message, &bob, 2000, true);
+        }
+        let mut bytes = messages
+            .iter()
+            .find(|message| matches!(message, MessageContainer::ChannelUpdate(_)))
+            .unwrap()
+            .to_bytes();
+        // a newer timestamp, so it would be the latest if it were loaded
+        bytes[2 + 64 + 32 + 8..2 + 64 + 32 + 8 + 4].copy_from_slice(&u32::MAX.to_be_bytes());
+        let (rejected, _) = MessageDecoder::from_bytes(&bytes).unwrap();
+        store.record(&rejected, &alice, 3000, false);
+        store.flush().unwrap();
+        assert!(store.pending.is_empty());
+
+        let (source, first_seen, last_seen, times_seen): (String, i64, i64, i64) = store
+            .connection
+            .query_row(
+                "SELECT source_peer, first_seen, last_seen, times_seen FROM gossip LIMIT 1",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)),
+            )
+            .unwrap();
+        assert_eq!(source, hex::encode(alice));
+        assert_eq!((first_seen, last_seen, times_seen), (1000, 2000, 2));
+
+        // one announcement, one node and the newest update of each direction
+        drop(store);
+        let store = GossipStore::open(&path).unwrap();
+        let loaded = store.load_latest().unwrap();
+        assert!(matches!(
+            loaded[0],
+            MessageContainer::ChannelAnnouncement(_)
+        ));
+        assert_eq!(loaded.len(), 1 + 1 + 2);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}